@@ -0,0 +1,10 @@
+//! Stand-in for the kernel's `boot`. Only `VirtAddr::from_phys`'s linear map uses these, and
+//! `ram::init` replaces it.
+
+use crate::mem::addr::{PhysAddr, VirtAddr};
+
+pub const VIRT_RAM_START: VirtAddr = VirtAddr::new_const(0xffffffffc0000000);
+
+pub fn phys_ram_start() -> PhysAddr {
+    PhysAddr(0x80000000)
+}
@@ -0,0 +1,18 @@
+//! Stand-in for the kernel's `io`, printing to stdout.
+
+pub mod serial {
+    macro_rules! print {
+        ($($arg:tt)*) => {
+            std::print!($($arg)*)
+        };
+    }
+
+    macro_rules! println {
+        ($($arg:tt)*) => {
+            std::println!($($arg)*)
+        };
+    }
+
+    pub(crate) use print;
+    pub(crate) use println;
+}
@@ -0,0 +1,43 @@
+//! Runs the parts of the kernel that don't need the hardware on the host. The kernel's own
+//! sources for `mem::alloc`, `mem::addr`, `mem::frame`, `mem::vma`, the page tables, the log ring
+//! buffer, the pstore record format, the monitor's line editor, the GDB stub's packets and
+//! instruction decoding, and `sbi` are compiled here with `std`, against a heap buffer standing
+//! in for physical memory and an emulated SBI, so their `#[test_case]`s run under a plain
+//! `cargo test`.
+
+// Only the tests use the kernel's code, and only some of it.
+#![allow(dead_code)]
+#![allow(clippy::unusual_byte_groupings)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::runner)]
+
+extern crate alloc;
+
+mod boot;
+#[path = "../../src/gdb/packet.rs"]
+mod gdb_packet;
+#[path = "../../src/gdb/step.rs"]
+mod gdb_step;
+#[cfg(test)]
+mod io;
+// Doesn't depend on the rest of the kernel's `io`
+#[path = "../../src/io/logger/ring.rs"]
+mod log_ring;
+mod mem;
+#[path = "../../src/monitor/editor.rs"]
+mod monitor_editor;
+#[path = "../../src/pstore/record.rs"]
+mod pstore_record;
+mod ram;
+#[path = "../../src/sbi/mod.rs"]
+mod sbi;
+#[cfg(test)]
+#[path = "../../src/test.rs"]
+mod test;
+
+#[cfg(test)]
+fn runner(tests: &[&dyn test::Testable]) {
+    ram::init();
+    sbi::set_backend(&sbi::emulator::EMULATOR);
+    test::runner(tests);
+}
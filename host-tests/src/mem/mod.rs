@@ -0,0 +1,15 @@
+//! The parts of the kernel's `mem` that don't need the hardware, built from the kernel's
+//! sources. `paging` leaves out address spaces and ASIDs on the host.
+
+pub const PAGE_SIZE: usize = 4096;
+
+#[path = "../../../src/mem/addr.rs"]
+pub mod addr;
+#[path = "../../../src/mem/alloc.rs"]
+pub mod alloc;
+#[path = "../../../src/mem/frame.rs"]
+pub mod frame;
+#[path = "../../../src/mem/paging/mod.rs"]
+pub mod paging;
+#[path = "../../../src/mem/vma.rs"]
+pub mod vma;
@@ -0,0 +1,44 @@
+//! Simulated physical memory: a buffer on the heap stands in for RAM at `RAM_START`, and
+//! `VirtAddr::from_phys` is pointed at it.
+
+use std::alloc::{Layout, alloc_zeroed};
+
+use crate::mem::{
+    PAGE_SIZE,
+    addr::{self, PhysAddr, PhysMap},
+    alloc::PAGE_ALLOCATOR,
+    paging::{self, PagingMode},
+};
+
+/// Where the simulated RAM starts, as on QEMU's virt machine.
+pub const RAM_START: PhysAddr = PhysAddr(0x80000000);
+pub const RAM_SIZE: usize = 64 * 1024 * 1024;
+
+// Holds the address of the buffer.
+struct Buffer(usize);
+
+impl PhysMap for Buffer {
+    fn phys_to_virt(&self, phys: PhysAddr) -> Option<usize> {
+        let offset = phys.as_usize().checked_sub(RAM_START.as_usize())?;
+        (offset < RAM_SIZE).then(|| self.0 + offset)
+    }
+}
+
+/// Sets up the simulated RAM and gives all of it to `PAGE_ALLOCATOR`. Must be called once,
+/// before anything touches physical memory.
+pub fn init() {
+    // The buffer can be anywhere in the host's address space, and only Sv57 takes every user
+    // address of the host as canonical.
+    paging::set_mode(PagingMode::Sv57);
+
+    let layout = Layout::from_size_align(RAM_SIZE, PAGE_SIZE).unwrap();
+    let buffer = unsafe { alloc_zeroed(layout) };
+    assert!(!buffer.is_null(), "no memory for the simulated RAM");
+    addr::set_phys_map(Box::leak(Box::new(Buffer(buffer as usize))));
+
+    unsafe {
+        PAGE_ALLOCATOR
+            .lock()
+            .claim_range(RAM_START, RAM_START + RAM_SIZE)
+    };
+}
@@ -0,0 +1,44 @@
+//! Frame-pointer based stack walking. The kernel is always built with
+//! `-C force-frame-pointers=yes` (see `.cargo/config.toml`), so every frame stores the caller's
+//! return address at `fp - 8` and the caller's frame pointer at `fp - 16`.
+
+use core::arch::asm;
+
+/// Calls `f` with the return address of each frame, innermost first, until `f` returns false or
+/// the chain ends. `_boot` zeroes `s0`, so the walk stops at `kmain`.
+#[inline(always)]
+pub fn walk(mut f: impl FnMut(usize) -> bool) {
+    let mut fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+
+    // Frame pointers only ever point into the kernel half of the address space.
+    while fp != 0 && fp.is_multiple_of(8) && (fp as isize) < 0 {
+        let (ra, prev_fp) = unsafe { (*(fp as *const usize).sub(1), *(fp as *const usize).sub(2)) };
+        if ra == 0 || !f(ra) {
+            break;
+        }
+
+        // Stacks grow down, so the caller's frame always sits above ours.
+        if prev_fp <= fp {
+            break;
+        }
+        fp = prev_fp;
+    }
+}
+
+/// Collects up to `N` return addresses, skipping the innermost `skip` frames. Unused slots are 0.
+#[inline(always)]
+pub fn capture<const N: usize>(mut skip: usize) -> [usize; N] {
+    let mut addrs = [0; N];
+    let mut i = 0;
+    walk(|ra| {
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            addrs[i] = ra;
+            i += 1;
+        }
+        i < N
+    });
+    addrs
+}
@@ -1,76 +1,139 @@
-use core::arch::naked_asm;
+use core::{
+    arch::{global_asm, naked_asm},
+    ops::Range,
+};
 
 use crate::{
     kmain,
     mem::{
         addr::{PhysAddr, VirtAddr},
         paging::{
+            PageType,
             entry::{Entry, EntryFlags},
-            table::{ENTRY_COUNT, RawTable},
+            table::RawTable,
         },
     },
 };
 
-pub const PHYS_RAM_START: PhysAddr = PhysAddr(0x80000000);
-pub const VIRT_RAM_START: VirtAddr = VirtAddr::new(0xffffffffc0000000);
+/// Where the GiB of physical memory the kernel was loaded into is linearly mapped.
+pub const VIRT_RAM_START: VirtAddr = VirtAddr::new_const(0xffffffffc0000000);
+const VIRT_RAM_ROOT_INDEX: usize = VIRT_RAM_START.vpn2();
+
+/// Link address of the image, see yaro.ld. `kaslr` slides it within the GiB that starts here.
+pub const VIRT_KERNEL: VirtAddr = VirtAddr::new_const(0xffffffff80000000);
 
-pub const PHYS_PHEAP: PhysAddr = PhysAddr(0x83000000);
-pub const VIRT_PHEAP: VirtAddr = VirtAddr::new(VIRT_RAM_START.as_usize() - STACK_LEN - PHEAP_LEN);
-pub const PHEAP_LEN: usize = 0x200000;
+/// The image is mapped with pages of this size, so it's loaded and slid at this alignment.
+pub const IMAGE_PAGE: PageType = PageType::Mega;
+/// Index of the boot root table's entry covering `VIRT_KERNEL`.
+pub const IMAGE_ROOT_INDEX: usize = VIRT_KERNEL.vpn2();
 
-const PHYS_STACK: PhysAddr = PhysAddr(PHYS_PHEAP.as_usize() + PHEAP_LEN);
-const STACK_LEN: usize = 0x200000;
-const STACK_TOP: VirtAddr = VIRT_RAM_START;
+unsafe extern "C" {
+    static __image_start: u8;
+    static __image_end: u8;
+    static __pheap_start: u8;
+    static __pheap_end: u8;
+}
 
+/// Physical address the image was loaded at, stored by `_boot`.
 #[unsafe(link_section = ".boot.data")]
-static DATA_PT: RawTable = {
-    let mut table = [Entry::new(); ENTRY_COUNT];
+static mut LOAD_BASE: usize = 0;
 
-    // bitwise or (|) operator is not const, so this is needed
-    let flags = EntryFlags::VALID
-        .union(EntryFlags::READ)
-        .union(EntryFlags::WRITE)
-        .union(EntryFlags::GLOBAL);
+/// Physical address the image was loaded at.
+pub fn phys_kernel() -> PhysAddr {
+    PhysAddr(unsafe { LOAD_BASE })
+}
+
+/// Start of the GiB of physical memory that's mapped at `VIRT_RAM_START`, the one the image
+/// was loaded into.
+pub fn phys_ram_start() -> PhysAddr {
+    let giga = PageType::Giga.size();
+    PhysAddr(phys_kernel().as_usize() / giga * giga)
+}
 
-    let pheap_entry = Entry::new().with_ppn(PHYS_PHEAP.ppn()).with_flags(flags);
-    let stack_entry = Entry::new().with_ppn(PHYS_STACK.ppn()).with_flags(flags);
+/// Where the image currently is in virtual memory, including .bss, the boot stack and the
+/// pheap.
+pub fn image_virt_range() -> Range<VirtAddr> {
+    VirtAddr::new(&raw const __image_start as usize)..VirtAddr::new(&raw const __image_end as usize)
+}
 
-    table[510] = pheap_entry;
-    table[511] = stack_entry;
+fn image_phys(virt: *const u8) -> PhysAddr {
+    phys_kernel() + (virt as usize - &raw const __image_start as usize)
+}
 
-    RawTable(table)
-};
+/// Physical memory occupied by the kernel image, including .bss and the boot stack.
+pub fn kernel_phys_range() -> Range<PhysAddr> {
+    phys_kernel()..image_phys(&raw const __pheap_start)
+}
 
-const KERNEL_PTE: Entry = {
-    let flags = EntryFlags::VALID
-        .union(EntryFlags::READ)
-        .union(EntryFlags::WRITE)
-        .union(EntryFlags::EXECUTE)
-        .union(EntryFlags::GLOBAL);
+/// Physical memory of the pheap, which seeds the page allocator.
+pub fn pheap_phys_range() -> Range<PhysAddr> {
+    image_phys(&raw const __pheap_start)..image_phys(&raw const __pheap_end)
+}
 
-    Entry::new()
-        .with_ppn(PHYS_RAM_START.ppn())
-        .with_flags(flags)
-};
+const IMAGE_FLAGS: EntryFlags = EntryFlags::VALID
+    .union(EntryFlags::READ)
+    .union(EntryFlags::WRITE)
+    .union(EntryFlags::EXECUTE)
+    .union(EntryFlags::GLOBAL)
+    .union(EntryFlags::ACCESSED)
+    .union(EntryFlags::DIRTY);
 
-// This is the primary kernel page table.
-// The kernel resides at 0x82000000 in physical memory, part of the gigapage [0x80000000, 0xc0000000).
-// The last GiB of virtual memory is mapped to this gigapage, so that 0xffffffffc2000000 corresponds to
-// 0x82000000. Also, it's identity mapped, so that a page fault doesn't happen right after enabling
-// paging.
-//
-// We can't add a PTE for DATA_PT at compile time, so that needs to be done at runtime in `_boot`.
+/// Maps the image, filled in by `_boot` and moved by `kaslr`. Hangs off the boot root table at
+/// `IMAGE_ROOT_INDEX`.
 #[unsafe(link_section = ".boot.data")]
-static mut KERNEL_PT: RawTable = {
-    let mut table = [Entry::new(); ENTRY_COUNT];
+pub static mut KERNEL_IMAGE_PT: RawTable = RawTable::new();
 
-    // identity map 0x8000000
-    table[VirtAddr::new(PHYS_RAM_START.as_usize()).vpn2()] = KERNEL_PTE;
+// Flags of the gigapage mapping RAM, both at `VIRT_RAM_START` and identity mapped.
+const RAM_FLAGS: EntryFlags = EntryFlags::VALID
+    .union(EntryFlags::READ)
+    .union(EntryFlags::WRITE)
+    .union(EntryFlags::EXECUTE)
+    .union(EntryFlags::GLOBAL);
 
-    // map high memory (last virtual GiB + 2 MiB)
-    table[511] = KERNEL_PTE;
-    RawTable(table)
-};
+// This is the primary kernel page table, filled in by `_boot` once it knows where it was loaded.
+// The RAM gigapage the image is in is mapped at the last GiB of virtual memory, which is how
+// `VirtAddr::from_phys` reaches physical memory. Also, it's identity mapped, so that a page
+// fault doesn't happen right after enabling paging. The image itself is mapped through
+// `KERNEL_IMAGE_PT`.
+#[unsafe(link_section = ".boot.data")]
+static mut KERNEL_PT: RawTable = RawTable::new();
+
+/// Version 0.2 of the `Image` header, the first to have `magic2`.
+const IMAGE_HEADER_VERSION: u32 = 2;
+
+// The header of Linux's RISC-V `Image` format, so the flat binary made with `objcopy -O binary`
+// can be booted by U-Boot's `booti`, OpenSBI's `fw_payload` and the like. Its sizes come from
+// yaro.ld.
+global_asm!(
+    ".pushsection .boot.header, \"ax\"",
+    ".global _start",
+    "_start:",
+    // code0, code1: jump over the header. Kept uncompressed so the fields stay where they are.
+    ".option push",
+    ".option norvc",
+    "j _boot",
+    ".option pop",
+    ".word 0",
+    // text_offset
+    ".quad __text_offset",
+    // image_size
+    ".quad __image_size",
+    // flags: little endian
+    ".quad 0",
+    // version
+    ".word {version}",
+    // res1, res2
+    ".word 0",
+    ".quad 0",
+    // magic, deprecated in favour of magic2
+    ".ascii \"RISCV\\0\\0\\0\"",
+    // magic2
+    ".ascii \"RSC\\x05\"",
+    // res3, the PE header offset for EFI stubs
+    ".word 0",
+    ".popsection",
+    version = const IMAGE_HEADER_VERSION,
+);
 
 #[unsafe(link_section = ".boot.start")]
 #[unsafe(no_mangle)]
@@ -79,14 +142,71 @@ unsafe extern "C" fn _boot() -> ! {
     #[allow(unused_unsafe)]
     unsafe {
         naked_asm!(
+            // Paging is off, so everything before the jump to kmain must be PC-relative (lla,
+            // never la, which would go through the not yet relocated GOT).
+
+            // Zero .bss before anything reads it. A warm reboot doesn't reload the image, so it
+            // would still hold the last boot's locks and pointers.
+            "lla t0, _sbss",
+            "lla t1, _ebss",
+            "2:",
+            "bgeu t0, t1, 4f",
+            "sd zero, 0(t0)",
+            "addi t0, t0, 8",
+            "j 2b",
+            "4:",
+
+            // t0 = physical address of the image, which lla computes with auipc
+            "lla t0, __image_start",
+            "lla t1, {load_base}",
+            "sd t0, 0(t1)",
+
+            // The image is mapped with megapages, so it can't be loaded at a finer alignment.
+            // There's no console yet to complain on, so just hang.
+            "li t1, {page_size} - 1",
+            "and t1, t0, t1",
+            "bnez t1, 3f",
+
+            // map the image at its link address, one megapage at a time
+            // t0 = physical address of the image, t1 = its end
+            "lla t1, __image_end",
+            // t2 = &KERNEL_IMAGE_PT[0]
+            "lla t2, {ipt}",
+            "li t5, {page_size}",
+            "1:",
+            // *t2 = (t0 >> 12) << 10 | flags
+            "srli t3, t0, 12",
+            "slli t3, t3, 10",
+            "ori t3, t3, {image_flags}",
+            "sd t3, 0(t2)",
+            "add t0, t0, t5",
+            "addi t2, t2, 8",
+            "bltu t0, t1, 1b",
+
             // t0 = KERNEL_PT
-            "la t0, {kpt}",
+            "lla t0, {kpt}",
+
+            // map the RAM gigapage containing the image, identity mapped and at
+            // VIRT_RAM_START
+            // t1 = gigapage number of the image, t2 = its pte
+            "lla t1, __image_start",
+            "srli t1, t1, 30",
+            "slli t2, t1, 28",
+            "ori t2, t2, {ram_flags}",
+            // t0[t1] = t2
+            "slli t1, t1, 3",
+            "add t1, t0, t1",
+            "sd t2, 0(t1)",
+            // t0[VIRT_RAM_ROOT_INDEX] = t2
+            "li t1, {ram_entry_offset}",
+            "add t1, t0, t1",
+            "sd t2, 0(t1)",
 
             // t1 is our pte register
-            // t1 = &DATA_PT
-            "la t1, {dpt}",
+            // t1 = &KERNEL_IMAGE_PT
+            "lla t1, {ipt}",
 
-            // spte = (spt >> 12) & 0xfffffffffff << 10 | 0xe1
+            // spte = (spt >> 12) & 0xfffffffffff << 10 | 0x21
             // t1 >> 12
             "srli t1, t1, 12",
             // t1 & 0xfffffffffff
@@ -98,9 +218,9 @@ unsafe extern "C" fn _boot() -> ! {
             // NOTE: non-leaf PTEs mustn't have the Dirty or Accessed bits set.
             "ori t1, t1, 0x21",
 
-            // t0[510] = t1
-            "li t2, 4080",
-            // t2 = t0 + 4080
+            // t0[IMAGE_ROOT_INDEX] = t1
+            "li t2, {image_entry_offset}",
+            // t2 = t0 + offset
             "add t2, t0, t2",
             // *t2 = t1
             "sd t1, 0(t2)",
@@ -120,15 +240,35 @@ unsafe extern "C" fn _boot() -> ! {
             // flush tlb
             "sfence.vma",
 
-            "li sp, {stack_top}",
+            // t1 = VIRT_KERNEL - physical address of the image, which turns the physical
+            // addresses lla gives into virtual ones
+            "lla t0, __image_start",
+            "li t1, {virt_kernel}",
+            "sub t1, t1, t0",
+
+            "lla sp, __stack_top",
+            "add sp, sp, t1",
+            // terminate the frame pointer chain for `backtrace::walk`
+            "li s0, 0",
+            "li ra, 0",
 
             // call kmain
-            "lui t0, %hi({kmain})",
-            "addi t0, t0, %lo({kmain})",
+            "lla t0, {kmain}",
+            "add t0, t0, t1",
             "jr t0",
+
+            "3:",
+            "wfi",
+            "j 3b",
+            load_base = sym LOAD_BASE,
             kpt = sym KERNEL_PT,
-            dpt = sym DATA_PT,
-            stack_top = const STACK_TOP.as_usize(),
+            ipt = sym KERNEL_IMAGE_PT,
+            page_size = const IMAGE_PAGE.size(),
+            image_flags = const IMAGE_FLAGS.bits(),
+            image_entry_offset = const IMAGE_ROOT_INDEX * size_of::<Entry>(),
+            ram_flags = const RAM_FLAGS.bits(),
+            ram_entry_offset = const VIRT_RAM_ROOT_INDEX * size_of::<Entry>(),
+            virt_kernel = const VIRT_KERNEL.as_usize(),
             kmain = sym kmain,
         )
     }
@@ -0,0 +1,234 @@
+//! The kernel command line, from `/chosen/bootargs`. It's a list of whitespace separated options,
+//! each either `name=value` or a bare `name`. Every option sets a `Param`, which is defined by
+//! the subsystem it configures and has to be listed in `PARAMS`.
+
+use core::fmt::{self, Display};
+
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use crate::{gdb, io, mem, monitor, pstore, sched};
+
+/// Every param that can be set from the command line.
+static PARAMS: &[&dyn AnyParam] = &[
+    &gdb::WAIT,
+    &io::LOGLEVEL,
+    &io::logger::FILTER,
+    &io::serial::CONSOLE,
+    &mem::heap::HEAP_MAX,
+    &mem::swap::ENABLE,
+    &monitor::MONITOR,
+    &pstore::REBOOT,
+    &sched::INIT,
+];
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// A type a param can have.
+pub trait Value: Copy + Display + Send + Sync + 'static {
+    /// Parses the value given on the command line, which is `None` for a bare `name`.
+    fn parse(value: Option<&'static str>) -> Option<Self>;
+}
+
+/// A boot parameter: its value from the command line, or its default if it wasn't given.
+pub struct Param<T> {
+    name: &'static str,
+    default: T,
+    value: Mutex<Option<T>>,
+}
+
+trait AnyParam: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns whether `value` was valid.
+    fn set(&self, value: Option<&'static str>) -> bool;
+
+    fn fmt_value(&self, f: &mut fmt::Formatter) -> fmt::Result;
+}
+
+impl<T: Value> Param<T> {
+    pub const fn new(name: &'static str, default: T) -> Self {
+        Self {
+            name,
+            default,
+            value: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        self.value.lock().unwrap_or(self.default)
+    }
+
+    /// Like `get`, but returns `None` instead of waiting if the value is locked, as it may be
+    /// for good by a hart that panicked.
+    pub fn try_get(&self) -> Option<T> {
+        self.value
+            .try_lock()
+            .map(|value| value.unwrap_or(self.default))
+    }
+}
+
+impl<T: Value> AnyParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&self, value: Option<&'static str>) -> bool {
+        let Some(value) = T::parse(value) else {
+            return false;
+        };
+        *self.value.lock() = Some(value);
+        true
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+/// Flags: a bare `name` is true, and so is `name=1`, `name=y` and `name=on`.
+impl Value for bool {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        match value {
+            None | Some("1" | "y" | "yes" | "on" | "true") => Some(true),
+            Some("0" | "n" | "no" | "off" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Numbers, decimal or `0x` hexadecimal, optionally followed by a K, M or G suffix.
+impl Value for usize {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        let value = value?;
+        let (digits, shift) = match value.as_bytes().last()? {
+            b'k' | b'K' => (&value[..value.len() - 1], 10),
+            b'm' | b'M' => (&value[..value.len() - 1], 20),
+            b'g' | b'G' => (&value[..value.len() - 1], 30),
+            _ => (value, 0),
+        };
+
+        let number = match digits.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+            None => digits.parse().ok()?,
+        };
+        number.checked_mul(1 << shift)
+    }
+}
+
+/// Strings, which point into the device tree. It's never freed, so they live forever.
+impl Value for &'static str {
+    fn parse(value: Option<&'static str>) -> Option<Self> {
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+/// Sets every param given in `cmdline`, and reports options that aren't params or have invalid
+/// values.
+pub fn parse(cmdline: &'static str) {
+    CMDLINE.call_once(|| cmdline);
+    info!("cmdline: {cmdline}");
+
+    for option in cmdline.split_whitespace() {
+        let (name, value) = split_option(option);
+        match PARAMS.iter().find(|param| param.name() == name) {
+            Some(param) => {
+                if !param.set(value) {
+                    warn!("cmdline: invalid value for {name}: {option}");
+                }
+            }
+            None => {
+                warn!("cmdline: unknown option {option}");
+            }
+        }
+    }
+}
+
+// `name=value` or a bare `name`. Only the first `=` splits, so the value may have more.
+fn split_option(option: &str) -> (&str, Option<&str>) {
+    match option.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (option, None),
+    }
+}
+
+/// The command line as it was given, or an empty one before `parse`.
+pub fn raw() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+struct ParamValue(&'static dyn AnyParam);
+
+impl Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_value(f)
+    }
+}
+
+/// Every param and its current value, formatted.
+pub fn params() -> impl Iterator<Item = (&'static str, impl Display)> {
+    PARAMS
+        .iter()
+        .map(|&param| (param.name(), ParamValue(param)))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test_case]
+    fn flags_take_the_usual_spellings() {
+        for value in [
+            None,
+            Some("1"),
+            Some("y"),
+            Some("yes"),
+            Some("on"),
+            Some("true"),
+        ] {
+            assert_eq!(bool::parse(value), Some(true));
+        }
+        for value in ["0", "n", "no", "off", "false"] {
+            assert_eq!(bool::parse(Some(value)), Some(false));
+        }
+        assert_eq!(bool::parse(Some("maybe")), None);
+        assert_eq!(bool::parse(Some("")), None);
+    }
+
+    #[test_case]
+    fn numbers_take_hex_and_suffixes() {
+        let parse = |value| usize::parse(Some(value));
+        assert_eq!(parse("42"), Some(42));
+        assert_eq!(parse("0x1f"), Some(0x1f));
+        assert_eq!(parse("4K"), Some(4 << 10));
+        assert_eq!(parse("16m"), Some(16 << 20));
+        assert_eq!(parse("0x2G"), Some(2 << 30));
+
+        for invalid in ["", "K", "0x", "1.5M", "-1", "12T", "0xfffffffffffffffffK"] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+        assert_eq!(parse("0xffffffffffffffffK"), None);
+        assert_eq!(usize::parse(None), None);
+    }
+
+    #[test_case]
+    fn options_split_at_the_first_equals() {
+        assert_eq!(split_option("loglevel=7"), ("loglevel", Some("7")));
+        assert_eq!(split_option("nokaslr"), ("nokaslr", None));
+        assert_eq!(split_option("init=/bin/sh=x"), ("init", Some("/bin/sh=x")));
+        assert_eq!(split_option("init="), ("init", Some("")));
+        assert_eq!(<&str>::parse(Some("")), None);
+    }
+
+    #[test_case]
+    fn invalid_values_keep_the_default() {
+        static PARAM: Param<usize> = Param::new("test", 7);
+        assert!(!PARAM.set(Some("seven")));
+        assert_eq!(PARAM.get(), 7);
+        assert!(PARAM.set(Some("1K")));
+        assert_eq!(PARAM.get(), 1024);
+        assert_eq!(ParamValue(&PARAM).to_string(), "1024");
+    }
+}
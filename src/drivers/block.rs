@@ -0,0 +1,55 @@
+//! Block devices: storage addressed in fixed-size blocks.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::Mutex;
+
+/// Block devices bound by drivers, in the order they were found.
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request runs past the end of the device, or the buffer isn't a whole number of
+    /// blocks.
+    OutOfRange,
+    /// The device reported an error.
+    Io,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads `buf.len()` bytes starting at `block`. The length must be a multiple of the block
+    /// size.
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf` starting at `block`. The length must be a multiple of the block size.
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Checks that a request of `len` bytes at `block` is whole blocks and fits the device.
+    fn check_range(&self, block: u64, len: usize) -> Result<(), BlockError> {
+        let size = self.block_size();
+        let blocks = (len / size) as u64;
+        if !len.is_multiple_of(size)
+            || block
+                .checked_add(blocks)
+                .is_none_or(|end| end > self.block_count())
+        {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+/// Makes a block device available to the rest of the kernel.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push(device);
+}
+
+/// Every block device, in the order they were found.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
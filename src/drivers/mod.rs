@@ -0,0 +1,228 @@
+//! Device discovery. Every device tree node with a `compatible` property is a device, and is
+//! bound to the first driver in `DRIVERS` that handles one of its compatible strings. Drivers get
+//! the device's `reg` ranges already mapped and its `interrupts` resolved to their controller.
+
+pub mod block;
+pub mod ns16550;
+pub mod sifive_test;
+pub mod virtio;
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, ptr::NonNull};
+
+use fdt::{Fdt, node::FdtNode};
+use log::{debug, info, warn};
+
+use crate::mem::{
+    addr::PhysAddr,
+    vmalloc::{ioremap, iounmap},
+};
+
+/// Every driver, tried in order.
+static DRIVERS: &[&dyn Driver] = &[
+    &virtio::VirtioMmio,
+    &sifive_test::SifiveTest,
+    &ns16550::Ns16550,
+];
+
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The `compatible` strings of the devices this driver handles.
+    fn compatible(&self) -> &'static [&'static str];
+
+    /// Takes over `device`. Its registers are unmapped again if this fails.
+    fn probe(&self, device: &Device) -> Result<(), ProbeError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The node describes a slot with nothing in it, like an unused virtio-mmio transport.
+    Absent,
+    /// The device is there, but the driver doesn't support it.
+    Unsupported,
+    OutOfMemory,
+    /// A `reg` range has no size or couldn't be mapped.
+    BadReg,
+}
+
+/// A range of device registers, mapped into the vmalloc area.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    pub phys: PhysAddr,
+    pub len: usize,
+    pub base: NonNull<u8>,
+}
+
+/// An interrupt of a device: the controller it goes to and the cells describing it, whose
+/// meaning is up to the controller. For the PLIC, the only cell is the source number.
+#[derive(Debug, Clone)]
+pub struct Interrupt<'a> {
+    pub controller: &'a str,
+    pub specifier: Vec<u32>,
+}
+
+/// A device found in the device tree, as handed to `Driver::probe`.
+pub struct Device<'a> {
+    pub node: FdtNode<'a, 'a>,
+    pub regs: Vec<Mmio>,
+    pub interrupts: Vec<Interrupt<'a>>,
+}
+
+impl Interrupt<'_> {
+    /// The first cell, which is the interrupt number for every common controller.
+    pub fn number(&self) -> Option<u32> {
+        self.specifier.first().copied()
+    }
+}
+
+fn cells(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+}
+
+fn interrupt_cells(controller: FdtNode) -> usize {
+    controller.interrupt_cells().unwrap_or(1).max(1)
+}
+
+// The `fdt` crate doesn't know a node's ancestors, so an `interrupt-parent` that isn't on the node
+// itself is taken from the root, which is where it's usually inherited from.
+fn interrupts<'a>(fdt: &'a Fdt<'a>, node: FdtNode<'a, 'a>) -> Vec<Interrupt<'a>> {
+    let mut interrupts = Vec::new();
+
+    // Pairs of a controller's phandle and its cells
+    if let Some(extended) = node.property("interrupts-extended") {
+        let mut cells = cells(extended.value);
+        while let Some(controller) = cells.next().and_then(|phandle| fdt.find_phandle(phandle)) {
+            let specifier: Vec<u32> = cells.by_ref().take(interrupt_cells(controller)).collect();
+            interrupts.push(Interrupt {
+                controller: controller.name,
+                specifier,
+            });
+        }
+        return interrupts;
+    }
+
+    let Some(property) = node.property("interrupts") else {
+        return interrupts;
+    };
+    let controller = node
+        .interrupt_parent()
+        .or_else(|| fdt.find_node("/")?.interrupt_parent());
+    let Some(controller) = controller else {
+        return interrupts;
+    };
+
+    let specifiers: Vec<u32> = cells(property.value).collect();
+    for specifier in specifiers.chunks(interrupt_cells(controller)) {
+        interrupts.push(Interrupt {
+            controller: controller.name,
+            specifier: specifier.into(),
+        });
+    }
+    interrupts
+}
+
+fn map_regs(node: FdtNode) -> Result<Vec<Mmio>, ProbeError> {
+    let mut regs = Vec::new();
+    for reg in node.reg().into_iter().flatten() {
+        let phys = PhysAddr(reg.starting_address as usize);
+        let mapping = reg.size.and_then(|len| Some((len, ioremap(phys, len)?)));
+        let Some((len, base)) = mapping else {
+            unmap_regs(&regs);
+            return Err(ProbeError::BadReg);
+        };
+        regs.push(Mmio { phys, len, base });
+    }
+    Ok(regs)
+}
+
+fn unmap_regs(regs: &[Mmio]) {
+    for reg in regs {
+        unsafe { iounmap(reg.base) };
+    }
+}
+
+fn find_driver(node: FdtNode) -> Option<&'static dyn Driver> {
+    // A node's compatible strings go from most to least specific.
+    let compatible = node.compatible()?;
+    compatible.all().find_map(|name| {
+        DRIVERS
+            .iter()
+            .find(|driver| driver.compatible().contains(&name))
+            .copied()
+    })
+}
+
+/// Probes the driver for `node`, if there is one. Returns the driver and the device if it bound.
+fn probe_node<'a>(
+    fdt: &'a Fdt<'a>,
+    node: FdtNode<'a, 'a>,
+) -> Result<Option<(&'static dyn Driver, Device<'a>)>, ProbeError> {
+    let Some(driver) = find_driver(node) else {
+        return Ok(None);
+    };
+
+    let device = Device {
+        node,
+        regs: map_regs(node)?,
+        interrupts: interrupts(fdt, node),
+    };
+    if let Err(err) = driver.probe(&device) {
+        unmap_regs(&device.regs);
+        return Err(err);
+    }
+    Ok(Some((driver, device)))
+}
+
+/// Binds drivers to every device in the device tree, and logs what was found.
+///
+/// # Safety
+/// Must be called once, after `vmalloc::init`.
+pub unsafe fn probe<'a>(fdt: &'a Fdt<'a>) {
+    for node in fdt.all_nodes() {
+        let Some(compatible) = node.compatible().map(|compatible| compatible.first()) else {
+            continue;
+        };
+
+        let disabled = node
+            .property("status")
+            .and_then(|status| status.as_str())
+            .is_some_and(|status| status != "okay" && status != "ok");
+        if disabled {
+            debug!("drivers: {} ({compatible}): disabled", node.name);
+            continue;
+        }
+
+        match probe_node(fdt, node) {
+            Ok(None) => {
+                debug!("drivers: {} ({compatible}): no driver", node.name);
+            }
+            Ok(Some((driver, device))) => {
+                let mut resources = String::new();
+                for reg in &device.regs {
+                    let _ = write!(
+                        resources,
+                        ", [{:#x}, {:#x})",
+                        reg.phys.as_usize(),
+                        reg.phys.as_usize() + reg.len
+                    );
+                }
+                for interrupt in &device.interrupts {
+                    if let Some(number) = interrupt.number() {
+                        let _ = write!(resources, ", irq {number} on {}", interrupt.controller);
+                    }
+                }
+                info!(
+                    "drivers: {} ({compatible}): bound to {}{resources}",
+                    node.name,
+                    driver.name()
+                );
+            }
+            Err(err) => {
+                warn!("drivers: {} ({compatible}): unbound, {err:?}", node.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! 16550-compatible UARTs, polled. The console UART belongs to the firmware, which the kernel
+//! prints through with SBI, so it's bound but never touched. The others are left for the kernel,
+//! like the GDB stub's.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{Device, Driver, ProbeError};
+use crate::mem::addr::PhysAddr;
+
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+// With the divisor latch enabled, RBR_THR and IER are the divisor.
+const DLL: usize = 0;
+const DLM: usize = 1;
+
+const FCR_ENABLE_AND_CLEAR: u8 = 0b111;
+const LCR_8N1: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+// DTR and RTS
+const MCR_READY: u8 = 0b11;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const BAUD: usize = 115_200;
+
+static PORTS: Mutex<Vec<Uart>> = Mutex::new(Vec::new());
+
+pub struct Ns16550;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    phys: PhysAddr,
+    base: usize,
+    // log2 of the distance between registers
+    reg_shift: usize,
+    clock: Option<usize>,
+}
+
+impl Driver for Ns16550 {
+    fn name(&self) -> &'static str {
+        "ns16550"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["ns16550a", "ns16550"]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let reg = device.regs.first().ok_or(ProbeError::BadReg)?;
+        let property = |name| {
+            device
+                .node
+                .property(name)
+                .and_then(|value| value.as_usize())
+        };
+        // Only byte-wide registers, as on QEMU
+        if property("reg-io-width").is_some_and(|width| width != 1) {
+            return Err(ProbeError::Unsupported);
+        }
+
+        PORTS.lock().push(Uart {
+            phys: reg.phys,
+            base: reg.base.as_ptr() as usize,
+            reg_shift: property("reg-shift").unwrap_or(0),
+            clock: property("clock-frequency"),
+        });
+        Ok(())
+    }
+}
+
+/// Every UART bound, the console's included.
+pub fn ports() -> Vec<Uart> {
+    PORTS.lock().clone()
+}
+
+impl Uart {
+    /// Where the registers are, as in the device tree.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Sets the UART up for 8N1 at 115200 baud, with interrupts off. The baud rate is left alone
+    /// if the device tree doesn't give the clock.
+    pub fn init(&self) {
+        self.write(IER, 0);
+        if let Some(clock) = self.clock {
+            let divisor = (clock / (16 * BAUD)).max(1);
+            self.write(LCR, LCR_DLAB);
+            self.write(DLL, divisor as u8);
+            self.write(DLM, (divisor >> 8) as u8);
+        }
+        self.write(LCR, LCR_8N1);
+        self.write(FCR, FCR_ENABLE_AND_CLEAR);
+        self.write(MCR, MCR_READY);
+    }
+
+    /// The next byte received, if there's one.
+    pub fn read_byte(&self) -> Option<u8> {
+        (self.read(LSR) & LSR_DATA_READY != 0).then(|| self.read(RBR_THR))
+    }
+
+    /// Sends `byte`, waiting for room first.
+    pub fn write_byte(&self, byte: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(RBR_THR, byte);
+    }
+
+    fn read(&self, reg: usize) -> u8 {
+        unsafe { ((self.base + (reg << self.reg_shift)) as *const u8).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u8) {
+        unsafe { ((self.base + (reg << self.reg_shift)) as *mut u8).write_volatile(value) };
+    }
+}
@@ -0,0 +1,44 @@
+//! QEMU's test device, whose only register powers off or resets the machine. Unlike SBI's
+//! shutdown, it can make QEMU exit with a failure status.
+
+use spin::Once;
+
+use super::{Device, Driver, ProbeError};
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+// Virtual address of the register, once the device is bound.
+static FINISHER: Once<usize> = Once::new();
+
+pub struct SifiveTest;
+
+impl Driver for SifiveTest {
+    fn name(&self) -> &'static str {
+        "sifive-test"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sifive,test0"]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let reg = device.regs.first().ok_or(ProbeError::BadReg)?;
+        FINISHER.call_once(|| reg.base.as_ptr() as usize);
+        Ok(())
+    }
+}
+
+/// Powers off, making QEMU exit with `code`. Returns if there's no test device.
+pub fn exit(code: u16) {
+    let Some(&finisher) = FINISHER.get() else {
+        return;
+    };
+
+    let value = if code == 0 {
+        FINISHER_PASS
+    } else {
+        (code as u32) << 16 | FINISHER_FAIL
+    };
+    unsafe { (finisher as *mut u32).write_volatile(value) };
+}
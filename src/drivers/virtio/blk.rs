@@ -0,0 +1,170 @@
+//! Virtio block devices.
+
+use spin::Mutex;
+
+use super::{Buffer, Queue, Transport, VirtioError};
+use crate::{
+    drivers::block::{BlockDevice, BlockError},
+    mem::{
+        PAGE_SIZE,
+        addr::{PhysAddr, VirtAddr},
+        alloc::{Allocation, PAGE_ALLOCATOR},
+        frame::{self, FrameFlags},
+    },
+};
+
+const SECTOR_SIZE: usize = 512;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const STATUS_OK: u8 = 0;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RequestHeader {
+    ty: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk {
+    capacity: u64,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    transport: Transport,
+    queue: Queue,
+    // Data goes through this page, so callers' buffers needn't be physically contiguous.
+    bounce: PhysAddr,
+}
+
+impl VirtioBlk {
+    /// Initializes the device behind `transport`, which must be a `DeviceId::Block`.
+    pub fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.init()?;
+        let queue = transport.setup_queue()?;
+
+        let allocation = PAGE_ALLOCATOR
+            .lock()
+            .alloc(0)
+            .ok_or(VirtioError::OutOfMemory)?;
+        let (bounce, _) = allocation.into_raw();
+        if let Some(frame) = frame::frame(bounce) {
+            frame.set_flags(FrameFlags::KERNEL);
+            frame.get();
+        }
+
+        // Capacity in sectors, as two 32-bit halves
+        let capacity = transport.config(0) as u64 | (transport.config(4) as u64) << 32;
+
+        Ok(Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                transport,
+                queue,
+                bounce,
+            }),
+        })
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+
+        let mut inner = self.inner.lock();
+        let bounce = VirtAddr::from_phys(inner.bounce).as_ptr::<u8>();
+        for (i, chunk) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+            let sector = block + (i * PAGE_SIZE / SECTOR_SIZE) as u64;
+            inner.request(REQ_IN, sector, chunk.len())?;
+            unsafe {
+                chunk
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(bounce, chunk.len())
+            };
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(block, buf.len())?;
+
+        let mut inner = self.inner.lock();
+        let bounce = VirtAddr::from_phys(inner.bounce).as_ptr::<u8>();
+        for (i, chunk) in buf.chunks(PAGE_SIZE).enumerate() {
+            let sector = block + (i * PAGE_SIZE / SECTOR_SIZE) as u64;
+            unsafe { bounce.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len()) };
+            inner.request(REQ_OUT, sector, chunk.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn request(&mut self, ty: u32, sector: u64, len: usize) -> Result<(), BlockError> {
+        let header_phys = self.queue.scratch_phys();
+        let status_phys = header_phys + size_of::<RequestHeader>();
+        unsafe {
+            VirtAddr::from_phys(header_phys)
+                .as_ptr::<RequestHeader>()
+                .write_volatile(RequestHeader {
+                    ty,
+                    reserved: 0,
+                    sector,
+                });
+            VirtAddr::from_phys(status_phys)
+                .as_ptr::<u8>()
+                .write_volatile(0xff);
+        }
+
+        let buffers = [
+            Buffer {
+                phys: header_phys,
+                len: size_of::<RequestHeader>(),
+                device_writes: false,
+            },
+            Buffer {
+                phys: self.bounce,
+                len,
+                device_writes: ty == REQ_IN,
+            },
+            Buffer {
+                phys: status_phys,
+                len: 1,
+                device_writes: true,
+            },
+        ];
+        self.transport.submit(&mut self.queue, &buffers);
+
+        let status = unsafe {
+            VirtAddr::from_phys(status_phys)
+                .as_ptr::<u8>()
+                .read_volatile()
+        };
+        if status == STATUS_OK {
+            Ok(())
+        } else {
+            Err(BlockError::Io)
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(frame) = frame::frame(self.bounce) {
+            frame.put();
+        }
+        PAGE_ALLOCATOR
+            .lock()
+            .free(unsafe { Allocation::from_raw(self.bounce, 0) });
+    }
+}
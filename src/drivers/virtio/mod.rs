@@ -0,0 +1,340 @@
+//! Virtio devices behind the MMIO transport, as found on QEMU's virt machine. Both the legacy
+//! (version 1) and modern (version 2) register layouts are supported. Requests are submitted one
+//! at a time and completion is polled for, so no interrupts are needed.
+
+pub mod blk;
+
+use alloc::sync::Arc;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{Ordering, fence},
+};
+
+use self::blk::VirtioBlk;
+use super::{Device, Driver, ProbeError, block};
+use crate::mem::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr},
+    alloc::{Allocation, PAGE_ALLOCATOR},
+    frame::{self, FrameFlags},
+};
+
+const MAGIC: u32 = 0x74726976;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_ALIGN: usize = 0x03c;
+const REG_QUEUE_PFN: usize = 0x040;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080;
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0a0;
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+/// Bit 0 of the second feature word.
+const FEATURE_VERSION_1: u32 = 1;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Descriptors per queue. A request never needs more than three.
+const QUEUE_LEN: usize = 8;
+
+/// Device ID of a transport with no device behind it.
+const DEVICE_ID_NONE: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceId {
+    Block = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The registers don't belong to a virtio device.
+    NotFound,
+    /// The device rejected the features or queue it was offered.
+    Unsupported,
+    OutOfMemory,
+}
+
+/// The registers of a virtio-mmio device.
+pub struct Transport {
+    base: NonNull<u8>,
+    version: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer in a request: its physical address and length, and whether the device writes it.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub phys: PhysAddr,
+    pub len: usize,
+    pub device_writes: bool,
+}
+
+/// A single virtqueue laid out the legacy way: descriptors and the available ring in the first
+/// page, the used ring in the second.
+pub struct Queue {
+    phys: PhysAddr,
+    last_used: u16,
+}
+
+// The registers are only accessed through `&mut Transport` or under the device's lock.
+unsafe impl Send for Transport {}
+unsafe impl Sync for Transport {}
+
+impl Transport {
+    /// # Safety
+    /// `base` must map the registers of a virtio-mmio device.
+    pub unsafe fn new(base: NonNull<u8>) -> Result<Self, VirtioError> {
+        let mut transport = Self { base, version: 0 };
+        if transport.read(REG_MAGIC) != MAGIC {
+            return Err(VirtioError::NotFound);
+        }
+
+        transport.version = transport.read(REG_VERSION);
+        Ok(transport)
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { self.base.add(reg).cast::<u32>().read_volatile() }
+    }
+
+    fn write(&mut self, reg: usize, value: u32) {
+        unsafe { self.base.add(reg).cast::<u32>().write_volatile(value) }
+    }
+
+    /// The kind of device behind the transport, as a raw device ID.
+    pub fn device_id(&self) -> u32 {
+        self.read(REG_DEVICE_ID)
+    }
+
+    /// Reads a 32-bit word of the device-specific configuration.
+    pub fn config(&self, offset: usize) -> u32 {
+        self.read(REG_CONFIG + offset)
+    }
+
+    /// Resets the device and negotiates features, accepting none but the ones the transport
+    /// needs.
+    pub fn init(&mut self) -> Result<(), VirtioError> {
+        self.write(REG_STATUS, 0);
+        self.write(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write(REG_DRIVER_FEATURES_SEL, 0);
+        self.write(REG_DRIVER_FEATURES, 0);
+        if self.version >= 2 {
+            self.write(REG_DEVICE_FEATURES_SEL, 1);
+            if self.read(REG_DEVICE_FEATURES) & FEATURE_VERSION_1 == 0 {
+                return Err(VirtioError::Unsupported);
+            }
+            self.write(REG_DRIVER_FEATURES_SEL, 1);
+            self.write(REG_DRIVER_FEATURES, FEATURE_VERSION_1);
+
+            self.write(
+                REG_STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+            );
+            if self.read(REG_STATUS) & STATUS_FEATURES_OK == 0 {
+                return Err(VirtioError::Unsupported);
+            }
+        } else {
+            self.write(REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Sets up queue 0 and tells the device the driver is ready.
+    pub fn setup_queue(&mut self) -> Result<Queue, VirtioError> {
+        self.write(REG_QUEUE_SEL, 0);
+        if (self.read(REG_QUEUE_NUM_MAX) as usize) < QUEUE_LEN {
+            return Err(VirtioError::Unsupported);
+        }
+
+        let allocation = PAGE_ALLOCATOR
+            .lock()
+            .alloc(1)
+            .ok_or(VirtioError::OutOfMemory)?;
+        let (phys, _) = allocation.into_raw();
+        unsafe {
+            VirtAddr::from_phys(phys)
+                .as_ptr::<u8>()
+                .write_bytes(0, 2 * PAGE_SIZE)
+        };
+        for page in [phys, phys + PAGE_SIZE] {
+            if let Some(frame) = frame::frame(page) {
+                frame.set_flags(FrameFlags::KERNEL);
+                frame.get();
+            }
+        }
+
+        let queue = Queue { phys, last_used: 0 };
+        self.write(REG_QUEUE_NUM, QUEUE_LEN as u32);
+        if self.version >= 2 {
+            let addrs = [
+                (REG_QUEUE_DESC, queue.desc_phys()),
+                (REG_QUEUE_DRIVER, queue.avail_phys()),
+                (REG_QUEUE_DEVICE, queue.used_phys()),
+            ];
+            for (reg, addr) in addrs {
+                self.write(reg, addr.as_usize() as u32);
+                self.write(reg + 4, (addr.as_usize() >> 32) as u32);
+            }
+            self.write(REG_QUEUE_READY, 1);
+        } else {
+            self.write(REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(REG_QUEUE_PFN, (phys.as_usize() / PAGE_SIZE) as u32);
+        }
+
+        let status = self.read(REG_STATUS);
+        self.write(REG_STATUS, status | STATUS_DRIVER_OK);
+        Ok(queue)
+    }
+
+    /// Submits a request made of `buffers` on queue 0 and waits for the device to finish it.
+    pub fn submit(&mut self, queue: &mut Queue, buffers: &[Buffer]) {
+        assert!(buffers.len() <= QUEUE_LEN);
+
+        let descs = queue.descriptors();
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = 0;
+            if buffer.device_writes {
+                flags |= DESC_F_WRITE;
+            }
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            descs[i] = Descriptor {
+                addr: buffer.phys.as_usize() as u64,
+                len: buffer.len as u32,
+                flags,
+                next: i as u16 + 1,
+            };
+        }
+
+        // Available ring: flags, idx, then the ring itself
+        let avail = VirtAddr::from_phys(queue.avail_phys()).as_ptr::<u16>();
+        unsafe {
+            let idx = avail.add(1).read_volatile();
+            avail.add(2 + idx as usize % QUEUE_LEN).write_volatile(0);
+            fence(Ordering::SeqCst);
+            avail.add(1).write_volatile(idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        self.write(REG_QUEUE_NOTIFY, 0);
+
+        // Used ring: flags, then idx
+        let used_idx = unsafe {
+            VirtAddr::from_phys(queue.used_phys())
+                .as_ptr::<u16>()
+                .add(1)
+        };
+        while unsafe { used_idx.read_volatile() } == queue.last_used {
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        queue.last_used = queue.last_used.wrapping_add(1);
+
+        let status = self.read(REG_INTERRUPT_STATUS);
+        self.write(REG_INTERRUPT_ACK, status);
+    }
+}
+
+impl Queue {
+    fn desc_phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    fn avail_phys(&self) -> PhysAddr {
+        self.phys + QUEUE_LEN * size_of::<Descriptor>()
+    }
+
+    fn used_phys(&self) -> PhysAddr {
+        self.phys + PAGE_SIZE
+    }
+
+    /// Memory in the first page after the available ring, free for request headers.
+    pub fn scratch_phys(&self) -> PhysAddr {
+        self.phys + PAGE_SIZE / 2
+    }
+
+    fn descriptors(&mut self) -> &mut [Descriptor; QUEUE_LEN] {
+        unsafe { &mut *VirtAddr::from_phys(self.phys).as_ptr::<[Descriptor; QUEUE_LEN]>() }
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        for page in [self.phys, self.phys + PAGE_SIZE] {
+            if let Some(frame) = frame::frame(page) {
+                frame.put();
+            }
+        }
+        PAGE_ALLOCATOR
+            .lock()
+            .free(unsafe { Allocation::from_raw(self.phys, 1) });
+    }
+}
+
+impl From<VirtioError> for ProbeError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::NotFound => Self::Absent,
+            VirtioError::Unsupported => Self::Unsupported,
+            VirtioError::OutOfMemory => Self::OutOfMemory,
+        }
+    }
+}
+
+/// The driver for virtio-mmio transports, which binds the device behind them.
+pub struct VirtioMmio;
+
+impl Driver for VirtioMmio {
+    fn name(&self) -> &'static str {
+        "virtio-mmio"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["virtio,mmio"]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let regs = device.regs.first().ok_or(ProbeError::BadReg)?;
+        let transport = unsafe { Transport::new(regs.base)? };
+
+        match transport.device_id() {
+            DEVICE_ID_NONE => Err(ProbeError::Absent),
+            id if id == DeviceId::Block as u32 => {
+                block::register(Arc::new(VirtioBlk::new(transport)?));
+                Ok(())
+            }
+            _ => Err(ProbeError::Unsupported),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! The "newc" cpio format, which Linux initramfs archives use. Every entry is a 110 byte ASCII
+//! header, the NUL terminated name, and the data, with the name and data each padded to 4 bytes.
+//! The archive ends with an entry named `TRAILER!!!`.
+
+const MAGIC: &[u8] = b"070701";
+// Same layout, with a checksum of the data in the last field, which isn't checked.
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+// Field indices in the header, after the magic. Each one is 8 hex digits.
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+/// Mask of the file type bits of a mode.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    /// An entry doesn't start with a newc magic.
+    BadMagic,
+    /// A header field isn't hex, or a name isn't UTF-8 and NUL terminated.
+    BadHeader,
+    /// The archive ends in the middle of an entry, or before the trailer.
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// The entries of an archive, up to the trailer.
+pub struct Reader<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self {
+            archive,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn bytes(&self, start: usize, len: usize) -> Result<&'a [u8], CpioError> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.archive.get(start..end))
+            .ok_or(CpioError::Truncated)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self.bytes(self.offset, HEADER_LEN)?;
+        if &header[..MAGIC.len()] != MAGIC && &header[..MAGIC_CRC.len()] != MAGIC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+        let field = |index: usize| {
+            let start = MAGIC.len() + index * 8;
+            let digits = core::str::from_utf8(&header[start..start + 8]).ok()?;
+            u32::from_str_radix(digits, 16).ok()
+        };
+        let mode = field(FIELD_MODE).ok_or(CpioError::BadHeader)?;
+        let filesize = field(FIELD_FILESIZE).ok_or(CpioError::BadHeader)? as usize;
+        let namesize = field(FIELD_NAMESIZE).ok_or(CpioError::BadHeader)? as usize;
+
+        let name_start = self.offset + HEADER_LEN;
+        let name = self.bytes(name_start, namesize)?;
+        let name = name
+            .strip_suffix(&[0])
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(CpioError::BadHeader)?;
+
+        let data_start = (name_start + namesize).next_multiple_of(4);
+        let data = self.bytes(data_start, filesize)?;
+        self.offset = (data_start + filesize).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.next_entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use alloc::{format, vec::Vec};
+
+    use super::*;
+
+    /// Appends an entry to `archive`, padded as `Reader` expects.
+    pub fn push(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor,
+        // rdevminor, namesize, check
+        let fields = [
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        archive.extend_from_slice(MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    pub fn trailer(archive: &mut Vec<u8>) {
+        push(archive, TRAILER, 0, &[]);
+    }
+
+    fn names(archive: &[u8]) -> Vec<Result<&str, CpioError>> {
+        Reader::new(archive)
+            .map(|entry| entry.map(|entry| entry.name))
+            .collect()
+    }
+
+    #[test_case]
+    fn entries_are_read_up_to_the_trailer() {
+        let mut archive = Vec::new();
+        push(&mut archive, "init", S_IFREG | 0o755, b"#!/bin/sh\n");
+        push(&mut archive, "etc", S_IFDIR | 0o755, &[]);
+        trailer(&mut archive);
+        // Anything after the trailer, like padding to a block, is ignored.
+        archive.extend_from_slice(&[0; 512]);
+
+        let entries: Vec<_> = Reader::new(&archive).map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "init");
+        assert_eq!(entries[0].mode, S_IFREG | 0o755);
+        assert_eq!(entries[0].data, b"#!/bin/sh\n");
+        assert_eq!(entries[1].mode & S_IFMT, S_IFDIR);
+    }
+
+    #[test_case]
+    fn bad_magic_stops_the_archive() {
+        let mut archive = Vec::new();
+        push(&mut archive, "a", S_IFREG, b"x");
+        let second = archive.len();
+        push(&mut archive, "b", S_IFREG, b"y");
+        archive[second..second + 6].copy_from_slice(b"070707");
+
+        assert_eq!(names(&archive), [Ok("a"), Err(CpioError::BadMagic)]);
+    }
+
+    #[test_case]
+    fn truncation_is_reported() {
+        let mut archive = Vec::new();
+        push(&mut archive, "file", S_IFREG, &[7; 100]);
+        let file_end = archive.len();
+        trailer(&mut archive);
+
+        // In the header, in the data, and with the trailer missing
+        for len in [50, file_end - 10] {
+            assert_eq!(names(&archive[..len]), [Err(CpioError::Truncated)]);
+        }
+        assert_eq!(
+            names(&archive[..file_end]),
+            [Ok("file"), Err(CpioError::Truncated)]
+        );
+    }
+
+    #[test_case]
+    fn headers_must_be_hex() {
+        let mut archive = Vec::new();
+        push(&mut archive, "file", S_IFREG, b"data");
+        archive[MAGIC.len() + FIELD_FILESIZE * 8] = b'z';
+        assert_eq!(names(&archive), [Err(CpioError::BadHeader)]);
+    }
+}
@@ -0,0 +1,380 @@
+//! The initial ramdisk, which the bootloader loads next to the kernel (QEMU's `-initrd`) and
+//! describes in `/chosen`. It's a cpio archive, unpacked into a read-only filesystem whose files
+//! point straight into it, so the archive stays reserved for as long as the kernel runs.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{
+    ops::{Bound, Range},
+    slice,
+};
+
+use fdt::Fdt;
+use log::{info, warn};
+use spin::Once;
+
+use super::{
+    FileKind, FsError,
+    cpio::{self, Reader},
+};
+use crate::mem::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr, kernel_mem},
+    vma::PageSource,
+};
+
+/// How many symlinks are followed when opening a path, before giving up on it as a loop.
+const MAX_SYMLINKS: usize = 8;
+
+static INITRAMFS: Once<Initramfs> = Once::new();
+
+enum Node {
+    File(&'static [u8]),
+    Directory,
+    Symlink(&'static str),
+}
+
+// Keyed by path, without leading or trailing slashes. The root is "".
+struct Initramfs {
+    nodes: BTreeMap<&'static str, Node>,
+}
+
+/// An open file of the initramfs.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    data: &'static [u8],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry {
+    pub name: &'static str,
+    pub kind: FileKind,
+}
+
+impl Node {
+    fn kind(&self) -> FileKind {
+        match self {
+            Self::File(_) => FileKind::File,
+            Self::Directory => FileKind::Directory,
+            Self::Symlink(_) => FileKind::Symlink,
+        }
+    }
+}
+
+impl Initramfs {
+    fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("", Node::Directory);
+        Self { nodes }
+    }
+
+    // Adds every entry of `archive`, up to the first bad one.
+    fn unpack(&mut self, archive: &'static [u8]) {
+        for entry in Reader::new(archive) {
+            match entry {
+                Ok(entry) => self.insert(entry),
+                Err(err) => {
+                    warn!("initramfs: {err:?}, ignoring the rest of the archive");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: cpio::Entry<'static>) {
+        // Archives made with `find .` name their entries "./path" and include "." itself.
+        let path = entry.name.trim_start_matches("./").trim_matches('/');
+        if path.is_empty() || path == "." {
+            return;
+        }
+
+        let node = match entry.mode & cpio::S_IFMT {
+            cpio::S_IFREG => Node::File(entry.data),
+            cpio::S_IFDIR => Node::Directory,
+            cpio::S_IFLNK => match core::str::from_utf8(entry.data) {
+                Ok(target) => Node::Symlink(target),
+                Err(_) => return,
+            },
+            // Device nodes, FIFOs and sockets mean nothing without a VFS.
+            _ => return,
+        };
+
+        // Parent directories needn't have entries of their own.
+        for (end, _) in path.match_indices('/') {
+            self.nodes.entry(&path[..end]).or_insert(Node::Directory);
+        }
+        self.nodes.insert(path, node);
+    }
+
+    /// Looks up `path`, following symlinks in any of its components. Returns the path it
+    /// resolved to and its node.
+    fn lookup(&self, path: &str) -> Result<(String, &Node), FsError> {
+        // The components left to walk, the next one last
+        let mut pending: Vec<&str> = path.rsplit('/').collect();
+        // The path walked so far, with no symlinks, "." or ".." in it
+        let mut resolved: Vec<&str> = Vec::new();
+        let mut is_dir = true;
+        let mut symlinks = 0;
+
+        while let Some(component) = pending.pop() {
+            if component.is_empty() {
+                continue;
+            }
+            if !is_dir {
+                return Err(FsError::NotADirectory);
+            }
+            match component {
+                "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => resolved.push(component),
+            }
+
+            match self.nodes.get(resolved.join("/").as_str()) {
+                None => return Err(FsError::NotFound),
+                Some(Node::Symlink(target)) => {
+                    symlinks += 1;
+                    if symlinks > MAX_SYMLINKS {
+                        return Err(FsError::SymlinkLoop);
+                    }
+
+                    // Relative targets are relative to the directory the symlink is in.
+                    resolved.pop();
+                    if target.starts_with('/') {
+                        resolved.clear();
+                    }
+                    pending.extend(target.rsplit('/'));
+                }
+                Some(node) => is_dir = matches!(node, Node::Directory),
+            }
+        }
+
+        let path = resolved.join("/");
+        let node = self.nodes.get(path.as_str()).ok_or(FsError::NotFound)?;
+        Ok((path, node))
+    }
+
+    /// The entries of the directory at `path`, sorted by name.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let (path, node) = self.lookup(path)?;
+        if !matches!(node, Node::Directory) {
+            return Err(FsError::NotADirectory);
+        }
+
+        let prefix = if path.is_empty() { path } else { path + "/" };
+        let entries = self
+            .nodes
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|(child, _)| child.starts_with(prefix.as_str()))
+            .filter_map(|(child, node)| {
+                let name = &child[prefix.len()..];
+                (!name.is_empty() && !name.contains('/')).then_some(DirEntry {
+                    name,
+                    kind: node.kind(),
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<File, FsError> {
+        match self.lookup(path)?.1 {
+            Node::File(data) => Ok(File { data }),
+            Node::Directory => Err(FsError::IsADirectory),
+            Node::Symlink(_) => unreachable!(),
+        }
+    }
+}
+
+/// The physical range of the initramfs archive, as given by `/chosen`.
+pub fn initrd_range(fdt: &Fdt) -> Option<Range<usize>> {
+    let chosen = fdt.find_node("/chosen")?;
+    // Either a u32 or a u64, depending on the bootloader.
+    let address = |name: &str| {
+        let value = chosen.property(name)?.value;
+        match value.len() {
+            4 => Some(u32::from_be_bytes(value.try_into().ok()?) as usize),
+            8 => Some(u64::from_be_bytes(value.try_into().ok()?) as usize),
+            _ => None,
+        }
+    };
+
+    let range = address("linux,initrd-start")?..address("linux,initrd-end")?;
+    (!range.is_empty()).then_some(range)
+}
+
+/// Unpacks the initramfs, if the bootloader loaded one. `mem::init` must have reserved it.
+pub fn init(fdt: &Fdt) {
+    let Some(range) = initrd_range(fdt) else {
+        info!("initramfs: none");
+        return;
+    };
+
+    let mapped = kernel_mem();
+    if range.start < mapped.start || range.end > mapped.end {
+        warn!(
+            "initramfs: [{:#x}, {:#x}) is outside mapped RAM",
+            range.start, range.end
+        );
+        return;
+    }
+
+    // The archive is reserved and never freed, so the files can borrow it forever.
+    let archive: &'static [u8] = unsafe {
+        slice::from_raw_parts(
+            VirtAddr::from_phys(PhysAddr(range.start)).as_ptr(),
+            range.len(),
+        )
+    };
+
+    let mut initramfs = Initramfs::new();
+    initramfs.unpack(archive);
+
+    info!(
+        "initramfs: {} entries, {} bytes at [{:#x}, {:#x})",
+        initramfs.nodes.len() - 1,
+        range.len(),
+        range.start,
+        range.end
+    );
+    INITRAMFS.call_once(|| initramfs);
+}
+
+fn initramfs() -> Result<&'static Initramfs, FsError> {
+    INITRAMFS.get().ok_or(FsError::NotFound)
+}
+
+/// Opens the file at `path`, following symlinks.
+pub fn open(path: &str) -> Result<File, FsError> {
+    initramfs()?.open(path)
+}
+
+/// The entries of the directory at `path`, sorted by name.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    initramfs()?.read_dir(path)
+}
+
+impl File {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Reads into `buf` from `offset`, returning how many bytes were read.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let data = self.data.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        len
+    }
+}
+
+impl PageSource for File {
+    fn read_page(&self, offset: usize, page: &mut [u8; PAGE_SIZE]) {
+        let len = self.read_at(offset, page);
+        page[len..].fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::fs::cpio::{
+        S_IFDIR, S_IFLNK, S_IFREG,
+        tests::{push, trailer},
+    };
+
+    // An initramfs of the given names, modes and data
+    fn unpack(entries: &[(&str, u32, &[u8])]) -> Initramfs {
+        let mut archive = Vec::new();
+        for &(name, mode, data) in entries {
+            push(&mut archive, name, mode, data);
+        }
+        trailer(&mut archive);
+
+        let mut initramfs = Initramfs::new();
+        initramfs.unpack(archive.leak());
+        initramfs
+    }
+
+    fn read(initramfs: &Initramfs, path: &str) -> Result<&'static [u8], FsError> {
+        initramfs.open(path).map(|file| file.data)
+    }
+
+    fn list(initramfs: &Initramfs, path: &str) -> Vec<(&'static str, FileKind)> {
+        let entries = initramfs.read_dir(path).unwrap();
+        entries
+            .iter()
+            .map(|entry| (entry.name, entry.kind))
+            .collect()
+    }
+
+    #[test_case]
+    fn paths_are_relative_to_the_root() {
+        // As `find . | cpio -o -H newc` names them, with "etc" left implied
+        let initramfs = unpack(&[
+            (".", S_IFDIR, b""),
+            ("./init", S_IFREG, b"init"),
+            ("./etc/hostname", S_IFREG, b"yaro\n"),
+        ]);
+
+        assert_eq!(read(&initramfs, "/init"), Ok(&b"init"[..]));
+        assert_eq!(read(&initramfs, "init"), Ok(&b"init"[..]));
+        assert_eq!(
+            read(&initramfs, "//etc/../etc/./hostname"),
+            Ok(&b"yaro\n"[..])
+        );
+        assert_eq!(
+            list(&initramfs, "/"),
+            [("etc", FileKind::Directory), ("init", FileKind::File)]
+        );
+        assert_eq!(list(&initramfs, "/etc/"), [("hostname", FileKind::File)]);
+
+        assert_eq!(read(&initramfs, "/etc"), Err(FsError::IsADirectory));
+        assert_eq!(read(&initramfs, "/missing"), Err(FsError::NotFound));
+        assert_eq!(read(&initramfs, "/init/x"), Err(FsError::NotADirectory));
+        assert_eq!(
+            initramfs.read_dir("/init").err(),
+            Some(FsError::NotADirectory)
+        );
+    }
+
+    #[test_case]
+    fn symlinks_are_followed_anywhere_in_a_path() {
+        // Like busybox's layout
+        let initramfs = unpack(&[
+            ("bin", S_IFLNK, b"usr/bin"),
+            ("sbin", S_IFLNK, b"/usr/bin"),
+            ("usr/bin/busybox", S_IFREG, b"busybox"),
+            ("usr/bin/sh", S_IFLNK, b"busybox"),
+            ("usr/lib", S_IFLNK, b"../lib"),
+            ("lib/libc.so", S_IFREG, b"libc"),
+        ]);
+
+        assert_eq!(read(&initramfs, "/bin/sh"), Ok(&b"busybox"[..]));
+        assert_eq!(read(&initramfs, "/sbin/sh"), Ok(&b"busybox"[..]));
+        assert_eq!(read(&initramfs, "/usr/lib/libc.so"), Ok(&b"libc"[..]));
+        // ".." goes up from where the symlink led
+        assert_eq!(read(&initramfs, "/bin/../lib/libc.so"), Ok(&b"libc"[..]));
+        assert_eq!(initramfs.lookup("/bin/").unwrap().0, "usr/bin");
+        assert_eq!(
+            list(&initramfs, "/bin"),
+            [("busybox", FileKind::File), ("sh", FileKind::Symlink)]
+        );
+    }
+
+    #[test_case]
+    fn symlink_loops_give_up() {
+        let initramfs = unpack(&[
+            ("a", S_IFLNK, b"b"),
+            ("b", S_IFLNK, b"a"),
+            ("c", S_IFLNK, b"c/x"),
+        ]);
+
+        for path in ["/a", "/b/x", "/c"] {
+            assert_eq!(read(&initramfs, path), Err(FsError::SymlinkLoop), "{path}");
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Filesystems. So far there's only the read-only initramfs.
+
+pub mod cpio;
+pub mod initramfs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    /// A directory was opened as a file.
+    IsADirectory,
+    /// A file was listed as a directory, or used as one in a path.
+    NotADirectory,
+    /// Following symlinks led in a circle, or just too far.
+    SymlinkLoop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+}
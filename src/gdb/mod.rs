@@ -0,0 +1,621 @@
+//! A GDB stub, speaking the remote serial protocol on the first 16550 UART that isn't the
+//! console. QEMU's own stub sees the CPU, where this one sees the kernel: breakpoints are `ebreak`s
+//! trapping into `int::trap_handler`, registers come from its `TrapFrame`, and memory is read
+//! through the current page table.
+//!
+//! Kernel threads are harts for now, as there's no scheduler, and each hart is a GDB thread. Only
+//! the hart that trapped has registers to show. S-mode can't trap after a single instruction, so
+//! single-stepping puts breakpoints wherever the instruction can go (see `step`). UART
+//! interrupts aren't used, so a running kernel can't be stopped with ^C, only by a breakpoint.
+
+pub mod packet;
+pub mod step;
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    ptr,
+};
+
+use fdt::Fdt;
+use log::info;
+use spin::{Mutex, Once};
+
+use self::packet::{Buffer, Decoder, Input, MAX_PACKET};
+use crate::{
+    cmdline::Param,
+    drivers::ns16550::{self, Uart},
+    hart,
+    int::TrapFrame,
+    kaslr,
+    mem::{
+        addr::{PhysAddr, VirtAddr, kernel_mem},
+        paging::{
+            entry::EntryFlags,
+            mapper::{Mapper, Translation},
+        },
+    },
+    sbi::{self, HartState},
+};
+
+/// Wait for GDB to attach once the stub is up.
+pub static WAIT: Param<bool> = Param::new("gdb", false);
+
+// x0 to x31, then pc, as GDB numbers them for RV64
+const REGISTERS: usize = 33;
+const PC: usize = 32;
+const BREAKPOINTS: usize = 32;
+// A step goes to at most two places, past a branch and to its target
+const STEPPING: usize = 2;
+const SIGTRAP: u8 = 5;
+
+static PORT: Once<Uart> = Once::new();
+// Nothing in it is on the heap, as the heap's lock may be held by whatever hit the breakpoint.
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+struct Stub {
+    decoder: Decoder,
+    reply: Buffer,
+    session: Session,
+}
+
+struct Session {
+    // Inserted with `Z0`
+    breakpoints: [Option<Breakpoint>; BREAKPOINTS],
+    // Put in for a single step, and removed at the next stop
+    stepping: [Option<Breakpoint>; STEPPING],
+    // Whether GDB is waiting for a stop reply
+    running: bool,
+    // The thread `g` and `G` are for, from `Hg`
+    thread: Option<usize>,
+    // Memory read or written by one packet
+    scratch: [u8; MAX_PACKET / 2],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    original: [u8; 4],
+    len: usize,
+}
+
+// What to do after a packet.
+enum Next {
+    Reply,
+    Resume { step: bool },
+    Detach { reply: bool },
+}
+
+// Writes text into a reply as hex.
+struct Hex<'a>(&'a mut Buffer);
+
+/// Takes the first UART that isn't the console, if there is one.
+pub fn init(fdt: &Fdt) {
+    let console = fdt
+        .chosen()
+        .stdout()
+        .and_then(|node| node.reg()?.next())
+        .map(|reg| PhysAddr(reg.starting_address as usize));
+    let Some(port) = ns16550::ports()
+        .into_iter()
+        .find(|port| Some(port.phys()) != console)
+    else {
+        info!("gdb: no UART besides the console");
+        return;
+    };
+
+    port.init();
+    PORT.call_once(|| port);
+    // GDB needs the slide to find the symbols, as in `symbol-file yaro -o <slide>`.
+    info!(
+        "gdb: listening on the UART at {:#x}, kaslr slide {:#x}",
+        port.phys().as_usize(),
+        kaslr::slide()
+    );
+
+    if WAIT.get() {
+        info!("gdb: waiting for a debugger");
+        breakpoint();
+    }
+}
+
+/// Stops in the debugger, if there's a stub.
+pub fn breakpoint() {
+    if PORT.get().is_some() {
+        unsafe { asm!("ebreak") };
+    }
+}
+
+/// Handles an `ebreak` in the kernel at `pc`, talking to GDB until it resumes. Returns false if
+/// the stub isn't up, or it was the stub that trapped.
+pub fn handle_breakpoint(frame: &mut TrapFrame, pc: &mut usize) -> bool {
+    let Some(port) = PORT.get() else {
+        return false;
+    };
+    let Some(mut stub) = STUB.try_lock() else {
+        return false;
+    };
+    let Stub {
+        decoder,
+        reply,
+        session,
+    } = &mut *stub;
+
+    session.remove_stepping();
+    if session.running {
+        session.running = false;
+        stop_reply(reply);
+        send(port, decoder, reply.as_bytes());
+    }
+
+    loop {
+        match receive(port, decoder) {
+            Input::Packet => {}
+            Input::Corrupt => {
+                port.write_byte(b'-');
+                continue;
+            }
+            // Already stopped
+            Input::Interrupt => continue,
+        }
+        port.write_byte(b'+');
+
+        reply.clear();
+        match session.command(decoder.packet(), reply, frame, pc) {
+            Next::Reply => send(port, decoder, reply.as_bytes()),
+            Next::Resume { step } => {
+                // An `ebreak` that isn't a breakpoint, like `breakpoint`'s, would trap forever.
+                if let Some(insn) = read_insn(*pc)
+                    && step::is_ebreak(insn)
+                    && !session.is_breakpoint(*pc)
+                {
+                    *pc += step::len(insn);
+                    if step {
+                        stop_reply(reply);
+                        send(port, decoder, reply.as_bytes());
+                        continue;
+                    }
+                }
+
+                if step && !session.insert_stepping(frame, *pc) {
+                    stop_reply(reply);
+                    send(port, decoder, reply.as_bytes());
+                    continue;
+                }
+                session.running = true;
+                return true;
+            }
+            Next::Detach { reply } => {
+                if reply {
+                    send(port, decoder, b"OK");
+                }
+                session.remove_all();
+                return true;
+            }
+        }
+    }
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            reply: Buffer::new(),
+            session: Session {
+                breakpoints: [None; BREAKPOINTS],
+                stepping: [None; STEPPING],
+                running: false,
+                thread: None,
+                scratch: [0; MAX_PACKET / 2],
+            },
+        }
+    }
+}
+
+impl Session {
+    // Handles the packet `data`, writing any reply into `reply`, which starts out empty.
+    fn command(
+        &mut self,
+        data: &[u8],
+        reply: &mut Buffer,
+        frame: &mut TrapFrame,
+        pc: &mut usize,
+    ) -> Next {
+        let Some((&kind, args)) = data.split_first() else {
+            return Next::Reply;
+        };
+        let done = match kind {
+            b'?' => {
+                stop_reply(reply);
+                Some(())
+            }
+            b'g' => self.registers().then(|| {
+                for n in 0..REGISTERS {
+                    reply.push_hex(&register(frame, *pc, n).to_le_bytes());
+                }
+            }),
+            b'G' => self.registers().then_some(()).and_then(|()| {
+                let bytes = packet::parse_bytes(args, &mut self.scratch)?;
+                let words = bytes.chunks_exact(size_of::<usize>());
+                if words.len() != REGISTERS || !words.remainder().is_empty() {
+                    return None;
+                }
+                for (n, word) in words.enumerate() {
+                    set_register(frame, pc, n, usize::from_le_bytes(word.try_into().unwrap()));
+                }
+                ok(reply)
+            }),
+            b'p' => packet::parse_number(args)
+                .filter(|&n| n < REGISTERS && self.registers())
+                .map(|n| reply.push_hex(&register(frame, *pc, n).to_le_bytes())),
+            b'P' => split(args, b'=').and_then(|(n, value)| {
+                let n = packet::parse_number(n).filter(|&n| n < REGISTERS && self.registers())?;
+                let mut word = [0; size_of::<usize>()];
+                if packet::parse_bytes(value, &mut word)?.len() != word.len() {
+                    return None;
+                }
+                set_register(frame, pc, n, usize::from_le_bytes(word));
+                ok(reply)
+            }),
+            b'm' => split(args, b',').and_then(|(addr, len)| {
+                let len = packet::parse_number(len)?.min(self.scratch.len());
+                let bytes = &mut self.scratch[..len];
+                read_memory(packet::parse_number(addr)?, bytes)?;
+                reply.push_hex(bytes);
+                Some(())
+            }),
+            b'M' => split(args, b':').and_then(|(range, bytes)| {
+                let (addr, len) = split(range, b',')?;
+                let bytes = packet::parse_bytes(bytes, &mut self.scratch)?;
+                if packet::parse_number(len)? != bytes.len() {
+                    return None;
+                }
+                write_memory(packet::parse_number(addr)?, bytes).then_some(())?;
+                ok(reply)
+            }),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match packet::parse_number(args) {
+                        Some(addr) => *pc = addr,
+                        None => {
+                            error(reply);
+                            return Next::Reply;
+                        }
+                    }
+                }
+                return Next::Resume { step: kind == b's' };
+            }
+            b'Z' | b'z' => {
+                let mut fields = args.split(|&byte| byte == b',');
+                let (Some(b"0"), Some(addr), Some(len)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    // Only software breakpoints
+                    return Next::Reply;
+                };
+                let (addr, len) = (packet::parse_number(addr), packet::parse_number(len));
+                addr.zip(len)
+                    .filter(|&(_, len)| len == 2 || len == 4)
+                    .and_then(|(addr, len)| match kind {
+                        b'Z' => self.insert(addr, len),
+                        _ => self.remove(addr),
+                    })
+                    .and_then(|()| ok(reply))
+            }
+            b'H' => args.split_first().and_then(|(&op, thread)| {
+                let thread = parse_thread(thread)?;
+                if op == b'g' {
+                    self.thread = thread;
+                }
+                ok(reply)
+            }),
+            b'T' => parse_thread(args)
+                .and_then(|thread| thread.filter(|&hart| running(hart)))
+                .and_then(|_| ok(reply)),
+            b'q' => {
+                query(args, reply);
+                return Next::Reply;
+            }
+            b'D' => return Next::Detach { reply: true },
+            b'k' => return Next::Detach { reply: false },
+            // Everything else is unsupported, including `vCont`, so GDB sticks to `c` and `s`
+            _ => return Next::Reply,
+        };
+
+        if done.is_none() {
+            reply.clear();
+            error(reply);
+        }
+        Next::Reply
+    }
+
+    // Whether `g` and friends are for the hart that trapped, the only one with registers.
+    fn registers(&self) -> bool {
+        self.thread.is_none_or(|hart| hart == hart::id())
+    }
+
+    fn is_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoints
+            .iter()
+            .chain(&self.stepping)
+            .flatten()
+            .any(|bp| bp.addr == addr)
+    }
+
+    fn insert(&mut self, addr: usize, len: usize) -> Option<()> {
+        if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            return Some(());
+        }
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(Breakpoint::insert(addr, len)?);
+        Some(())
+    }
+
+    fn remove(&mut self, addr: usize) -> Option<()> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|bp| bp.addr == addr))?;
+        slot.take()?.remove().then_some(())
+    }
+
+    // Puts breakpoints after the instruction at `pc`, returning whether there's anywhere to go.
+    fn insert_stepping(&mut self, frame: &TrapFrame, pc: usize) -> bool {
+        let Some(insn) = read_insn(pc) else {
+            return false;
+        };
+
+        for (i, next) in step::successors(insn, pc, |n| frame.x(n))
+            .into_iter()
+            .enumerate()
+        {
+            let Some(next) = next.filter(|&next| !self.is_breakpoint(next)) else {
+                continue;
+            };
+            self.stepping[i] =
+                read_insn(next).and_then(|insn| Breakpoint::insert(next, step::len(insn)));
+        }
+        self.stepping.iter().any(Option::is_some)
+    }
+
+    fn remove_stepping(&mut self) {
+        // Newest first, in case both went to the same place
+        for slot in self.stepping.iter_mut().rev() {
+            if let Some(bp) = slot.take() {
+                bp.remove();
+            }
+        }
+    }
+
+    fn remove_all(&mut self) {
+        self.remove_stepping();
+        for slot in &mut self.breakpoints {
+            if let Some(bp) = slot.take() {
+                bp.remove();
+            }
+        }
+    }
+}
+
+impl Breakpoint {
+    fn insert(addr: usize, len: usize) -> Option<Self> {
+        let mut original = [0; 4];
+        read_memory(addr, &mut original[..len])?;
+        let ebreak = match len {
+            2 => &step::C_EBREAK.to_le_bytes()[..],
+            _ => &step::EBREAK.to_le_bytes()[..],
+        };
+        write_memory(addr, ebreak).then_some(Self {
+            addr,
+            original,
+            len,
+        })
+    }
+
+    fn remove(self) -> bool {
+        write_memory(self.addr, &self.original[..self.len])
+    }
+}
+
+impl Write for Hex<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.push_hex(s.as_bytes());
+        Ok(())
+    }
+}
+
+// Waits for the next packet from GDB.
+fn receive(port: &Uart, decoder: &mut Decoder) -> Input {
+    loop {
+        match port.read_byte() {
+            Some(byte) => {
+                if let Some(input) = decoder.feed(byte) {
+                    return input;
+                }
+            }
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+// Sends a packet, again until GDB acknowledges it. Anything else GDB sends meanwhile starts the
+// next packet, so it goes to the decoder.
+fn send(port: &Uart, decoder: &mut Decoder, data: &[u8]) {
+    loop {
+        packet::encode(data, |byte| port.write_byte(byte));
+        let byte = loop {
+            if let Some(byte) = port.read_byte() {
+                break byte;
+            }
+            core::hint::spin_loop();
+        };
+        match byte {
+            b'+' => return,
+            b'-' => continue,
+            _ => {
+                decoder.feed(byte);
+                return;
+            }
+        }
+    }
+}
+
+fn stop_reply(reply: &mut Buffer) {
+    reply.clear();
+    let _ = write!(reply, "T{SIGTRAP:02x}thread:{:x};", thread_id(hart::id()));
+}
+
+// Always `Some`, for the end of a command that worked.
+fn ok(reply: &mut Buffer) -> Option<()> {
+    reply.extend(b"OK");
+    Some(())
+}
+
+fn error(reply: &mut Buffer) {
+    reply.extend(b"E01");
+}
+
+fn query(query: &[u8], reply: &mut Buffer) {
+    let (name, args) = split(query, b',').unwrap_or((query, b""));
+    match name {
+        // GDB's features come after a colon, and none of them matter here
+        _ if name.starts_with(b"Supported") => {
+            let _ = write!(reply, "PacketSize={MAX_PACKET:x}");
+        }
+        b"Attached" => reply.extend(b"1"),
+        b"C" => {
+            let _ = write!(reply, "QC{:x}", thread_id(hart::id()));
+        }
+        b"fThreadInfo" => {
+            reply.extend(b"m");
+            for (i, hart) in harts().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                let _ = write!(reply, "{separator}{:x}", thread_id(hart));
+            }
+        }
+        b"sThreadInfo" => reply.extend(b"l"),
+        b"ThreadExtraInfo" => match parse_thread(args) {
+            Some(Some(hart)) => {
+                let state = if hart == hart::id() {
+                    "stopped"
+                } else {
+                    "running"
+                };
+                let _ = write!(Hex(reply), "hart {hart}, {state}");
+            }
+            _ => error(reply),
+        },
+        _ => {}
+    }
+}
+
+// The parts of `data` before and after the first `separator`.
+fn split(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = data.iter().position(|&byte| byte == separator)?;
+    Some((&data[..at], &data[at + 1..]))
+}
+
+// GDB's thread IDs start at 1, as 0 means any thread and -1 all of them.
+fn thread_id(hart: usize) -> usize {
+    hart + 1
+}
+
+// The hart a thread ID is for, `Some(None)` for any or all of them, or `None` if there's no such
+// hart running.
+fn parse_thread(id: &[u8]) -> Option<Option<usize>> {
+    match id {
+        b"0" | b"-1" => Some(None),
+        _ => packet::parse_number(id)
+            .and_then(|id| id.checked_sub(1))
+            .filter(|&hart| running(hart))
+            .map(Some),
+    }
+}
+
+// Harts running the kernel. QEMU numbers them from 0 without gaps, so the first that doesn't
+// exist is past the end.
+fn harts() -> impl Iterator<Item = usize> {
+    (0..)
+        .map_while(|hart| Some((hart, sbi::hart_status(hart).ok()?)))
+        .filter(|&(hart, state)| hart == hart::id() || state == HartState::Started)
+        .map(|(hart, _)| hart)
+}
+
+fn running(hart: usize) -> bool {
+    hart == hart::id() || sbi::hart_status(hart) == Ok(HartState::Started)
+}
+
+fn register(frame: &TrapFrame, pc: usize, n: usize) -> usize {
+    match n {
+        PC => pc,
+        _ => frame.x(n),
+    }
+}
+
+fn set_register(frame: &mut TrapFrame, pc: &mut usize, n: usize, value: usize) {
+    match n {
+        PC => *pc = value,
+        _ => frame.set_x(n, value),
+    }
+}
+
+// The instruction at `addr`, reading its second half only if it's a long one.
+fn read_insn(addr: usize) -> Option<u32> {
+    let mut low = [0; 2];
+    read_memory(addr, &mut low)?;
+    let low = u16::from_le_bytes(low) as u32;
+    if step::len(low) == 2 {
+        return Some(low);
+    }
+    let mut high = [0; 2];
+    read_memory(addr.checked_add(2)?, &mut high)?;
+    Some(low | (u16::from_le_bytes(high) as u32) << 16)
+}
+
+fn read_memory(addr: usize, out: &mut [u8]) -> Option<()> {
+    addr.checked_add(out.len())?;
+    for (addr, byte) in (addr..).zip(out) {
+        let (virt, translation) = translate(addr)?;
+        if !translation.flags.contains(EntryFlags::READ) {
+            return None;
+        }
+        *byte = unsafe { ptr::read_volatile(virt.as_ptr::<u8>()) };
+    }
+    Some(())
+}
+
+// Writes what's mapped read-only, like breakpoints in the kernel's text, through the linear map.
+// Nothing is written unless all of it can be.
+fn write_memory(addr: usize, bytes: &[u8]) -> bool {
+    let Some(end) = addr.checked_add(bytes.len()) else {
+        return false;
+    };
+    if !(addr..end).all(|addr| write_target(addr).is_some()) {
+        return false;
+    }
+
+    for (addr, &byte) in (addr..end).zip(bytes) {
+        let target = write_target(addr).expect("was just checked");
+        unsafe { ptr::write_volatile(target.as_ptr::<u8>(), byte) };
+    }
+    // The hart may have fetched the old instructions.
+    unsafe { asm!("fence.i") };
+    true
+}
+
+// Where the byte at `addr` can be written.
+fn write_target(addr: usize) -> Option<VirtAddr> {
+    let (virt, translation) = translate(addr)?;
+    if translation.flags.contains(EntryFlags::WRITE) {
+        return Some(virt);
+    }
+    let phys = translation.phys;
+    kernel_mem()
+        .contains(&phys.as_usize())
+        .then(|| VirtAddr::from_phys(phys))
+}
+
+fn translate(addr: usize) -> Option<(VirtAddr, Translation)> {
+    let virt = VirtAddr::try_new(addr)?;
+    Some((virt, unsafe { Mapper::current() }.translate(virt)?))
+}
@@ -0,0 +1,307 @@
+//! GDB's remote serial protocol framing. A packet is `$`, the data, `#` and two hex digits of
+//! the data's checksum, the sum of its bytes. `#`, `$`, `}` and `*` in the data are escaped as
+//! `}` followed by the byte xored with 0x20. A lone 0x03 outside a packet asks to stop.
+//!
+//! Nothing here allocates, as the stub runs in the trap handler, maybe with the heap locked.
+
+use core::fmt;
+
+/// The longest packet accepted, which is also what's announced to GDB.
+pub const MAX_PACKET: usize = 4096;
+
+const INTERRUPT: u8 = 0x03;
+const ESCAPE: u8 = b'}';
+
+/// Assembles packets out of the bytes received.
+pub struct Decoder {
+    state: State,
+    data: [u8; MAX_PACKET],
+    len: usize,
+    checksum: u8,
+    // Whether the last byte was `}`
+    escaped: bool,
+    // Set when the data didn't fit, so the packet is dropped
+    overflow: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A whole packet, whose data is in `Decoder::packet` until the next byte is fed.
+    Packet,
+    /// A packet whose checksum didn't match, or that was too long. GDB sends it again on a `-`.
+    Corrupt,
+    Interrupt,
+}
+
+/// A packet's data being put together, up to `MAX_PACKET` bytes. Anything past that is dropped,
+/// though nothing the stub sends is that long.
+pub struct Buffer {
+    data: [u8; MAX_PACKET],
+    len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Data,
+    // The first checksum digit, and then the second with the first's value
+    Checksum,
+    Checksum2(u8),
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            data: [0; MAX_PACKET],
+            len: 0,
+            checksum: 0,
+            escaped: false,
+            overflow: false,
+        }
+    }
+
+    /// Handles one byte received. Says so once a packet is complete. Acknowledgements and
+    /// anything else between packets are ignored.
+    pub fn feed(&mut self, byte: u8) -> Option<Input> {
+        match self.state {
+            State::Idle => match byte {
+                b'$' => {
+                    self.state = State::Data;
+                    self.len = 0;
+                    self.checksum = 0;
+                    self.escaped = false;
+                    self.overflow = false;
+                }
+                INTERRUPT => return Some(Input::Interrupt),
+                _ => {}
+            },
+            State::Data if byte == b'#' => self.state = State::Checksum,
+            State::Data => {
+                self.checksum = self.checksum.wrapping_add(byte);
+                if self.len == MAX_PACKET {
+                    self.overflow = true;
+                } else if self.escaped {
+                    self.escaped = false;
+                    self.push(byte ^ 0x20);
+                } else if byte == ESCAPE {
+                    self.escaped = true;
+                } else {
+                    self.push(byte);
+                }
+            }
+            State::Checksum => match hex_digit(byte) {
+                Some(high) => self.state = State::Checksum2(high),
+                None => return self.corrupt(),
+            },
+            State::Checksum2(high) => {
+                let valid = hex_digit(byte).is_some_and(|low| high << 4 | low == self.checksum);
+                if !valid || self.overflow {
+                    return self.corrupt();
+                }
+                self.state = State::Idle;
+                return Some(Input::Packet);
+            }
+        }
+        None
+    }
+
+    /// The data of the last packet.
+    pub fn packet(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.data[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn corrupt(&mut self) -> Option<Input> {
+        self.state = State::Idle;
+        Some(Input::Corrupt)
+    }
+}
+
+impl Buffer {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PACKET - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    /// Appends `bytes` as pairs of lowercase hex digits.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.extend(&hex_pair(byte));
+        }
+    }
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.extend(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Frames `data` as a packet, handing it to `out` a byte at a time.
+pub fn encode(data: &[u8], mut out: impl FnMut(u8)) {
+    out(b'$');
+    let mut checksum = 0u8;
+    for &byte in data {
+        let escaped: &[u8] = match byte {
+            b'#' | b'$' | ESCAPE | b'*' => &[ESCAPE, byte ^ 0x20],
+            _ => &[byte],
+        };
+        for &byte in escaped {
+            checksum = checksum.wrapping_add(byte);
+            out(byte);
+        }
+    }
+    out(b'#');
+    hex_pair(checksum).into_iter().for_each(out);
+}
+
+/// Decodes pairs of hex digits into the start of `out`, returning that part. `None` if there's
+/// an odd number, anything else, or more than fit.
+pub fn parse_bytes<'a>(hex: &[u8], out: &'a mut [u8]) -> Option<&'a [u8]> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > out.len() {
+        return None;
+    }
+    for (pair, byte) in hex.chunks_exact(2).zip(out.iter_mut()) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(&out[..hex.len() / 2])
+}
+
+/// A number in hex, most significant digit first, like addresses and lengths.
+pub fn parse_number(hex: &[u8]) -> Option<usize> {
+    if hex.is_empty() || hex.len() > 2 * size_of::<usize>() {
+        return None;
+    }
+    hex.iter().try_fold(0, |number, &digit| {
+        Some(number << 4 | hex_digit(digit)? as usize)
+    })
+}
+
+fn hex_pair(byte: u8) -> [u8; 2] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xf) as usize]]
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    // Each packet's data, or what came instead
+    fn decode(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Result<Vec<u8>, Input>> {
+        bytes
+            .iter()
+            .filter_map(|&byte| match decoder.feed(byte)? {
+                Input::Packet => Some(Ok(decoder.packet().to_vec())),
+                input => Some(Err(input)),
+            })
+            .collect()
+    }
+
+    fn encode_to_vec(data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        encode(data, |byte| packet.push(byte));
+        packet
+    }
+
+    #[test_case]
+    fn packets_are_checked() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decode(&mut decoder, b"+$g#67$m0,4#fd"),
+            [Ok(b"g".to_vec()), Ok(b"m0,4".to_vec())]
+        );
+        assert_eq!(
+            decode(&mut decoder, b"$g#68\x03$?#3F"),
+            [
+                Err(Input::Corrupt),
+                Err(Input::Interrupt),
+                Ok(b"?".to_vec())
+            ]
+        );
+    }
+
+    #[test_case]
+    fn escapes_round_trip() {
+        let data = b"a#b$c}d*e";
+        let packet = encode_to_vec(data);
+        assert_eq!(packet, b"$a}\x03b}\x04c}]d}\x0ae#51");
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decode(&mut decoder, &packet), [Ok(data.to_vec())]);
+    }
+
+    #[test_case]
+    fn long_packets_are_dropped() {
+        let mut decoder = Decoder::new();
+        let mut data = vec![b'0'; MAX_PACKET + 1];
+        data[0] = b'M';
+        let mut packet = encode_to_vec(&data);
+        assert_eq!(decode(&mut decoder, &packet), [Err(Input::Corrupt)]);
+
+        packet = encode_to_vec(&data[..MAX_PACKET]);
+        assert_eq!(
+            decode(&mut decoder, &packet),
+            [Ok(data[..MAX_PACKET].to_vec())]
+        );
+        assert_eq!(decoder.feed(b'$'), None);
+    }
+
+    #[test_case]
+    fn replies_are_cut_at_the_packet_size() {
+        let mut buffer = Buffer::new();
+        buffer.push_hex(&[0x00, 0x7f, 0xab]);
+        assert_eq!(buffer.as_bytes(), b"007fab");
+
+        buffer.extend(&[b'x'; MAX_PACKET]);
+        assert_eq!(buffer.as_bytes().len(), MAX_PACKET);
+        buffer.clear();
+        assert_eq!(buffer.as_bytes(), b"");
+    }
+
+    #[test_case]
+    fn hex_parses_both_ways() {
+        let mut out = [0; 3];
+        assert_eq!(
+            parse_bytes(b"007FaB", &mut out),
+            Some(&[0x00, 0x7f, 0xab][..])
+        );
+        assert_eq!(parse_bytes(b"7", &mut out), None);
+        assert_eq!(parse_bytes(b"zz", &mut out), None);
+        assert_eq!(parse_bytes(b"0011223344", &mut out), None);
+
+        assert_eq!(
+            parse_number(b"ffffffc000001000"),
+            Some(0xffff_ffc0_0000_1000)
+        );
+        assert_eq!(parse_number(b"10000000000000000"), None);
+        assert_eq!(parse_number(b""), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Single-stepping without hardware help: S-mode can't trap after one instruction, so the stub
+//! decodes the instruction, and puts breakpoints wherever it can go next.
+
+/// `ebreak`, and `c.ebreak` for replacing compressed instructions.
+pub const EBREAK: u32 = 0x0010_0073;
+pub const C_EBREAK: u16 = 0x9002;
+
+const OP_BRANCH: u32 = 0b110_0011;
+const OP_JALR: u32 = 0b110_0111;
+const OP_JAL: u32 = 0b110_1111;
+
+/// Length in bytes of the instruction starting with `insn`. Compressed ones have anything but
+/// 0b11 in their lowest two bits.
+pub fn len(insn: u32) -> usize {
+    if insn & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// Whether `insn` is an `ebreak` of either length.
+pub fn is_ebreak(insn: u32) -> bool {
+    match len(insn) {
+        4 => insn == EBREAK,
+        _ => insn as u16 == C_EBREAK,
+    }
+}
+
+/// Where execution can go after the instruction `insn` at `pc`: the next instruction unless it's
+/// a jump, and the target of a jump or branch. `reg` reads an x register, for indirect jumps.
+pub fn successors(insn: u32, pc: usize, reg: impl Fn(usize) -> usize) -> [Option<usize>; 2] {
+    let next = pc + len(insn);
+    let target = |offset: i64| pc.wrapping_add_signed(offset as isize);
+
+    if len(insn) == 4 {
+        let rs1 = (insn >> 15 & 0x1f) as usize;
+        return match insn & 0x7f {
+            OP_JAL => {
+                let imm = bit(insn, 31, 20) | bits(insn, 19, 12, 12) | bit(insn, 20, 11);
+                [
+                    None,
+                    Some(target(sign_extend(imm | bits(insn, 30, 21, 1), 21))),
+                ]
+            }
+            OP_JALR => {
+                let imm = sign_extend(insn >> 20, 12);
+                [None, Some(reg(rs1).wrapping_add_signed(imm as isize) & !1)]
+            }
+            OP_BRANCH => {
+                let imm = bit(insn, 31, 12) | bit(insn, 7, 11) | bits(insn, 30, 25, 5);
+                [
+                    Some(next),
+                    Some(target(sign_extend(imm | bits(insn, 11, 8, 1), 13))),
+                ]
+            }
+            _ => [Some(next), None],
+        };
+    }
+
+    let funct3 = insn >> 13 & 0b111;
+    match (insn & 0b11, funct3) {
+        // c.j
+        (0b01, 0b101) => {
+            let imm =
+                bit(insn, 12, 11) | bit(insn, 11, 4) | bits(insn, 10, 9, 8) | bit(insn, 8, 10);
+            let imm =
+                imm | bit(insn, 7, 6) | bit(insn, 6, 7) | bits(insn, 5, 3, 1) | bit(insn, 2, 5);
+            [None, Some(target(sign_extend(imm, 12)))]
+        }
+        // c.beqz and c.bnez
+        (0b01, 0b110 | 0b111) => {
+            let imm = bit(insn, 12, 8) | bits(insn, 11, 10, 3) | bits(insn, 6, 5, 6);
+            let imm = imm | bits(insn, 4, 3, 1) | bit(insn, 2, 5);
+            [Some(next), Some(target(sign_extend(imm, 9)))]
+        }
+        // c.jr and c.jalr, unless it's c.ebreak with rs1 = 0
+        (0b10, 0b100) if insn >> 2 & 0x1f == 0 && insn >> 7 & 0x1f != 0 => {
+            [None, Some(reg((insn >> 7 & 0x1f) as usize) & !1)]
+        }
+        _ => [Some(next), None],
+    }
+}
+
+// Bit `from` of `insn`, moved to bit `to`.
+fn bit(insn: u32, from: u32, to: u32) -> u32 {
+    (insn >> from & 1) << to
+}
+
+// Bits `high` down to `low` of `insn`, moved down to start at bit `to`.
+fn bits(insn: u32, high: u32, low: u32, to: u32) -> u32 {
+    (insn >> low & ((1 << (high - low + 1)) - 1)) << to
+}
+
+fn sign_extend(value: u32, width: u32) -> i64 {
+    let shift = 64 - width;
+    ((value as i64) << shift) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: usize = 0x8020_1000;
+
+    // Encodings from `llvm-mc -triple=riscv64 -mattr=+c -show-encoding`
+    fn at(insn: u32) -> [Option<usize>; 2] {
+        successors(insn, PC, |reg| reg * 0x100 + 1)
+    }
+
+    #[test_case]
+    fn lengths_follow_the_low_bits() {
+        assert_eq!(len(0x0015_0513), 4);
+        assert_eq!(len(0x0505), 2);
+        assert!(is_ebreak(EBREAK));
+        assert!(is_ebreak(C_EBREAK as u32));
+        assert!(!is_ebreak(0x0015_0513));
+    }
+
+    #[test_case]
+    fn jumps_only_go_to_their_target() {
+        // jal ra, 0x800 and j -16
+        assert_eq!(at(0x0010_00ef), [None, Some(PC + 0x800)]);
+        assert_eq!(at(0xff1f_f06f), [None, Some(PC - 16)]);
+        // jalr ra, 12(a0), with the low bit cleared
+        assert_eq!(at(0x00c5_00e7), [None, Some(0xa00 + 1 + 12 - 1)]);
+        // c.j -6 and c.j 0x10
+        assert_eq!(at(0xbfed), [None, Some(PC - 6)]);
+        assert_eq!(at(0xa801), [None, Some(PC + 0x10)]);
+        // c.jr ra and c.jalr a5
+        assert_eq!(at(0x8082), [None, Some(0x100)]);
+        assert_eq!(at(0x9782), [None, Some(0xf00)]);
+    }
+
+    #[test_case]
+    fn branches_go_either_way() {
+        // beq a0, a1, -8 and bnez t0, 0x40
+        assert_eq!(at(0xfeb5_0ce3), [Some(PC + 4), Some(PC - 8)]);
+        assert_eq!(at(0x0402_9063), [Some(PC + 4), Some(PC + 0x40)]);
+        // c.beqz a0, 0x20 and c.bnez s1, -4
+        assert_eq!(at(0xc105), [Some(PC + 2), Some(PC + 0x20)]);
+        assert_eq!(at(0xfcf5), [Some(PC + 2), Some(PC - 4)]);
+    }
+
+    #[test_case]
+    fn everything_else_falls_through() {
+        assert_eq!(at(0x0015_0513), [Some(PC + 4), None]);
+        assert_eq!(at(0x0505), [Some(PC + 2), None]);
+        assert_eq!(at(C_EBREAK as u32), [Some(PC + 2), None]);
+    }
+}
@@ -0,0 +1,18 @@
+//! The hart running the kernel. Its ID is kept in `tp`, which nothing else uses in the kernel.
+
+use core::arch::asm;
+
+/// The current hart's ID.
+pub fn id() -> usize {
+    let id;
+    unsafe { asm!("mv {}, tp", out(reg) id, options(nomem, nostack, preserves_flags)) };
+    id
+}
+
+/// Records that the current hart is `id`.
+///
+/// # Safety
+/// `id` must be the hart's actual ID, from the firmware.
+pub unsafe fn set_id(id: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) id, options(nomem, nostack, preserves_flags)) };
+}
@@ -1,6 +1,24 @@
-use core::arch::naked_asm;
+use core::arch::{asm, naked_asm};
 
-use crate::asm::{read_csr, write_csr};
+use crate::{
+    asm::{read_csr, write_csr},
+    mem::{
+        addr::VirtAddr,
+        fault::{self, Access, FaultError},
+    },
+};
+
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+const SUPERVISOR_SOFTWARE_INTERRUPT: usize = SCAUSE_INTERRUPT | 1;
+const BREAKPOINT: usize = 3;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+const STORE_PAGE_FAULT: usize = 15;
+
+// Set in sstatus when the trap came from S-mode.
+const SSTATUS_SPP: usize = 1 << 8;
+// Software interrupts in sie and sip, which is how IPIs arrive.
+const SSIE: usize = 1 << 1;
 
 #[unsafe(naked)]
 unsafe extern "C" fn kernel_entry() -> ! {
@@ -77,10 +95,11 @@ unsafe extern "C" fn kernel_entry() -> ! {
     );
 }
 
-#[allow(dead_code)]
+/// The registers of the code that trapped, saved by `kernel_entry` and restored from here when
+/// the trap handler returns.
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(C)]
-struct TrapFrame {
+pub struct TrapFrame {
     ra: usize,
     gp: usize,
     tp: usize,
@@ -114,17 +133,234 @@ struct TrapFrame {
     sp: usize,
 }
 
+impl TrapFrame {
+    // Where x1 to x31 are in the frame, in words, as `kernel_entry` saves them
+    const SLOTS: [usize; 32] = [
+        usize::MAX,
+        0,
+        30,
+        1,
+        2,
+        3,
+        4,
+        5,
+        18,
+        19,
+        10,
+        11,
+        12,
+        13,
+        14,
+        15,
+        16,
+        17,
+        20,
+        21,
+        22,
+        23,
+        24,
+        25,
+        26,
+        27,
+        28,
+        29,
+        6,
+        7,
+        8,
+        9,
+    ];
+
+    /// Register `x<n>`. x0 is always 0.
+    pub fn x(&self, n: usize) -> usize {
+        match n {
+            1..32 => self.words()[Self::SLOTS[n]],
+            _ => 0,
+        }
+    }
+
+    /// Sets register `x<n>`. Writes to x0 are ignored, as they are by the hardware.
+    pub fn set_x(&mut self, n: usize, value: usize) {
+        if (1..32).contains(&n) {
+            self.words_mut()[Self::SLOTS[n]] = value;
+        }
+    }
+
+    fn words(&self) -> &[usize; 31] {
+        // It's `repr(C)` and all `usize`s
+        unsafe { &*(self as *const Self).cast() }
+    }
+
+    fn words_mut(&mut self) -> &mut [usize; 31] {
+        unsafe { &mut *(self as *mut Self).cast() }
+    }
+}
+
 #[allow(dead_code)]
-unsafe extern "C" fn trap_handler(_trap_frame: &mut TrapFrame) {
+unsafe extern "C" fn trap_handler(trap_frame: &mut TrapFrame) {
     let scause = unsafe { read_csr!("scause") };
     let stval = unsafe { read_csr!("stval") };
     let user_pc = unsafe { read_csr!("sepc") };
 
+    let access = match scause {
+        INSTRUCTION_PAGE_FAULT => Some(Access::Execute),
+        LOAD_PAGE_FAULT => Some(Access::Read),
+        STORE_PAGE_FAULT => Some(Access::Write),
+        _ => None,
+    };
+
+    if scause == SUPERVISOR_SOFTWARE_INTERRUPT {
+        unsafe { asm!("csrc sip, {}", in(reg) SSIE) };
+        // The only IPIs sent so far are from a panicking hart, to stop the others.
+        if crate::panic::is_panicking() {
+            crate::panic::park();
+        }
+        return;
+    }
+
+    #[cfg(feature = "semihosting")]
+    if scause == BREAKPOINT
+        && unsafe { read_csr!("sstatus") } & SSTATUS_SPP != 0
+        && crate::io::semihosting::skip_trap(user_pc)
+    {
+        // Past the `ebreak`, which makes the call return its operation number
+        unsafe {
+            write_csr!("sepc", user_pc + 4);
+        }
+        return;
+    }
+
+    if scause == BREAKPOINT && unsafe { read_csr!("sstatus") } & SSTATUS_SPP != 0 {
+        let mut pc = user_pc;
+        if crate::gdb::handle_breakpoint(trap_frame, &mut pc) {
+            unsafe {
+                write_csr!("sepc", pc);
+            }
+            return;
+        }
+    }
+
+    if scause & SCAUSE_INTERRUPT == 0
+        && let Some(access) = access
+    {
+        let result = VirtAddr::try_new(stval)
+            .ok_or(FaultError::AccessViolation)
+            .and_then(|addr| fault::handle(addr, access));
+
+        if let Err(err) = result {
+            let sstatus = unsafe { read_csr!("sstatus") };
+            let context = if sstatus & SSTATUS_SPP != 0 {
+                "kernel"
+            } else {
+                "user"
+            };
+
+            // There are no user processes to kill yet, so every failed fault is fatal.
+            panic!("{context} {err:?}: {access:?} of {stval:#x} at pc={user_pc:#x}");
+        }
+
+        return;
+    }
+
     panic!("unexpected trap scause={scause}, stval={stval}, user_pc={user_pc}");
 }
 
+/// Lets IPIs interrupt the current hart, once it enables interrupts.
+pub fn enable_ipis() {
+    unsafe { asm!("csrs sie, {}", in(reg) SSIE) };
+}
+
 pub unsafe fn set_kernel_entry() {
     unsafe {
         write_csr!("stvec", kernel_entry as usize);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::{arch::asm, ptr};
+
+    use super::TrapFrame;
+    use crate::mem::{
+        PAGE_SIZE,
+        addr::VirtAddr,
+        paging::space::{self, AddressSpace},
+        vma::{Backing, Vma, VmaFlags},
+    };
+
+    const AREA: VirtAddr = VirtAddr::new_const(0x1000_0000);
+
+    // Runs `f` in a new address space with an anonymous area of `pages` pages at `AREA`, none of
+    // which are mapped until `f` touches them.
+    fn with_area(pages: usize, f: impl FnOnce(*mut u8)) {
+        let space = AddressSpace::new().unwrap();
+        let vma = Vma::new(
+            AREA,
+            AREA + pages * PAGE_SIZE,
+            VmaFlags::READ | VmaFlags::WRITE,
+            Backing::Anonymous,
+        )
+        .unwrap();
+        space.map_area(vma).unwrap();
+
+        space.activate();
+        f(AREA.as_ptr());
+        space::activate_kernel();
+    }
+
+    #[test_case]
+    fn load_fault_maps_zeroed_page() {
+        with_area(2, |area| {
+            for offset in [0, 8, PAGE_SIZE - 8, PAGE_SIZE + 16] {
+                let value = unsafe { ptr::read_volatile(area.add(offset).cast::<u64>()) };
+                assert_eq!(value, 0);
+            }
+        });
+    }
+
+    #[test_case]
+    fn store_faults_across_pages() {
+        with_area(3, |area| {
+            let len = 3 * PAGE_SIZE;
+            unsafe { ptr::write_bytes(area, 0xa5, len) };
+            for offset in (0..len).step_by(PAGE_SIZE / 4) {
+                assert_eq!(unsafe { ptr::read_volatile(area.add(offset)) }, 0xa5);
+            }
+        });
+    }
+
+    #[test_case]
+    fn trap_frame_is_indexed_by_register_number() {
+        let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+        for n in 0..32 {
+            frame.set_x(n, n * 0x10);
+        }
+        assert_eq!(frame.x(0), 0);
+        assert_eq!((frame.ra, frame.sp, frame.s0), (0x10, 0x20, 0x80));
+        assert_eq!((frame.a0, frame.s2, frame.t6), (0xa0, 0x120, 0x1f0));
+        assert!((1..32).all(|n| frame.x(n) == n * 0x10));
+    }
+
+    #[test_case]
+    fn trap_preserves_registers() {
+        with_area(1, |area| {
+            let sum: usize;
+            // The store faults, and the trap handler returns to retry it.
+            unsafe {
+                asm!(
+                    "li t0, 1",
+                    "li t6, 2",
+                    "li a7, 4",
+                    "sd zero, 0({area})",
+                    "add {sum}, t0, t6",
+                    "add {sum}, {sum}, a7",
+                    area = in(reg) area,
+                    sum = lateout(reg) sum,
+                    out("t0") _,
+                    out("t6") _,
+                    out("a7") _,
+                );
+            }
+            assert_eq!(sum, 7);
+        });
+    }
+}
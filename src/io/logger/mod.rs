@@ -0,0 +1,225 @@
+//! The kernel's logger, behind the `log` crate's macros. Every record goes into a ring buffer,
+//! stamped with the hart and the time, and those important enough for `LOGLEVEL` are also
+//! printed. Until the command line has set the console up (see `console_ready`), records are
+//! only buffered, and printed once it has.
+//!
+//! The `log` param filters records by module, with a comma separated list of `module=level`,
+//! e.g. `log=mem=warn,drivers::virtio=trace`. Modules are named without the leading `yaro::`,
+//! and a module's filter covers its submodules. Filtered out records aren't even buffered.
+
+pub mod ring;
+
+use core::{
+    fmt::{self, Display, Write},
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use fdt::Fdt;
+use log::{Level, LevelFilter, Log, Metadata};
+use spin::Mutex;
+
+use self::ring::{MAX_MESSAGE, Record, Ring};
+use super::{LOGLEVEL, serial::println};
+use crate::{asm::read_csr, cmdline::Param, hart};
+
+/// Per-module filters. See the module docs.
+pub static FILTER: Param<&str> = Param::new("log", "");
+
+const RING_SIZE: usize = 64 * 1024;
+
+static RING: Mutex<Ring<RING_SIZE>> = Mutex::new(Ring::new());
+static READY: AtomicBool = AtomicBool::new(false);
+// Frequency of the `time` CSR, in Hz. QEMU's virt machine uses 10 MHz.
+static TIMEBASE: AtomicU64 = AtomicU64::new(10_000_000);
+
+static LOGGER: Logger = Logger;
+
+struct Logger;
+
+// The message of a record being logged, cut to `MAX_MESSAGE` bytes.
+struct Message {
+    bytes: [u8; MAX_MESSAGE],
+    len: usize,
+}
+
+/// Formats a record as a line, without the newline.
+pub struct Line<'a>(pub &'a Record<'a>);
+
+/// Installs the logger.
+///
+/// # Safety
+/// Must be called once the image is at its final address, as the `log` crate keeps a pointer to
+/// the logger.
+pub unsafe fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(LevelFilter::Trace);
+}
+
+/// Takes the frequency of the `time` CSR from the device tree, for timestamps.
+pub fn set_timebase(fdt: &Fdt) {
+    if let Some(cpu) = fdt.cpus().next() {
+        TIMEBASE.store(cpu.timebase_frequency() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Starts printing records, once `LOGLEVEL`, `FILTER` and the console have been set from the
+/// command line. The buffered records that pass the filters are printed first.
+pub fn console_ready() {
+    let ring = RING.lock();
+    ring.for_each(|record| {
+        if module_enabled(record.target, record.level) && console_enabled(record.level) {
+            println!("{}", Line(record));
+        }
+    });
+    READY.store(true, Ordering::Release);
+}
+
+/// Prints every buffered record, whatever its level.
+pub fn dump() {
+    for_each(|record| {
+        println!("{}", Line(record));
+    });
+}
+
+/// Calls `f` on every buffered record, oldest first.
+pub fn for_each(f: impl FnMut(&Record)) {
+    RING.lock().for_each(f);
+}
+
+/// Like `for_each`, but only the newest `count` records, and gives up and returns false if the
+/// ring is locked, as it may be for good by a hart that panicked or was stopped.
+pub fn try_for_each_newest(count: usize, mut f: impl FnMut(&Record)) -> bool {
+    let Some(ring) = RING.try_lock() else {
+        return false;
+    };
+
+    let mut total = 0usize;
+    ring.for_each(|_| total += 1);
+    let mut skip = total.saturating_sub(count);
+    ring.for_each(|record| {
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            f(record);
+        }
+    });
+    true
+}
+
+// Linux's number for `level`, which `LOGLEVEL` is compared against.
+fn level_number(level: Level) -> usize {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+        Level::Trace => 8,
+    }
+}
+
+fn console_enabled(level: Level) -> bool {
+    level_number(level) < LOGLEVEL.get()
+}
+
+// Whether the most specific filter in `FILTER` that covers `target` lets `level` through.
+// Targets no filter covers are let through.
+fn module_enabled(target: &str, level: Level) -> bool {
+    let target = target.strip_prefix("yaro::").unwrap_or(target);
+    let mut best: Option<(&str, LevelFilter)> = None;
+
+    for filter in FILTER.get().split(',') {
+        let Some((module, filter)) = filter.split_once('=') else {
+            continue;
+        };
+        let Ok(filter) = LevelFilter::from_str(filter) else {
+            continue;
+        };
+
+        let covers = target
+            .strip_prefix(module)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"));
+        if covers && best.is_none_or(|(best, _)| module.len() >= best.len()) {
+            best = Some((module, filter));
+        }
+    }
+
+    best.is_none_or(|(_, filter)| level <= filter)
+}
+
+fn ticks() -> u64 {
+    (unsafe { read_csr!("time") }) as u64
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let s = ring::truncate(s, MAX_MESSAGE - self.len);
+        self.bytes[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+impl Message {
+    fn as_str(&self) -> &str {
+        // Only whole characters are written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // The filters aren't known until the command line has been parsed
+        !READY.load(Ordering::Acquire) || module_enabled(metadata.target(), metadata.level())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut message = Message {
+            bytes: [0; MAX_MESSAGE],
+            len: 0,
+        };
+        let _ = message.write_fmt(*record.args());
+
+        let record = Record {
+            level: record.level(),
+            hart: hart::id(),
+            ticks: ticks(),
+            target: record.target(),
+            message: message.as_str(),
+        };
+
+        // Printing under the lock keeps the console in the same order as the ring.
+        let mut ring = RING.lock();
+        ring.push(&record);
+        if READY.load(Ordering::Acquire) && console_enabled(record.level) {
+            println!("{}", Line(&record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let record = self.0;
+        let timebase = TIMEBASE.load(Ordering::Relaxed);
+        let seconds = record.ticks / timebase;
+        let micros = record.ticks % timebase * 1_000_000 / timebase;
+        let level = match record.level {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'T',
+        };
+        write!(
+            f,
+            "[{seconds:>5}.{micros:06}] {} {level} {}",
+            record.hart, record.message
+        )
+    }
+}
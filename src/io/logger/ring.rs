@@ -0,0 +1,243 @@
+//! A fixed-size ring of log records, which drops the oldest records to make room for new ones.
+//! Records are packed one after another: a header, then the target and the message.
+
+use log::Level;
+
+/// Messages are cut to this many bytes.
+pub const MAX_MESSAGE: usize = 512;
+const MAX_TARGET: usize = u8::MAX as usize;
+
+// Ticks, hart, level, target length and message length
+const HEADER: usize = 8 + 8 + 1 + 1 + 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub level: Level,
+    pub hart: usize,
+    /// Value of the `time` CSR when it was logged.
+    pub ticks: u64,
+    pub target: &'a str,
+    pub message: &'a str,
+}
+
+pub struct Ring<const N: usize> {
+    buf: [u8; N],
+    // Offsets of the oldest record and of the end of the newest, which only ever grow. They're
+    // taken modulo N to index `buf`.
+    head: u64,
+    tail: u64,
+}
+
+/// The longest prefix of `s` that fits in `len` bytes without splitting a character.
+pub fn truncate(s: &str, len: usize) -> &str {
+    if s.len() <= len {
+        return s;
+    }
+    let mut end = len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn level_from_u8(level: u8) -> Level {
+    match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        assert!(N >= HEADER + MAX_TARGET + MAX_MESSAGE, "a record must fit");
+        Self {
+            buf: [0; N],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    /// Adds `record`, dropping as many of the oldest records as it takes to fit it. Its target
+    /// and message are truncated if they're too long.
+    pub fn push(&mut self, record: &Record) {
+        let target = truncate(record.target, MAX_TARGET);
+        let message = truncate(record.message, MAX_MESSAGE);
+        let size = HEADER + target.len() + message.len();
+
+        while self.tail + size as u64 - self.head > N as u64 {
+            self.head += self.size_at(self.head) as u64;
+        }
+
+        let mut header = [0; HEADER];
+        header[0..8].copy_from_slice(&record.ticks.to_le_bytes());
+        header[8..16].copy_from_slice(&(record.hart as u64).to_le_bytes());
+        header[16] = record.level as u8;
+        header[17] = target.len() as u8;
+        header[18..20].copy_from_slice(&(message.len() as u16).to_le_bytes());
+
+        let mut offset = self.tail;
+        for bytes in [&header[..], target.as_bytes(), message.as_bytes()] {
+            self.write(offset, bytes);
+            offset += bytes.len() as u64;
+        }
+        self.tail = offset;
+    }
+
+    /// Calls `f` on every record, oldest first.
+    pub fn for_each(&self, mut f: impl FnMut(&Record)) {
+        let mut bytes = [0; HEADER + MAX_TARGET + MAX_MESSAGE];
+        let mut offset = self.head;
+        while offset < self.tail {
+            let size = self.size_at(offset);
+            let bytes = &mut bytes[..size];
+            self.read(offset, bytes);
+
+            let target_len = bytes[17] as usize;
+            let (target, message) = bytes[HEADER..].split_at(target_len);
+            f(&Record {
+                level: level_from_u8(bytes[16]),
+                hart: u64::from_le_bytes(bytes[8..16].try_into().unwrap()) as usize,
+                ticks: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+                // Both were written from a `str`, cut at a character boundary
+                target: core::str::from_utf8(target).unwrap_or_default(),
+                message: core::str::from_utf8(message).unwrap_or_default(),
+            });
+            offset += size as u64;
+        }
+    }
+
+    // Nothing empties the kernel's ring, but the tests check both
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    #[allow(dead_code)]
+    pub fn clear(&mut self) {
+        self.head = self.tail;
+    }
+
+    // Size of the record at `offset`, header included.
+    fn size_at(&self, offset: u64) -> usize {
+        let mut header = [0; HEADER];
+        self.read(offset, &mut header);
+        HEADER + header[17] as usize + u16::from_le_bytes([header[18], header[19]]) as usize
+    }
+
+    fn write(&mut self, offset: u64, bytes: &[u8]) {
+        let start = (offset % N as u64) as usize;
+        let (first, second) = bytes.split_at(bytes.len().min(N - start));
+        self.buf[start..start + first.len()].copy_from_slice(first);
+        self.buf[..second.len()].copy_from_slice(second);
+    }
+
+    fn read(&self, offset: u64, bytes: &mut [u8]) {
+        let start = (offset % N as u64) as usize;
+        let first_len = bytes.len().min(N - start);
+        let (first, second) = bytes.split_at_mut(first_len);
+        first.copy_from_slice(&self.buf[start..start + first_len]);
+        second.copy_from_slice(&self.buf[..second.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    use super::*;
+
+    const SIZE: usize = 1024;
+
+    fn record(ticks: u64, message: &str) -> Record<'_> {
+        Record {
+            level: Level::Info,
+            hart: 1,
+            ticks,
+            target: "yaro::test",
+            message,
+        }
+    }
+
+    fn messages<const N: usize>(ring: &Ring<N>) -> Vec<String> {
+        let mut messages = Vec::new();
+        ring.for_each(|record| messages.push(record.message.to_string()));
+        messages
+    }
+
+    #[test_case]
+    fn records_come_back_oldest_first() {
+        let mut ring = Ring::<SIZE>::new();
+        assert!(ring.is_empty());
+
+        let warning = Record {
+            level: Level::Warn,
+            hart: 3,
+            ticks: 42,
+            target: "yaro::mem",
+            message: "low on memory",
+        };
+        ring.push(&record(1, "first"));
+        ring.push(&warning);
+
+        let mut records = Vec::new();
+        ring.for_each(|record| {
+            records.push((
+                record.level,
+                record.hart,
+                record.ticks,
+                record.target.to_string(),
+            ))
+        });
+        assert_eq!(
+            records,
+            [
+                (Level::Info, 1, 1, "yaro::test".to_string()),
+                (Level::Warn, 3, 42, "yaro::mem".to_string()),
+            ]
+        );
+        assert_eq!(messages(&ring), ["first", "low on memory"]);
+    }
+
+    #[test_case]
+    fn oldest_records_are_dropped_to_make_room() {
+        let mut ring = Ring::<SIZE>::new();
+        for i in 0..100 {
+            ring.push(&record(i, &format!("message {i}")));
+        }
+
+        // Whatever's left is the newest records, in order, wrapped around the buffer
+        let messages = messages(&ring);
+        assert!(messages.len() < 100);
+        let first = 100 - messages.len();
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(*message, format!("message {}", first + i));
+        }
+    }
+
+    #[test_case]
+    fn long_messages_are_cut_at_a_character() {
+        let mut ring = Ring::<SIZE>::new();
+        let message = "é".repeat(MAX_MESSAGE);
+        ring.push(&record(0, &message));
+        assert_eq!(messages(&ring), [truncate(&message, MAX_MESSAGE)]);
+        assert_eq!(truncate(&message, MAX_MESSAGE).len(), MAX_MESSAGE);
+        assert_eq!(truncate("éa", 1), "");
+    }
+
+    #[test_case]
+    fn clear_forgets_everything() {
+        let mut ring = Ring::<SIZE>::new();
+        ring.push(&record(0, "gone"));
+        ring.clear();
+        assert!(ring.is_empty());
+        ring.push(&record(1, "kept"));
+        assert_eq!(messages(&ring), ["kept"]);
+    }
+}
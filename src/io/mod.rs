@@ -1 +1,10 @@
+pub mod logger;
+#[cfg(feature = "semihosting")]
+pub mod semihosting;
 pub mod serial;
+
+use crate::cmdline::Param;
+
+/// Messages less important than this aren't printed, with 0 being the most important and 7 the
+/// least, like Linux's log levels.
+pub static LOGLEVEL: Param<usize> = Param::new("loglevel", 7);
@@ -0,0 +1,249 @@
+//! RISC-V semihosting, which has QEMU do I/O on the host when it runs with `-semihosting`. A call
+//! is an `ebreak` between two magic no-ops, with the operation in a0 and a pointer to its
+//! parameters in a1. Without `-semihosting` the `ebreak` traps to the kernel instead, which
+//! skips it (see `skip_trap`), and semihosting is treated as unavailable from then on.
+
+use alloc::{ffi::CString, vec::Vec};
+use core::{
+    arch::asm,
+    ffi::CStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use spin::Once;
+
+const SYS_OPEN: usize = 0x01;
+const SYS_CLOSE: usize = 0x02;
+const SYS_WRITE: usize = 0x05;
+const SYS_READ: usize = 0x06;
+const SYS_FLEN: usize = 0x0c;
+const SYS_ERRNO: usize = 0x13;
+const SYS_EXIT_EXTENDED: usize = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+
+// `slli zero, zero, 0x1f` and `ebreak`
+const ENTRY_NOP: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+
+// Whether semihosting works, which is found out by the first call
+const UNKNOWN: u8 = 0;
+const AVAILABLE: u8 = 1;
+const UNAVAILABLE: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+// The host's stdout, opened on first use
+static STDOUT: Once<File> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SemihostingError {
+    /// QEMU isn't running with `-semihosting`.
+    Unavailable,
+    /// A path has a NUL in it.
+    InvalidPath,
+    /// The host call failed, with the host's errno.
+    Host(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // Host files are only opened by the tests so far
+    #[allow(dead_code)]
+    Read,
+    /// Creates the file, or truncates it.
+    Write,
+    #[allow(dead_code)]
+    Append,
+}
+
+/// A file on the host. It's closed when dropped.
+#[derive(Debug)]
+pub struct File {
+    handle: usize,
+}
+
+// QEMU only recognizes the sequence if it's all in one page, hence the alignment.
+#[inline(never)]
+unsafe fn call(op: usize, param: usize) -> usize {
+    let ret;
+    unsafe {
+        asm!(
+            ".balign 16",
+            ".option push",
+            ".option norvc",
+            "slli zero, zero, 0x1f",
+            "ebreak",
+            "srai zero, zero, 7",
+            ".option pop",
+            inout("a0") op => ret,
+            in("a1") param,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Whether QEMU is running with `-semihosting`. The first call finds out, by making a call
+/// that has no effect.
+pub fn available() -> bool {
+    if STATE.load(Ordering::Acquire) == UNKNOWN {
+        unsafe { call(SYS_ERRNO, 0) };
+        // Unless the call trapped, and `skip_trap` marked it unavailable
+        let _ = STATE.compare_exchange(UNKNOWN, AVAILABLE, Ordering::AcqRel, Ordering::Acquire);
+    }
+    STATE.load(Ordering::Acquire) == AVAILABLE
+}
+
+/// Called on a breakpoint trap from S-mode at `pc`. If it was a semihosting call, marks
+/// semihosting unavailable and returns true, and the trap handler should resume after the
+/// `ebreak`.
+pub fn skip_trap(pc: usize) -> bool {
+    // `call` aligns the sequence to 16 bytes, so its `ebreak` is always 4 bytes in. Any other
+    // `ebreak`, like a GDB breakpoint, may be compressed and only 2-byte aligned, or the first
+    // instruction of a page, so it mustn't be read around.
+    if pc % 16 != 4 {
+        return false;
+    }
+
+    // The sequence never crosses a page, so the entry no-op can be read if `pc` can.
+    let is_call = unsafe {
+        (pc as *const u32).read() == EBREAK && (pc as *const u32).sub(1).read() == ENTRY_NOP
+    };
+    if is_call {
+        STATE.store(UNAVAILABLE, Ordering::Release);
+    }
+    is_call
+}
+
+// Makes a call that returns -1 on failure.
+fn checked_call(op: usize, param: usize) -> Result<usize, SemihostingError> {
+    if !available() {
+        return Err(SemihostingError::Unavailable);
+    }
+    match unsafe { call(op, param) } {
+        usize::MAX => Err(SemihostingError::Host(unsafe { call(SYS_ERRNO, 0) })),
+        ret => Ok(ret),
+    }
+}
+
+/// Writes `s` to the host's stdout, NULs and all. Never allocates, as the logger prints through
+/// here before there's a heap, and while holding its lock.
+pub fn print(s: &str) -> Result<(), SemihostingError> {
+    let stdout = STDOUT.try_call_once(|| File::open_c_str(c":tt", Mode::Write))?;
+    stdout.write_all(s.as_bytes())
+}
+
+/// Makes QEMU exit with `code`. Only returns if semihosting isn't available.
+pub fn exit(code: usize) {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code];
+    let _ = checked_call(SYS_EXIT_EXTENDED, block.as_ptr() as usize);
+}
+
+impl Mode {
+    // ISO C `fopen` modes, numbered from "r"
+    fn fopen_mode(self) -> usize {
+        match self {
+            Self::Read => 1,   // "rb"
+            Self::Write => 5,  // "wb"
+            Self::Append => 9, // "ab"
+        }
+    }
+}
+
+impl File {
+    /// Opens `path`, relative to QEMU's working directory. ":tt" is the host's terminal.
+    // Only the tests read or write host files so far
+    #[allow(dead_code)]
+    pub fn open(path: &str, mode: Mode) -> Result<Self, SemihostingError> {
+        let path = CString::new(path).map_err(|_| SemihostingError::InvalidPath)?;
+        Self::open_c_str(&path, mode)
+    }
+
+    // Like `open`, without allocating.
+    fn open_c_str(path: &CStr, mode: Mode) -> Result<Self, SemihostingError> {
+        let block = [
+            path.as_ptr() as usize,
+            mode.fopen_mode(),
+            path.count_bytes(),
+        ];
+        let handle = checked_call(SYS_OPEN, block.as_ptr() as usize)?;
+        Ok(Self { handle })
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> Result<usize, SemihostingError> {
+        let block = [self.handle];
+        checked_call(SYS_FLEN, block.as_ptr() as usize)
+    }
+
+    /// Reads into `buf`, returning how many bytes were read. 0 means the end of the file.
+    #[allow(dead_code)]
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, SemihostingError> {
+        let block = [self.handle, buf.as_mut_ptr() as usize, buf.len()];
+        // Returns how many bytes weren't read
+        let left = checked_call(SYS_READ, block.as_ptr() as usize)?;
+        Ok(buf.len() - left.min(buf.len()))
+    }
+
+    /// Reads the rest of the file.
+    #[allow(dead_code)]
+    pub fn read_to_end(&self) -> Result<Vec<u8>, SemihostingError> {
+        let mut data = Vec::with_capacity(self.len()?);
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                len => data.extend_from_slice(&chunk[..len]),
+            }
+        }
+    }
+
+    pub fn write_all(&self, buf: &[u8]) -> Result<(), SemihostingError> {
+        let block = [self.handle, buf.as_ptr() as usize, buf.len()];
+        // Returns how many bytes weren't written
+        match checked_call(SYS_WRITE, block.as_ptr() as usize)? {
+            0 => Ok(()),
+            _ => Err(SemihostingError::Host(unsafe { call(SYS_ERRNO, 0) })),
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let block = [self.handle];
+        let _ = checked_call(SYS_CLOSE, block.as_ptr() as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn paths_with_nul_are_rejected() {
+        assert_eq!(
+            File::open("a\0b", Mode::Read).err(),
+            Some(SemihostingError::InvalidPath)
+        );
+    }
+
+    #[test_case]
+    fn host_files_round_trip() {
+        if !available() {
+            return;
+        }
+
+        let path = "target/semihosting-test";
+        let write = |mode, data: &[u8]| File::open(path, mode).unwrap().write_all(data).unwrap();
+        write(Mode::Write, b"old");
+        write(Mode::Write, b"hello, ");
+        write(Mode::Append, b"host");
+
+        let file = File::open(path, Mode::Read).unwrap();
+        assert_eq!(file.len(), Ok(11));
+        assert_eq!(file.read_to_end().unwrap(), b"hello, host");
+        let mut buf = [0; 4];
+        assert_eq!(file.read(&mut buf), Ok(0));
+    }
+}
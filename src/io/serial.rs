@@ -2,30 +2,63 @@ use core::fmt::Write;
 
 use spin::Mutex;
 
-use crate::sbi::SbiCall;
+use crate::{cmdline::Param, sbi};
+
+/// The console to print to: `sbi`, or `semihosting` with the `semihosting` feature.
+pub static CONSOLE: Param<&str> = Param::new("console", "sbi");
 
 pub struct Serial(());
 
+/// Writes to `CONSOLE` without taking `SERIAL`, which a panicking hart may be holding. Only for
+/// panics, as nothing keeps its output from interleaving with other writers.
+pub struct Emergency;
+
 pub static SERIAL: Mutex<Serial> = Mutex::new(Serial(()));
 
+/// Whether `CONSOLE` names a console that works. If it doesn't, the SBI console is used.
+pub fn console_available() -> bool {
+    match CONSOLE.get() {
+        "sbi" => true,
+        #[cfg(feature = "semihosting")]
+        "semihosting" => super::semihosting::available(),
+        _ => false,
+    }
+}
+
 impl Write for Serial {
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        let ret = unsafe { SbiCall::new().with_arg0(c as usize).with_eid(1).call() };
-        if ret.is_success() {
-            Ok(())
-        } else {
-            Err(core::fmt::Error)
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        #[cfg(feature = "semihosting")]
+        if CONSOLE.get() == "semihosting" && super::semihosting::print(s).is_ok() {
+            return Ok(());
         }
+
+        write_sbi(s)
     }
+}
 
+impl Write for Emergency {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.chars() {
-            self.write_char(c)?;
+        match CONSOLE.try_get() {
+            #[cfg(feature = "semihosting")]
+            Some("semihosting") if super::semihosting::print(s).is_ok() => Ok(()),
+            // The SBI console, which is also the fallback if the param is locked
+            _ => write_sbi(s),
         }
-        Ok(())
     }
 }
 
+fn write_sbi(s: &str) -> core::fmt::Result {
+    for byte in s.bytes() {
+        sbi::console_putchar(byte).map_err(|_| core::fmt::Error)?;
+    }
+    Ok(())
+}
+
+/// The next byte typed on the console, if there's one.
+pub fn read_byte() -> Option<u8> {
+    sbi::console_getchar()
+}
+
 #[doc(hidden)]
 pub fn print_inner(args: core::fmt::Arguments) {
     let _ = SERIAL.lock().write_fmt(args);
@@ -0,0 +1,275 @@
+//! Kernel address space layout randomisation. The image is linked as a position-independent
+//! executable at `VIRT_KERNEL`, and `_boot` maps it there. Early in `kmain`, it's mapped again
+//! at a random megapage-aligned slot of the same GiB, its dynamic relocations are applied for
+//! the new address, and execution continues there. Since both mappings point at the same memory
+//! until the old one is removed, every pointer stays valid while the relocations are applied.
+
+use core::{
+    arch::asm,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use fdt::Fdt;
+
+use crate::{
+    boot::{IMAGE_PAGE, KERNEL_IMAGE_PT, image_virt_range},
+    io::serial::println,
+    mem::paging::{entry::Entry, flush_all, table::ENTRY_COUNT},
+};
+
+const R_RISCV_RELATIVE: usize = 3;
+
+// Zkr's entropy source. Reading it requires a write, so it's accessed with csrrw.
+const CSR_SEED: usize = 0x015;
+const SEED_OPST_SHIFT: usize = 30;
+const SEED_OPST_ES16: usize = 0b10;
+const SEED_OPST_DEAD: usize = 0b11;
+
+static SLIDE: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
+}
+
+unsafe extern "C" {
+    static __rela_start: Rela;
+    static __rela_end: Rela;
+    static __stack_top: u8;
+}
+
+/// How far the image was moved from its link address.
+pub fn slide() -> usize {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+// splitmix64's finalizer, to spread seed bits over the whole word.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn has_zkr(fdt: &Fdt) -> bool {
+    let Some(cpu) = fdt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.children().find(|node| node.name.starts_with("cpu@")))
+    else {
+        return false;
+    };
+
+    let in_isa_string = cpu
+        .property("riscv,isa")
+        .and_then(|isa| isa.as_str())
+        .is_some_and(|isa| isa.split('_').any(|ext| ext == "zkr"));
+    let in_extensions = cpu
+        .property("riscv,isa-extensions")
+        .is_some_and(|extensions| {
+            extensions
+                .value
+                .split(|&byte| byte == 0)
+                .any(|ext| ext == b"zkr")
+        });
+
+    in_isa_string || in_extensions
+}
+
+/// 64 bits from the seed CSR, or `None` if the entropy source is dead.
+fn seed_csr() -> Option<u64> {
+    let mut seed = 0;
+    let mut bits = 0;
+    // Bounded, in case the source keeps reporting BIST or WAIT.
+    for _ in 0..1024 {
+        let value: usize;
+        unsafe { asm!("csrrw {}, {csr}, zero", out(reg) value, csr = const CSR_SEED) };
+
+        match value >> SEED_OPST_SHIFT & 0b11 {
+            SEED_OPST_ES16 => {
+                seed = seed << 16 | (value & 0xffff) as u64;
+                bits += 16;
+                if bits == 64 {
+                    return Some(seed);
+                }
+            }
+            SEED_OPST_DEAD => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Entropy from the device tree's `/chosen/rng-seed`, or failing that, from Zkr.
+fn entropy(fdt: &Fdt) -> Option<u64> {
+    let rng_seed = fdt
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("rng-seed"))
+        .filter(|seed| !seed.value.is_empty());
+    if let Some(seed) = rng_seed {
+        let mixed = seed.value.chunks(8).fold(0, |acc, chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            mix(acc ^ u64::from_le_bytes(word))
+        });
+        return Some(mixed);
+    }
+
+    if has_zkr(fdt) {
+        return seed_csr().map(mix);
+    }
+
+    None
+}
+
+/// The slide to a random slot for an image of `pages` image pages. Slots are image pages of the
+/// image's table, and the new slot mustn't overlap the image's current one, which is slot 0.
+fn pick_slide(random: u64, pages: usize) -> usize {
+    let slots = (ENTRY_COUNT + 1 - 2 * pages) as u64;
+    (pages + (random % slots) as usize) * IMAGE_PAGE.size()
+}
+
+/// Applies the image's dynamic relocations for an image that's `slide` bytes away from its link
+/// address. The writes go through the mapping at the link address.
+unsafe fn relocate(slide: usize) {
+    let start = &raw const __rela_start;
+    let end = &raw const __rela_end;
+    let relas = unsafe { slice::from_raw_parts(start, end.offset_from_unsigned(start)) };
+    unsafe { apply_relocations(relas, slide) };
+}
+
+unsafe fn apply_relocations(relas: &[Rela], slide: usize) {
+    for rela in relas {
+        assert_eq!(
+            rela.info & 0xffffffff,
+            R_RISCV_RELATIVE,
+            "kaslr: unsupported relocation type"
+        );
+        unsafe { (rela.offset as *mut usize).write(rela.addend.wrapping_add(slide)) };
+    }
+}
+
+/// Moves the image to a random address and continues in `entry` there, with the same arguments.
+/// Keeps the image where it is if there's no entropy to pick an address with.
+///
+/// # Safety
+/// Must be called once, from `kmain`, before anything stores pointers into the image.
+pub unsafe fn randomize(
+    fdt: &Fdt,
+    hart_id: usize,
+    dtb_addr: usize,
+    entry: unsafe extern "C" fn(usize, usize) -> !,
+) -> ! {
+    let image = image_virt_range();
+    let page_size = IMAGE_PAGE.size();
+    let pages = (image.end - image.start).div_ceil(page_size);
+
+    let slide = match entropy(fdt) {
+        Some(random) => pick_slide(random, pages),
+        None => {
+            println!("kaslr: no entropy source, not randomizing");
+            0
+        }
+    };
+
+    // Taken before relocating, when these still come out relative to the link address.
+    let stack_top = &raw const __stack_top as usize + slide;
+    let entry = entry as usize + slide;
+
+    if slide != 0 {
+        let table = &raw mut KERNEL_IMAGE_PT;
+        let entries = unsafe { &mut (*table).0 };
+        entries.copy_within(0..pages, slide / page_size);
+        flush_all();
+
+        unsafe { relocate(slide) };
+    }
+    SLIDE.store(slide, Ordering::Relaxed);
+    unsafe {
+        asm!(
+            "mv sp, {stack_top}",
+            // terminate the frame pointer chain for `backtrace::walk`
+            "li s0, 0",
+            "li ra, 0",
+            "jr {entry}",
+            stack_top = in(reg) stack_top,
+            entry = in(reg) entry,
+            in("a0") hart_id,
+            in("a1") dtb_addr,
+            options(noreturn),
+        )
+    }
+}
+
+/// Removes the mapping at the link address once execution has moved to the new one.
+///
+/// # Safety
+/// Must be called once, by the `entry` passed to `randomize`.
+pub unsafe fn finish() {
+    let slide = slide();
+    if slide == 0 {
+        return;
+    }
+
+    let page_size = IMAGE_PAGE.size();
+    let image = image_virt_range();
+    let pages = (image.end - image.start).div_ceil(page_size);
+
+    let table = &raw mut KERNEL_IMAGE_PT;
+    let entries = unsafe { &mut (*table).0 };
+    entries[..pages].fill(Entry::new());
+    flush_all();
+
+    println!(
+        "kaslr: kernel at {:#x} (slide {slide:#x})",
+        image.start.as_usize()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn slides_keep_the_image_clear_of_its_old_slot() {
+        let page_size = IMAGE_PAGE.size();
+        let pages = 3;
+        let slots = (ENTRY_COUNT + 1 - 2 * pages) as u64;
+
+        assert_eq!(pick_slide(0, pages), pages * page_size);
+        assert_eq!(
+            pick_slide(slots - 1, pages),
+            (ENTRY_COUNT - pages) * page_size
+        );
+        assert_eq!(pick_slide(slots, pages), pick_slide(0, pages));
+
+        for random in [1, 0x1234_5678, u64::MAX] {
+            let slide = pick_slide(random, pages);
+            assert!(slide.is_multiple_of(page_size));
+            assert!(slide >= pages * page_size);
+            assert!(slide + pages * page_size <= ENTRY_COUNT * page_size);
+        }
+    }
+
+    #[test_case]
+    fn relocations_add_the_slide_to_the_addend() {
+        let mut words = [0usize; 2];
+        let relas = [
+            Rela {
+                offset: &raw mut words[0] as usize,
+                info: R_RISCV_RELATIVE,
+                addend: 0x1000,
+            },
+            Rela {
+                // The symbol index in the upper half doesn't matter
+                offset: &raw mut words[1] as usize,
+                info: 7 << 32 | R_RISCV_RELATIVE,
+                addend: usize::MAX,
+            },
+        ];
+
+        unsafe { apply_relocations(&relas, 0x20_0000) };
+        assert_eq!(words, [0x20_1000, 0x1f_ffff]);
+    }
+}
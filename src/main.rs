@@ -1,99 +1,138 @@
 #![no_std]
 #![no_main]
 #![allow(clippy::unusual_byte_groupings)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test::runner)]
+#![reexport_test_harness_main = "test_main"]
 
 mod asm;
+mod backtrace;
 mod boot;
+mod cmdline;
+mod drivers;
+mod fs;
+mod gdb;
+mod hart;
 mod int;
 mod io;
+mod kaslr;
 mod mem;
+mod monitor;
+mod panic;
+mod pstore;
 mod sbi;
 mod sched;
+#[cfg(test)]
+mod test;
 
 extern crate alloc;
 
-use core::panic::PanicInfo;
-
-use talc::Span;
+use fdt::Fdt;
+use log::{error, info, warn};
 
 use crate::{
-    boot::{PHEAP_LEN, PHYS_PHEAP},
-    io::serial::print,
-    mem::{
-        addr::VirtAddr,
-        alloc::{Block, HEAP_ALLOCATOR, PAGE_ALLOCATOR},
-    },
+    mem::addr::{PhysAddr, VirtAddr},
+    sbi::{ResetReason, ResetType},
 };
 
-fn shutdown() -> ! {
-    const SHUTDOWN_EID: usize = 0x53525354;
-    let _ = unsafe { sbi::SbiCall::default().with_eid(SHUTDOWN_EID).call() };
+/// Powers off. Under QEMU, a non-zero `code` makes it exit with a failure status.
+fn exit(code: u16) -> ! {
+    #[cfg(feature = "semihosting")]
+    io::semihosting::exit(code as usize);
 
-    loop {
-        core::hint::spin_loop();
-    }
-}
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    print!("Kernel panic in ");
+    // SBI can only pass on whether it was a failure, and firmware may ignore even that.
+    drivers::sifive_test::exit(code);
 
-    if let Some(location) = info.location() {
-        print!("[{}:{}]: ", location.file(), location.line());
+    let reason = if code == 0 {
+        ResetReason::None
     } else {
-        print!("[Location unavailable]: ");
-    }
-
-    print!("{}", info.message());
+        ResetReason::SystemFailure
+    };
+    let _ = sbi::system_reset(ResetType::Shutdown, reason);
 
-    shutdown();
+    loop {
+        core::hint::spin_loop();
+    }
 }
 
-unsafe extern "C" {
-    static mut _sbss: usize;
-    static mut _ebss: usize;
+fn shutdown() -> ! {
+    exit(0)
 }
 
-unsafe fn zero_bss() {
-    let mut ptr = &raw mut _sbss;
-    while ptr < &raw mut _ebss {
-        unsafe {
-            ptr.write(0);
-            ptr = ptr.add(1)
-        }
+unsafe extern "C" fn kmain(hart_id: usize, dtb_addr: usize) -> ! {
+    unsafe {
+        hart::set_id(hart_id);
+        int::set_kernel_entry();
     }
-}
-
-unsafe extern "C" fn kmain(_hart_id: usize, _dtb_addr: usize) -> ! {
     crate::io::serial::println!("Hello World!");
 
+    let fdt = unsafe { Fdt::from_ptr(VirtAddr::from_phys(PhysAddr(dtb_addr)).as_ptr()) }
+        .expect("invalid device tree");
+    unsafe { kaslr::randomize(&fdt, hart_id, dtb_addr, kmain_randomized) }
+}
+
+/// Where `kmain` continues once the image has been moved.
+unsafe extern "C" fn kmain_randomized(_hart_id: usize, dtb_addr: usize) -> ! {
     unsafe {
-        zero_bss();
+        kaslr::finish();
+        // stvec still points into the old mapping
         int::set_kernel_entry();
+        int::enable_ipis();
+        io::logger::init();
+    }
+
+    let dtb_addr = PhysAddr(dtb_addr);
+    let fdt = unsafe { Fdt::from_ptr(VirtAddr::from_phys(dtb_addr).as_ptr()) }
+        .expect("invalid device tree");
 
-        let mut page_alloc = PAGE_ALLOCATOR.lock();
+    io::logger::set_timebase(&fdt);
+    cmdline::parse(fdt.chosen().bootargs().unwrap_or(""));
+    if !io::serial::console_available() {
+        warn!(
+            "console: {} isn't available, using sbi",
+            io::serial::CONSOLE.get()
+        );
+    }
+    io::logger::console_ready();
 
-        let pheap_block = Block::from_range(PHYS_PHEAP, PHYS_PHEAP + PHEAP_LEN).unwrap();
-        page_alloc.claim(pheap_block);
+    unsafe {
+        // The kernel heap grows out of the page allocator on demand (see `mem::heap`), so the
+        // page allocator lock mustn't be held across heap allocations.
+        mem::init(&fdt, dtb_addr);
+        mem::vmalloc::init();
+    }
 
-        const HEAP_ORDER: usize = 3;
+    pstore::init(&fdt, dtb_addr);
 
-        let heap_allocation = page_alloc.alloc(HEAP_ORDER).unwrap();
-        let span = Span::new(
-            VirtAddr::from_phys(heap_allocation.start()).as_ptr(),
-            VirtAddr::from_phys(heap_allocation.end()).as_ptr(),
-        );
+    unsafe { drivers::probe(&fdt) };
+    gdb::init(&fdt);
 
-        HEAP_ALLOCATOR.lock().claim(span).unwrap();
+    fs::initramfs::init(&fdt);
+    match fs::initramfs::open(sched::INIT.get()) {
+        Ok(init) => {
+            info!("init: {}, {} bytes", sched::INIT.get(), init.len());
+        }
+        Err(err) => {
+            error!("init: {}: {err:?}", sched::INIT.get());
+        }
     }
 
-    // Kernel heap test
-    let mut vec = alloc::vec::Vec::new();
-    for i in 0..100 {
-        vec.push(i);
+    if mem::swap::ENABLE.get() {
+        match drivers::block::devices().into_iter().next() {
+            Some(device) => mem::swap::enable(device),
+            None => {
+                warn!("swap: no block device");
+            }
+        }
     }
 
-    crate::io::serial::println!("{vec:?}");
+    #[cfg(test)]
+    test_main();
+
+    #[cfg(not(test))]
+    if monitor::requested() {
+        monitor::run();
+    }
 
     shutdown();
 }
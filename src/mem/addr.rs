@@ -3,11 +3,69 @@ use core::{
     ptr::NonNull,
 };
 
-use crate::boot::{PHEAP_LEN, PHYS_PHEAP, PHYS_RAM_START, VIRT_PHEAP, VIRT_RAM_START};
+use spin::Once;
 
-pub const KERNEL_MEM: Range<usize> =
-    PHYS_RAM_START.as_usize()..(PHYS_RAM_START.as_usize() + 0x40000000);
-pub const PHEAP_MEM: Range<usize> = PHYS_PHEAP.as_usize()..(PHYS_PHEAP.as_usize() + PHEAP_LEN);
+use crate::{
+    boot::{VIRT_RAM_START, phys_ram_start},
+    mem::paging,
+};
+
+// Replaces `LinearMap` when set.
+static PHYS_MAP: Once<&'static dyn PhysMap> = Once::new();
+
+/// Where `mem::init` maps RAM past `kernel_mem`, offset like it is from the start of
+/// `kernel_mem`. The vmalloc region follows it.
+pub const DIRECT_MAP_START: VirtAddr = VirtAddr::new_const(0xffffffc000000000);
+pub const DIRECT_MAP_LEN: usize = 64 * 1024 * 1024 * 1024;
+
+/// The GiB the kernel was loaded into, which `_boot` maps, so it's reachable through
+/// `VirtAddr::from_phys` from the start.
+pub fn kernel_mem() -> Range<usize> {
+    let start = phys_ram_start().as_usize();
+    start..start + 0x40000000
+}
+
+/// Physical memory the direct map has room for, `kernel_mem` included. Only the RAM in it past
+/// `kernel_mem` is actually mapped there.
+pub fn direct_map() -> Range<usize> {
+    let start = kernel_mem().start;
+    start..start + DIRECT_MAP_LEN
+}
+
+/// Translates physical addresses for `VirtAddr::from_phys`.
+pub trait PhysMap: Sync {
+    /// Where `phys` can be accessed, or `None` if it can't be.
+    fn phys_to_virt(&self, phys: PhysAddr) -> Option<usize>;
+}
+
+/// The kernel's mapping of `kernel_mem` at `VIRT_RAM_START`, set up by `_boot`, and of the rest
+/// of RAM at `DIRECT_MAP_START`, set up by `mem::init`.
+struct LinearMap;
+
+impl PhysMap for LinearMap {
+    fn phys_to_virt(&self, phys: PhysAddr) -> Option<usize> {
+        let start = if kernel_mem().contains(&phys.as_usize()) {
+            VIRT_RAM_START
+        } else if direct_map().contains(&phys.as_usize()) {
+            DIRECT_MAP_START
+        } else {
+            return None;
+        };
+        Some((phys - phys_ram_start()) + start.as_usize())
+    }
+}
+
+/// Makes `from_phys` go through `map` instead of the linear map, which lets the memory code run
+/// on the host with a buffer standing in for RAM. Only the first call has any effect.
+// Only called by the host tests
+#[allow(dead_code)]
+pub fn set_phys_map(map: &'static dyn PhysMap) {
+    PHYS_MAP.call_once(|| map);
+}
+
+/// Width of a virtual address under Sv39, the narrowest paging mode. Addresses that are
+/// canonical under Sv39 are canonical under every mode.
+pub const MIN_VA_BITS: usize = 39;
 
 pub trait Addr: Copy + Eq + Ord {
     fn try_new(addr: usize) -> Option<Self>;
@@ -93,12 +151,18 @@ impl Sub for PhysAddr {
     }
 }
 
+const fn sign_extend(addr: usize, bits: usize) -> usize {
+    let shift = usize::BITS as usize - bits;
+    ((addr << shift) as isize >> shift) as usize
+}
+
 impl VirtAddr {
-    const fn new_truncate(addr: usize) -> Self {
-        Self(((addr << 25) as isize >> 25) as usize)
+    /// Sign-extends the low bits of `addr` according to the current paging mode.
+    pub fn new_truncate(addr: usize) -> Self {
+        Self(sign_extend(addr, paging::mode().va_bits()))
     }
 
-    pub const fn try_new(addr: usize) -> Option<Self> {
+    pub fn try_new(addr: usize) -> Option<Self> {
         if Self::new_truncate(addr).0 == addr {
             Some(Self(addr))
         } else {
@@ -106,17 +170,24 @@ impl VirtAddr {
         }
     }
 
-    pub const fn new(addr: usize) -> Self {
+    pub fn new(addr: usize) -> Self {
         Self::try_new(addr).expect("non-canonical virtual address")
     }
 
+    /// For constants: checks `addr` against Sv39, so it's canonical whatever mode is picked at
+    /// boot.
+    pub const fn new_const(addr: usize) -> Self {
+        if sign_extend(addr, MIN_VA_BITS) != addr {
+            panic!("non-canonical virtual address");
+        }
+        Self(addr)
+    }
+
     pub fn from_phys(phys: PhysAddr) -> Self {
-        if PHEAP_MEM.contains(&phys.as_usize()) {
-            Self::new((phys - PHYS_PHEAP) + VIRT_PHEAP.as_usize())
-        } else if KERNEL_MEM.contains(&phys.as_usize()) {
-            Self::new((phys - PHYS_RAM_START) + VIRT_RAM_START.as_usize())
-        } else {
-            panic!("physical address {phys:?} not mapped");
+        let map = PHYS_MAP.get().copied().unwrap_or(&LinearMap);
+        match map.phys_to_virt(phys) {
+            Some(addr) => Self::new(addr),
+            None => panic!("physical address {phys:?} not mapped"),
         }
     }
 
@@ -143,6 +214,17 @@ impl VirtAddr {
     pub const fn vpn2(self) -> usize {
         self.0 >> 30 & 0o777
     }
+
+    /// Index into the page table at `level` (0 being the last level).
+    pub const fn vpn(self, level: usize) -> usize {
+        self.0 >> (12 + 9 * level) & 0o777
+    }
+
+    /// Offset of the address in a linear space without the non-canonical hole, as wide as the
+    /// current mode's virtual addresses.
+    pub fn linear(self) -> usize {
+        self.0 & ((1 << paging::mode().va_bits()) - 1)
+    }
 }
 
 impl From<VirtAddr> for usize {
@@ -151,10 +233,84 @@ impl From<VirtAddr> for usize {
     }
 }
 
-// impl<T: Into<usize>> Add<T> for VirtAddr {
-//     type Output = Self;
+impl Add<usize> for VirtAddr {
+    type Output = Self;
+
+    fn add(self, rhs: usize) -> Self::Output {
+        Self::new(self.0 + rhs)
+    }
+}
+
+impl Sub<usize> for VirtAddr {
+    type Output = Self;
+
+    fn sub(self, rhs: usize) -> Self::Output {
+        Self::new(self.0 - rhs)
+    }
+}
 
-//     fn add(self, rhs: T) -> Self::Output {
-//         Self(self.0 + )
-//     }
-// }
+impl Sub for VirtAddr {
+    type Output = usize;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{PAGE_SIZE, alloc::PAGE_ALLOCATOR};
+
+    #[test_case]
+    fn canonical_addresses_follow_the_mode() {
+        let bits = paging::mode().va_bits();
+        let low_end = (1 << (bits - 1)) - 1;
+        let high_start = !low_end;
+
+        assert!(VirtAddr::try_new(low_end).is_some());
+        assert!(VirtAddr::try_new(high_start).is_some());
+        assert!(VirtAddr::try_new(low_end + 1).is_none());
+        assert!(VirtAddr::try_new(high_start - 1).is_none());
+        assert_eq!(VirtAddr::new_truncate(low_end + 1).as_usize(), high_start);
+        assert_eq!(VirtAddr::new(high_start).linear(), low_end + 1);
+    }
+
+    #[test_case]
+    fn page_table_indices() {
+        let addr = VirtAddr::new_const(0x3f_c060_3abc);
+        assert_eq!(addr.vpn(0), addr.vpn0());
+        assert_eq!(addr.vpn(1), addr.vpn1());
+        assert_eq!(addr.vpn(2), addr.vpn2());
+        assert_eq!(
+            [addr.vpn0(), addr.vpn1(), addr.vpn2()],
+            [0x003, 0x003, 0x0ff]
+        );
+
+        let phys = PhysAddr(0x1_4060_3abc);
+        assert_eq!(phys.ppn(), 0x1_4060_3);
+        assert_eq!([phys.ppn0(), phys.ppn1(), phys.ppn2()], [0x003, 0x003, 0x5]);
+    }
+
+    #[test_case]
+    fn from_phys_reaches_memory() {
+        let allocation = PAGE_ALLOCATOR.lock().alloc(1).unwrap();
+        let start = allocation.start();
+
+        // Both pages are contiguous through the mapping too
+        let first = VirtAddr::from_phys(start);
+        assert_eq!(VirtAddr::from_phys(start + PAGE_SIZE), first + PAGE_SIZE);
+
+        let value = 0x0123_4567_89ab_cdef_u64;
+        unsafe {
+            first
+                .as_ptr::<u64>()
+                .add(PAGE_SIZE / 8)
+                .write_volatile(value)
+        };
+        let second = VirtAddr::from_phys(start + PAGE_SIZE).as_ptr::<u64>();
+        assert_eq!(unsafe { second.read_volatile() }, value);
+
+        PAGE_ALLOCATOR.lock().free(allocation);
+    }
+}
@@ -1,16 +1,16 @@
+#[cfg(feature = "debug-alloc")]
+use core::panic::Location;
 use core::{num::NonZeroUsize, ptr::NonNull};
 
 use spin::Mutex;
-use talc::{ErrOnOom, Talc, Talck};
 
-use super::{PAGE_SIZE, addr::VirtAddr};
+use super::{PAGE_SIZE, addr::VirtAddr, frame::FRAME_TABLE};
+#[cfg(feature = "debug-alloc")]
+use super::{debug, frame::FrameFlags};
 use crate::mem::addr::PhysAddr;
 
 pub static PAGE_ALLOCATOR: Mutex<BiBuddy> = Mutex::new(BiBuddy::new());
 
-#[global_allocator]
-pub static HEAP_ALLOCATOR: Talck<Mutex<()>, ErrOnOom> = Talck::new(Talc::new(ErrOnOom));
-
 pub const HIGHEST_ORDER: usize = 12;
 pub const ORDER_COUNT: usize = HIGHEST_ORDER + 1;
 
@@ -36,7 +36,11 @@ pub struct BiBuddy {
 
 // Used to verify that a block did indeed come from the allocator.
 #[derive(Debug)]
-pub struct Allocation(Block);
+pub struct Allocation {
+    block: Block,
+    #[cfg(feature = "debug-alloc")]
+    site: &'static Location<'static>,
+}
 
 unsafe impl Send for Block {}
 unsafe impl Sync for Block {}
@@ -73,17 +77,6 @@ impl Block {
         }
     }
 
-    pub unsafe fn from_range(mut start: PhysAddr, end: PhysAddr) -> Option<Self> {
-        start = PhysAddr(start.as_usize().next_multiple_of(PAGE_SIZE));
-        if start >= end {
-            return None;
-        }
-
-        let size_pages = (end - start) / PAGE_SIZE;
-        let order = size_pages.ilog2() as usize;
-        Some(unsafe { Self::new(start, order) }.unwrap())
-    }
-
     pub const fn addr(&self) -> NonZeroUsize {
         self.phys_addr
     }
@@ -92,6 +85,10 @@ impl Block {
         self.order
     }
 
+    fn header(&self) -> &BlockHeader {
+        unsafe { self.header_ptr().as_ref() }
+    }
+
     fn header_mut(&mut self) -> &mut BlockHeader {
         unsafe { self.header_ptr().as_mut() }
     }
@@ -146,6 +143,10 @@ impl FreeList {
         head
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        core::iter::successors(self.head.as_ref(), |block| block.header().next.as_ref())
+    }
+
     pub fn peek(&self) -> Option<&Block> {
         self.head.as_ref()
     }
@@ -166,9 +167,10 @@ impl FreeList {
             current = current.header_mut().next.as_mut()?;
 
             if predicate(current) {
+                // `current` lives in the previous header, so it's gone once taken out of it.
                 return unsafe {
-                    let ret = (*prev.as_ptr()).next.take().unwrap();
-                    (*prev.as_ptr()).next = current.header_mut().next.take();
+                    let mut ret = (*prev.as_ptr()).next.take().unwrap();
+                    (*prev.as_ptr()).next = ret.header_mut().next.take();
                     Some(ret)
                 };
             }
@@ -185,13 +187,38 @@ impl BiBuddy {
         }
     }
 
-    pub unsafe fn claim(&mut self, mut block: Block) {
-        if block.order > HIGHEST_ORDER {
-            unsafe { self.claim(block.split()) };
+    /// Claims every whole page in `[start, end)`, split into the largest naturally aligned
+    /// blocks and merged with any free buddies.
+    pub unsafe fn claim_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.as_usize().next_multiple_of(PAGE_SIZE);
+        let end = end.as_usize() / PAGE_SIZE * PAGE_SIZE;
+
+        while addr < end {
+            let align_order = addr.trailing_zeros() as usize - PAGE_SIZE.trailing_zeros() as usize;
+            let size_order = ((end - addr) / PAGE_SIZE).ilog2() as usize;
+            let order = align_order.min(size_order).min(HIGHEST_ORDER);
+
+            let block = unsafe { Block::new(PhysAddr(addr), order) }.expect("null block address");
+            self.free_inner(block);
+            addr += order_size(order);
         }
-        self.free_lists[block.order].push(block);
     }
 
+    /// How many free blocks there are of each order.
+    pub fn free_counts(&self) -> [usize; ORDER_COUNT] {
+        self.free_lists.each_ref().map(|list| list.iter().count())
+    }
+
+    /// Free memory in bytes.
+    pub fn free_size(&self) -> usize {
+        self.free_counts()
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count * order_size(order))
+            .sum()
+    }
+
+    #[cfg_attr(feature = "debug-alloc", track_caller)]
     pub fn alloc(&mut self, order: usize) -> Option<Allocation> {
         assert!(order < ORDER_COUNT, "order too high");
         let mut next_order = order;
@@ -215,18 +242,85 @@ impl BiBuddy {
         }
 
         block.header_mut().next = None;
-        Some(Allocation(block))
+        Some(Allocation::new(block))
     }
 
+    #[cfg_attr(feature = "debug-alloc", track_caller)]
     pub fn free(&mut self, allocation: Allocation) {
-        self.free_inner(allocation.0);
+        #[cfg(feature = "debug-alloc")]
+        self.check_free(&allocation);
+
+        if let Some(frame_table) = FRAME_TABLE.get() {
+            for frame in frame_table.frames(allocation.start(), allocation.end()) {
+                assert!(
+                    frame.refcount() == 0,
+                    "freeing {:?} (order {}) while one of its pages is still referenced",
+                    allocation.start(),
+                    allocation.order(),
+                );
+                frame.reset();
+            }
+        }
+
+        #[cfg(feature = "debug-alloc")]
+        unsafe {
+            debug::poison(
+                VirtAddr::from_phys(allocation.start()).as_ptr(),
+                allocation.size(),
+            );
+        }
+
+        self.free_inner(allocation.block);
+    }
+
+    // Catches frees of blocks that are misaligned, lie outside of RAM or were never handed
+    // out, and double frees (blocks that overlap one that's already free).
+    #[cfg(feature = "debug-alloc")]
+    #[track_caller]
+    fn check_free(&self, allocation: &Allocation) {
+        let caller = Location::caller();
+        let site = allocation.site;
+        let start = allocation.start().as_usize();
+        let end = allocation.end().as_usize();
+        let order = allocation.order();
+
+        if order > HIGHEST_ORDER || !start.is_multiple_of(allocation.size()) {
+            panic!(
+                "buddy: free of misaligned block {start:#x} (order {order}) at {caller}, allocated at {site}"
+            );
+        }
+
+        if let Some(frame_table) = FRAME_TABLE.get() {
+            let ram = frame_table.range();
+            let reserved = frame_table
+                .frames(allocation.start(), allocation.end())
+                .any(|frame| frame.flags().contains(FrameFlags::RESERVED));
+
+            if start < ram.start.as_usize() || end > ram.end.as_usize() || reserved {
+                panic!(
+                    "buddy: free of unowned block {start:#x} (order {order}) at {caller}, allocated at {site}"
+                );
+            }
+        }
+
+        for (free_order, free_list) in self.free_lists.iter().enumerate() {
+            for block in free_list.iter() {
+                let free_start = block.addr().get();
+                if free_start < end && start < free_start + order_size(free_order) {
+                    panic!(
+                        "buddy: double free of {start:#x} (order {order}) at {caller}, allocated at {site}; \
+                         it overlaps free block {free_start:#x} (order {free_order})"
+                    );
+                }
+            }
+        }
     }
 
     // Unfortunately, this does a linear search of free lists to find buddies.
     // It's also recursive, but I believe rustc should be able to optimize it.
     fn free_inner(&mut self, block: Block) {
         let buddy_addr = block.buddy_addr();
-        if block.order() <= HIGHEST_ORDER
+        if block.order() < HIGHEST_ORDER
             && let Some(buddy) = self.free_lists[block.order()]
                 .remove_if(|other_block| other_block.addr().get() == buddy_addr)
         {
@@ -239,19 +333,297 @@ impl BiBuddy {
 }
 
 impl Allocation {
+    #[cfg_attr(feature = "debug-alloc", track_caller)]
+    fn new(block: Block) -> Self {
+        Self {
+            block,
+            #[cfg(feature = "debug-alloc")]
+            site: Location::caller(),
+        }
+    }
+
+    /// Rebuilds an allocation that was turned into its start address and order with
+    /// `into_raw`, so it can be freed.
+    ///
+    /// # Safety
+    /// `start` and `order` must come from `into_raw`, and each allocation may only be rebuilt
+    /// once.
+    #[cfg_attr(feature = "debug-alloc", track_caller)]
+    pub unsafe fn from_raw(start: PhysAddr, order: usize) -> Self {
+        Self::new(Block {
+            phys_addr: NonZeroUsize::new(start.as_usize()).expect("null allocation address"),
+            order,
+        })
+    }
+
+    pub fn into_raw(self) -> (PhysAddr, usize) {
+        (self.start(), self.order())
+    }
+
     pub const fn start(&self) -> PhysAddr {
-        PhysAddr(self.0.addr().get())
+        PhysAddr(self.block.addr().get())
     }
 
     pub const fn order(&self) -> usize {
-        self.0.order()
+        self.block.order()
     }
 
     pub const fn size(&self) -> usize {
-        PAGE_SIZE * (1 << self.0.order)
+        PAGE_SIZE * (1 << self.block.order)
     }
 
     pub const fn end(&self) -> PhysAddr {
-        PhysAddr(self.0.addr().get() + self.size())
+        PhysAddr(self.block.addr().get() + self.size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const SCRATCH_ORDER: usize = 4;
+    // Big enough for every order to come up, small enough to check after every step.
+    const RANDOM_ORDER: usize = 8;
+    const RANDOM_STEPS: usize = 2000;
+    const SEEDS: [u64; 4] = [1, 0x5eed, 0xdead_beef, 0x1234_5678_9abc_def0];
+
+    // Runs `f` with an empty allocator and a block of `order` from the page allocator for it to
+    // claim.
+    fn with_scratch(order: usize, f: impl FnOnce(&mut BiBuddy, PhysAddr)) {
+        let scratch = PAGE_ALLOCATOR
+            .lock()
+            .alloc(order)
+            .expect("no memory for the test");
+        let mut buddy = BiBuddy::new();
+        f(&mut buddy, scratch.start());
+        PAGE_ALLOCATOR.lock().free(scratch);
+    }
+
+    // xorshift64, so every run makes the same sequence of calls.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        // Order 0 half the time, 1 a quarter of the time and so on, like real callers.
+        fn order(&mut self, max: usize) -> usize {
+            (self.next().trailing_zeros() as usize).min(max)
+        }
+    }
+
+    /// Checks that the free blocks are aligned, on the right lists and fully merged, and that
+    /// together with `live` they tile `[start, end)` without gaps or overlaps.
+    fn check_invariants(buddy: &BiBuddy, live: &[Allocation], start: PhysAddr, end: PhysAddr) {
+        let mut free = Vec::new();
+        for (order, list) in buddy.free_lists.iter().enumerate() {
+            for block in list.iter() {
+                assert_eq!(block.order(), order, "block on the wrong free list");
+                assert!(
+                    block.addr().get().is_multiple_of(order_size(order)),
+                    "misaligned free block {:#x} (order {order})",
+                    block.addr()
+                );
+                free.push((block.addr().get(), order));
+            }
+        }
+
+        free.sort();
+        for &(addr, order) in &free {
+            let buddy_addr = addr ^ order_size(order);
+            assert!(
+                order == HIGHEST_ORDER || free.binary_search(&(buddy_addr, order)).is_err(),
+                "free buddies {addr:#x} and {buddy_addr:#x} (order {order}) weren't merged"
+            );
+        }
+
+        let mut blocks: Vec<_> = live
+            .iter()
+            .map(|allocation| (allocation.start().as_usize(), allocation.size()))
+            .chain(free.iter().map(|&(addr, order)| (addr, order_size(order))))
+            .collect();
+        blocks.sort();
+        let mut next = start.as_usize();
+        for (addr, size) in blocks {
+            assert!(addr >= next, "block {addr:#x} overlaps the one before it");
+            assert_eq!(addr, next, "[{next:#x}, {addr:#x}) went missing");
+            next += size;
+        }
+        assert_eq!(next, end.as_usize(), "blocks run past the end");
+    }
+
+    #[test_case]
+    fn alloc_splits_and_free_coalesces() {
+        with_scratch(SCRATCH_ORDER, |buddy, start| {
+            unsafe { buddy.claim_range(start, start + order_size(SCRATCH_ORDER)) };
+
+            let pages: Vec<_> = (0..1 << SCRATCH_ORDER)
+                .map(|_| buddy.alloc(0).expect("claimed pages ran out early"))
+                .collect();
+            assert!(buddy.alloc(0).is_none());
+
+            let mut starts: Vec<_> = pages.iter().map(|page| page.start()).collect();
+            starts.sort();
+            starts.dedup();
+            assert_eq!(starts.len(), pages.len(), "a page was handed out twice");
+            assert_eq!(starts[0], start);
+
+            for page in pages {
+                buddy.free(page);
+            }
+            let whole = buddy
+                .alloc(SCRATCH_ORDER)
+                .expect("free pages weren't merged");
+            assert_eq!(whole.start(), start);
+            buddy.free(whole);
+        });
+    }
+
+    #[test_case]
+    fn alloc_is_naturally_aligned() {
+        with_scratch(SCRATCH_ORDER, |buddy, start| {
+            unsafe { buddy.claim_range(start, start + order_size(SCRATCH_ORDER)) };
+
+            let page = buddy.alloc(0).unwrap();
+            let block = buddy.alloc(2).unwrap();
+            assert!(block.start().as_usize().is_multiple_of(order_size(2)));
+            assert!(block.end() <= page.start() || page.end() <= block.start());
+
+            buddy.free(page);
+            buddy.free(block);
+            let whole = buddy
+                .alloc(SCRATCH_ORDER)
+                .expect("free blocks weren't merged");
+            buddy.free(whole);
+        });
+    }
+
+    #[test_case]
+    fn claim_range_skips_partial_pages() {
+        with_scratch(SCRATCH_ORDER, |buddy, start| {
+            unsafe { buddy.claim_range(start + 100, start + 3 * PAGE_SIZE + 5) };
+
+            let first = buddy.alloc(0).unwrap();
+            let second = buddy.alloc(0).unwrap();
+            assert!(buddy.alloc(0).is_none());
+            for page in [&first, &second] {
+                assert!(start + PAGE_SIZE <= page.start() && page.end() <= start + 3 * PAGE_SIZE);
+            }
+
+            buddy.free(first);
+            buddy.free(second);
+        });
+    }
+
+    #[test_case]
+    fn free_counts_follow_splits() {
+        with_scratch(SCRATCH_ORDER, |buddy, start| {
+            unsafe { buddy.claim_range(start, start + order_size(SCRATCH_ORDER)) };
+            let mut expected = [0; ORDER_COUNT];
+            expected[SCRATCH_ORDER] = 1;
+            assert_eq!(buddy.free_counts(), expected);
+
+            // Splitting down to a page leaves one free block of each lower order
+            let page = buddy.alloc(0).unwrap();
+            let expected: [usize; ORDER_COUNT] =
+                core::array::from_fn(|order| (order < SCRATCH_ORDER) as usize);
+            assert_eq!(buddy.free_counts(), expected);
+            assert_eq!(buddy.free_size(), order_size(SCRATCH_ORDER) - PAGE_SIZE);
+
+            buddy.free(page);
+            assert_eq!(buddy.free_size(), order_size(SCRATCH_ORDER));
+        });
+    }
+
+    #[test_case]
+    fn alloc_fails_above_highest_free_order() {
+        with_scratch(SCRATCH_ORDER, |buddy, start| {
+            unsafe { buddy.claim_range(start, start + order_size(SCRATCH_ORDER)) };
+            assert!(buddy.alloc(SCRATCH_ORDER + 1).is_none());
+            let whole = buddy.alloc(SCRATCH_ORDER).unwrap();
+            buddy.free(whole);
+        });
+    }
+
+    #[test_case]
+    fn random_alloc_free_keeps_invariants() {
+        for seed in SEEDS {
+            with_scratch(RANDOM_ORDER, |buddy, start| {
+                let end = start + order_size(RANDOM_ORDER);
+                unsafe { buddy.claim_range(start, end) };
+
+                let mut rng = Rng(seed);
+                let mut live = Vec::new();
+                for _ in 0..RANDOM_STEPS {
+                    // Allocate twice as often as free, so the region fills up at times
+                    if live.is_empty() || rng.below(3) != 0 {
+                        let order = rng.order(RANDOM_ORDER + 1);
+                        match buddy.alloc(order) {
+                            Some(allocation) => {
+                                assert_eq!(allocation.order(), order);
+                                live.push(allocation);
+                            }
+                            None => assert!(
+                                buddy.free_lists[order..]
+                                    .iter()
+                                    .all(|list| list.peek().is_none()),
+                                "order {order} failed with a big enough block free"
+                            ),
+                        }
+                    } else {
+                        buddy.free(live.swap_remove(rng.below(live.len())));
+                    }
+                    check_invariants(buddy, &live, start, end);
+                }
+
+                for allocation in live.drain(..) {
+                    buddy.free(allocation);
+                }
+                check_invariants(buddy, &live, start, end);
+                let whole = buddy
+                    .alloc(RANDOM_ORDER)
+                    .expect("free blocks weren't merged back");
+                buddy.free(whole);
+            });
+        }
+    }
+
+    #[test_case]
+    fn random_claim_range_covers_whole_pages() {
+        let mut rng = Rng(SEEDS[1]);
+        for _ in 0..64 {
+            with_scratch(RANDOM_ORDER, |buddy, start| {
+                let len = order_size(RANDOM_ORDER);
+                let (a, b) = (rng.below(len + 1), rng.below(len + 1));
+                let (claim_start, claim_end) = (start + a.min(b), start + a.max(b));
+                unsafe { buddy.claim_range(claim_start, claim_end) };
+
+                let first = claim_start.as_usize().next_multiple_of(PAGE_SIZE);
+                let last = claim_end.as_usize() / PAGE_SIZE * PAGE_SIZE;
+                let pages = last.saturating_sub(first) / PAGE_SIZE;
+                check_invariants(
+                    buddy,
+                    &[],
+                    PhysAddr(first),
+                    PhysAddr(first + pages * PAGE_SIZE),
+                );
+
+                let allocations: Vec<_> = core::iter::from_fn(|| buddy.alloc(0)).collect();
+                assert_eq!(allocations.len(), pages);
+                for allocation in allocations {
+                    buddy.free(allocation);
+                }
+            });
+        }
     }
 }
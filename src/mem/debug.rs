@@ -0,0 +1,245 @@
+//! Allocator debugging, enabled by the `debug-alloc` feature. Freed pages and heap chunks are
+//! filled with `POISON`, and every heap allocation is surrounded by red zones that are checked
+//! when it's freed.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr,
+};
+
+use super::heap::{GrowOnOom, HEAP_ALLOCATOR};
+use crate::backtrace;
+
+pub type Heap = talc::Talck<spin::Mutex<()>, GrowOnOom>;
+
+/// Byte written over freed memory.
+pub const POISON: u8 = 0x6b;
+/// Byte written into red zones.
+pub const RED_ZONE: u8 = 0xa5;
+pub const RED_ZONE_LEN: usize = 16;
+
+const LIVE_MAGIC: usize = 0x11fe_a110_c0de_0001;
+const FREED_MAGIC: usize = 0xdead_a110_c0de_0002;
+
+// Number of return addresses recorded per heap allocation.
+const SITE_DEPTH: usize = 4;
+
+#[global_allocator]
+static DEBUG_HEAP: DebugHeap = DebugHeap(&HEAP_ALLOCATOR);
+
+/// Precedes every heap allocation. The layout of a chunk is:
+/// `[ChunkHeader][padding][red zone][user data][red zone]`, where the header region is sized so
+/// the user data stays aligned. `magic` comes last because talc writes its free list node over
+/// the start of a chunk once it's freed.
+#[repr(C)]
+struct ChunkHeader {
+    site: [usize; SITE_DEPTH],
+    size: usize,
+    magic: usize,
+}
+
+pub struct DebugHeap(&'static Heap);
+
+/// Fills `len` bytes at `ptr` with `POISON`.
+///
+/// # Safety
+/// `ptr` must be valid for `len` bytes of writes.
+pub unsafe fn poison(ptr: *mut u8, len: usize) {
+    unsafe { ptr::write_bytes(ptr, POISON, len) };
+}
+
+fn header_len(align: usize) -> usize {
+    (size_of::<ChunkHeader>() + RED_ZONE_LEN).next_multiple_of(align)
+}
+
+fn outer_layout(layout: Layout) -> Option<Layout> {
+    let align = layout.align().max(align_of::<ChunkHeader>());
+    let size = header_len(align) + layout.size() + RED_ZONE_LEN;
+    Layout::from_size_align(size, align).ok()
+}
+
+fn first_corrupt_byte(ptr: *const u8, len: usize) -> Option<usize> {
+    (0..len).find(|&i| unsafe { *ptr.add(i) } != RED_ZONE)
+}
+
+/// What's wrong with a chunk that's being freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkError {
+    DoubleFree,
+    /// It wasn't allocated here, or its header was overwritten.
+    Unowned,
+    /// It's freed with a size other than this one, which it was allocated with.
+    WrongSize(usize),
+    /// Something wrote this many bytes before its start.
+    Underflow(usize),
+    /// Something wrote this many bytes past its end.
+    Overflow(usize),
+}
+
+/// Checks the header and red zones of the chunk at `ptr`, which is being freed with `layout`.
+///
+/// # Safety
+/// `ptr` must be preceded by as many readable bytes as a chunk header for `layout` takes, and
+/// followed by `layout.size()` bytes and a red zone.
+unsafe fn check_chunk(ptr: *const u8, layout: Layout) -> Result<(), ChunkError> {
+    let outer = outer_layout(layout).ok_or(ChunkError::Unowned)?;
+    let header = unsafe { &*ptr.sub(header_len(outer.align())).cast::<ChunkHeader>() };
+    match header.magic {
+        LIVE_MAGIC => {}
+        FREED_MAGIC => return Err(ChunkError::DoubleFree),
+        _ => return Err(ChunkError::Unowned),
+    }
+
+    if header.size != layout.size() {
+        return Err(ChunkError::WrongSize(header.size));
+    }
+    if let Some(i) = first_corrupt_byte(unsafe { ptr.sub(RED_ZONE_LEN) }, RED_ZONE_LEN) {
+        return Err(ChunkError::Underflow(RED_ZONE_LEN - i));
+    }
+    if let Some(i) = first_corrupt_byte(unsafe { ptr.add(layout.size()) }, RED_ZONE_LEN) {
+        return Err(ChunkError::Overflow(i + 1));
+    }
+    Ok(())
+}
+
+struct Site([usize; SITE_DEPTH]);
+
+impl core::fmt::Display for Site {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for ra in self.0.iter().take_while(|&&ra| ra != 0) {
+            write!(f, " {ra:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for DebugHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(outer) = outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+
+        let base = unsafe { self.0.alloc(outer) };
+        if base.is_null() {
+            return base;
+        }
+
+        unsafe {
+            let header_len = header_len(outer.align());
+            let data = base.add(header_len);
+
+            base.cast::<ChunkHeader>().write(ChunkHeader {
+                // Skip this function and the `__rust_alloc` shim.
+                site: backtrace::capture(2),
+                size: layout.size(),
+                magic: LIVE_MAGIC,
+            });
+            ptr::write_bytes(data.sub(RED_ZONE_LEN), RED_ZONE, RED_ZONE_LEN);
+            ptr::write_bytes(data.add(layout.size()), RED_ZONE, RED_ZONE_LEN);
+
+            data
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let outer = outer_layout(layout).expect("dealloc with a layout that was never allocated");
+        let header_len = header_len(outer.align());
+
+        unsafe {
+            let header = ptr.sub(header_len).cast::<ChunkHeader>();
+            let site = Site((*header).site);
+
+            match check_chunk(ptr, layout) {
+                Ok(()) => {}
+                Err(ChunkError::DoubleFree) => {
+                    panic!("heap: double free of {ptr:p}, allocated at{site}")
+                }
+                Err(ChunkError::Unowned) => {
+                    panic!("heap: free of unowned or corrupted chunk {ptr:p}")
+                }
+                Err(ChunkError::WrongSize(size)) => panic!(
+                    "heap: {ptr:p} freed with size {} but allocated with size {size}, allocated at{site}",
+                    layout.size(),
+                ),
+                Err(ChunkError::Underflow(len)) => {
+                    panic!("heap: underflow of {ptr:p} ({len} bytes before it), allocated at{site}")
+                }
+                Err(ChunkError::Overflow(len)) => {
+                    panic!(
+                        "heap: overflow of {ptr:p} ({len} bytes past its end), allocated at{site}"
+                    )
+                }
+            }
+
+            poison(ptr.sub(RED_ZONE_LEN), layout.size() + 2 * RED_ZONE_LEN);
+            (*header).magic = FREED_MAGIC;
+
+            self.0.dealloc(header.cast(), outer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::slice;
+
+    use super::*;
+
+    const LAYOUT: Layout = Layout::new::<[u64; 5]>();
+
+    fn alloc() -> *mut u8 {
+        let ptr = unsafe { DEBUG_HEAP.alloc(LAYOUT) };
+        assert!(!ptr.is_null());
+        ptr
+    }
+
+    #[test_case]
+    fn red_zones_catch_writes_past_either_end() {
+        let ptr = alloc();
+        unsafe {
+            assert_eq!(check_chunk(ptr, LAYOUT), Ok(()));
+
+            ptr.add(LAYOUT.size() + 2).write(0);
+            assert_eq!(check_chunk(ptr, LAYOUT), Err(ChunkError::Overflow(3)));
+            ptr.add(LAYOUT.size() + 2).write(RED_ZONE);
+
+            ptr.sub(1).write(0);
+            assert_eq!(check_chunk(ptr, LAYOUT), Err(ChunkError::Underflow(1)));
+            ptr.sub(1).write(RED_ZONE);
+
+            let larger = Layout::new::<[u64; 6]>();
+            assert_eq!(
+                check_chunk(ptr, larger),
+                Err(ChunkError::WrongSize(LAYOUT.size()))
+            );
+
+            DEBUG_HEAP.dealloc(ptr, LAYOUT);
+        }
+    }
+
+    #[test_case]
+    fn freed_chunks_are_poisoned_and_cant_be_freed_again() {
+        let ptr = alloc();
+        unsafe {
+            ptr.write_bytes(0, LAYOUT.size());
+            DEBUG_HEAP.dealloc(ptr, LAYOUT);
+
+            // Nothing was allocated since, so the chunk is as `dealloc` left it.
+            let data = slice::from_raw_parts(ptr, LAYOUT.size());
+            assert!(data.iter().all(|&byte| byte == POISON));
+            assert_eq!(check_chunk(ptr, LAYOUT), Err(ChunkError::DoubleFree));
+        }
+    }
+
+    #[test_case]
+    fn chunks_from_elsewhere_are_unowned() {
+        let buf = [0u64; 32];
+        let ptr = buf.as_ptr().cast::<u8>();
+        let data = unsafe { ptr.add(size_of_val(&buf) / 2) };
+        assert_eq!(
+            unsafe { check_chunk(data, LAYOUT) },
+            Err(ChunkError::Unowned)
+        );
+    }
+}
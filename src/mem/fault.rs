@@ -0,0 +1,206 @@
+//! Page fault handling. Pages of an area are mapped lazily: the first access to one faults, and
+//! the handler looks up the area in the current address space and maps a page for it. Stores to
+//! copy-on-write pages shared by `AddressSpace::fork`, and accesses to pages that were swapped
+//! out, are also resolved here.
+
+use super::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr},
+    alloc::{Allocation, PAGE_ALLOCATOR},
+    frame::{self, FrameFlags},
+    paging::{
+        PageType,
+        entry::{Entry, EntryFlags},
+        flush,
+        mapper::{MapError, Mapper},
+        space,
+    },
+    swap,
+    vma::{Backing, Vma, VmaFlags},
+};
+
+/// The kind of access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address isn't inside any area, or the area doesn't allow the access.
+    AccessViolation,
+    /// There's no memory left for the page or its tables.
+    OutOfMemory,
+    /// The page couldn't be read back from swap.
+    Io,
+    /// The page table rejected the page, which the area's checks should have ruled out.
+    Unmappable(MapError),
+}
+
+impl From<MapError> for FaultError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfMemory => Self::OutOfMemory,
+            MapError::Misaligned | MapError::AlreadyMapped => Self::Unmappable(err),
+        }
+    }
+}
+
+impl Access {
+    fn allowed_by(self, flags: VmaFlags) -> bool {
+        match self {
+            Self::Read => flags.contains(VmaFlags::READ),
+            Self::Write => flags.contains(VmaFlags::WRITE),
+            Self::Execute => flags.contains(VmaFlags::EXECUTE),
+        }
+    }
+}
+
+/// Handles a page fault at `addr` in the current address space.
+pub fn handle(addr: VirtAddr, access: Access) -> Result<(), FaultError> {
+    let space = space::current().ok_or(FaultError::AccessViolation)?;
+    space.handle_fault(addr, access)
+}
+
+/// Maps the page containing `addr` for `vma`, or fixes up the accessed and dirty bits if it's
+/// already mapped, for harts that leave that to software.
+pub(super) fn resolve(
+    mapper: &mut Mapper,
+    vma: &Vma,
+    addr: VirtAddr,
+    access: Access,
+) -> Result<(), FaultError> {
+    if !access.allowed_by(vma.flags()) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    let page = VirtAddr::new(addr.as_usize() / PAGE_SIZE * PAGE_SIZE);
+    if let Some((entry, _)) = mapper.leaf_mut(page)
+        && entry.valid()
+    {
+        if access == Access::Write && entry.flags().contains(EntryFlags::COW) {
+            break_cow(entry, vma)?;
+        }
+
+        let mut flags = entry.flags() | EntryFlags::ACCESSED;
+        if access == Access::Write {
+            flags |= EntryFlags::DIRTY;
+        }
+        *entry = entry.with_flags(flags);
+        flush(page);
+        return Ok(());
+    }
+
+    if let Some((entry, _)) = mapper.leaf_mut(page)
+        && let Some(slot) = entry.swap_slot()
+    {
+        return swap_in(entry, slot, vma, page);
+    }
+
+    let offset = page - vma.start();
+    let phys = match vma.backing() {
+        Backing::Anonymous => alloc_page(vma, |_| {})?,
+        Backing::File {
+            source,
+            offset: base,
+        } => alloc_page(vma, |bytes| source.read_page(base + offset, bytes))?,
+        Backing::Device { phys } => *phys + offset,
+    };
+
+    if let Err(err) = mapper.map_page(page, phys, PageType::Base, vma.entry_flags()) {
+        if !matches!(vma.backing(), Backing::Device { .. }) {
+            release_page(phys);
+        }
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// Reads a swapped out page back in and maps it in place of its swap entry.
+fn swap_in(entry: &mut Entry, slot: usize, vma: &Vma, page: VirtAddr) -> Result<(), FaultError> {
+    let phys = alloc_page(vma, |_| {})?;
+    let bytes = unsafe { &mut *VirtAddr::from_phys(phys).as_ptr::<[u8; PAGE_SIZE]>() };
+    if swap::read_page(slot, bytes).is_err() {
+        release_page(phys);
+        return Err(FaultError::Io);
+    }
+
+    // Dirty, because the page no longer matches the zero page it started out as (see
+    // `swap::age`).
+    let flags = vma.entry_flags() | EntryFlags::VALID | EntryFlags::ACCESSED | EntryFlags::DIRTY;
+    *entry = Entry::new().with_ppn(phys.ppn()).with_flags(flags);
+    swap::free_slot(slot);
+    flush(page);
+    Ok(())
+}
+
+/// Gives the address space its own writable copy of a copy-on-write page. If nothing else maps
+/// the page anymore, it's simply made writable again.
+fn break_cow(entry: &mut Entry, vma: &Vma) -> Result<(), FaultError> {
+    let old = entry.phys_addr();
+    let flags = (entry.flags() - EntryFlags::COW) | EntryFlags::WRITE;
+
+    let shared = frame::frame(old).is_some_and(|frame| frame.refcount() > 1);
+    if shared {
+        let new = alloc_page(vma, |bytes| {
+            let old_bytes = unsafe { &*VirtAddr::from_phys(old).as_ptr::<[u8; PAGE_SIZE]>() };
+            bytes.copy_from_slice(old_bytes);
+        })?;
+        *entry = entry.with_ppn(new.ppn()).with_flags(flags);
+        release_page(old);
+    } else {
+        *entry = entry.with_flags(flags);
+    }
+
+    Ok(())
+}
+
+/// Allocates a zeroed page for `vma` and lets `fill` initialize it.
+fn alloc_page(vma: &Vma, fill: impl FnOnce(&mut [u8; PAGE_SIZE])) -> Result<PhysAddr, FaultError> {
+    let allocation = PAGE_ALLOCATOR
+        .lock()
+        .alloc(0)
+        .ok_or(FaultError::OutOfMemory)?;
+    let (phys, _) = allocation.into_raw();
+
+    let bytes = unsafe { &mut *VirtAddr::from_phys(phys).as_ptr::<[u8; PAGE_SIZE]>() };
+    bytes.fill(0);
+    fill(bytes);
+
+    if let Some(frame) = frame::frame(phys) {
+        frame.set_flags(if vma.flags().contains(VmaFlags::USER) {
+            FrameFlags::USER
+        } else {
+            FrameFlags::KERNEL
+        });
+        frame.get();
+        frame.inc_map_count();
+    }
+
+    Ok(phys)
+}
+
+/// Adds a mapping of a page allocated by `alloc_page`, sharing it with its current owners.
+pub(super) fn share_page(phys: PhysAddr) {
+    if let Some(frame) = frame::frame(phys) {
+        frame.get();
+        frame.inc_map_count();
+    }
+}
+
+/// Drops one mapping of a page allocated by `alloc_page`, freeing it once nothing maps it.
+pub(super) fn release_page(phys: PhysAddr) {
+    let Some(frame) = frame::frame(phys) else {
+        return;
+    };
+
+    frame.dec_map_count();
+    if frame.put() {
+        PAGE_ALLOCATOR
+            .lock()
+            .free(unsafe { Allocation::from_raw(phys, 0) });
+    }
+}
@@ -0,0 +1,192 @@
+//! The frame table keeps one `Frame` per physical page of RAM, recording who owns the page and
+//! how many references to it exist. Pages sitting in `PAGE_ALLOCATOR` always have a refcount of
+//! zero; whoever takes a page out of it is responsible for `get`ting and `put`ting references.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+};
+
+use spin::Once;
+
+use super::{PAGE_SIZE, addr::PhysAddr};
+
+pub static FRAME_TABLE: Once<FrameTable> = Once::new();
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FrameFlags: u16 {
+        /// Owned by the kernel (image, heap, stacks, kernel data structures).
+        const KERNEL     = 0b00001;
+        /// Mapped into a user address space.
+        const USER       = 0b00010;
+        /// Holds a page table.
+        const PAGE_TABLE = 0b00100;
+        /// Backs a slab or heap span.
+        const SLAB       = 0b01000;
+        /// Never handed to the page allocator (firmware, kernel image, DTB, ...).
+        const RESERVED   = 0b10000;
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    refcount: AtomicU32,
+    map_count: AtomicU16,
+    flags: AtomicU16,
+}
+
+pub struct FrameTable {
+    base: PhysAddr,
+    frames: &'static [Frame],
+}
+
+impl Frame {
+    pub const fn new() -> Self {
+        Self {
+            refcount: AtomicU32::new(0),
+            map_count: AtomicU16::new(0),
+            flags: AtomicU16::new(0),
+        }
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Takes a reference to the page, returning the new refcount.
+    pub fn get(&self) -> u32 {
+        let old = self.refcount.fetch_add(1, Ordering::AcqRel);
+        assert!(old != u32::MAX, "frame refcount overflow");
+        old + 1
+    }
+
+    /// Drops a reference to the page, returning true if it was the last one. The caller is then
+    /// responsible for giving the page back to the page allocator.
+    pub fn put(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old != 0, "frame refcount underflow");
+        old == 1
+    }
+
+    /// Number of page table entries that map the page.
+    pub fn map_count(&self) -> u16 {
+        self.map_count.load(Ordering::Acquire)
+    }
+
+    pub fn inc_map_count(&self) {
+        self.map_count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn dec_map_count(&self) {
+        let old = self.map_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(old != 0, "frame map count underflow");
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_retain(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn set_flags(&self, flags: FrameFlags) {
+        self.flags.store(flags.bits(), Ordering::Release);
+    }
+
+    /// Resets the frame to the state of a page sitting in the page allocator.
+    pub fn reset(&self) {
+        self.map_count.store(0, Ordering::Release);
+        self.flags.store(0, Ordering::Release);
+    }
+}
+
+impl FrameTable {
+    /// # Safety
+    /// `frames` must hold one initialized `Frame` per page of RAM starting at `base`.
+    pub const unsafe fn new(base: PhysAddr, frames: &'static [Frame]) -> Self {
+        Self { base, frames }
+    }
+
+    /// Physical address range covered by the table.
+    pub fn range(&self) -> Range<PhysAddr> {
+        self.base..self.base + self.frames.len() * PAGE_SIZE
+    }
+
+    pub fn frame(&self, addr: PhysAddr) -> Option<&Frame> {
+        let index = addr.as_usize().checked_sub(self.base.as_usize())? / PAGE_SIZE;
+        self.frames.get(index)
+    }
+
+    /// All frames whose pages intersect `[start, end)`. Pages outside the table are skipped.
+    pub fn frames(&self, start: PhysAddr, end: PhysAddr) -> impl Iterator<Item = &Frame> {
+        let first = start.as_usize().saturating_sub(self.base.as_usize()) / PAGE_SIZE;
+        let last = end
+            .as_usize()
+            .saturating_sub(self.base.as_usize())
+            .div_ceil(PAGE_SIZE)
+            .min(self.frames.len());
+
+        self.frames[first.min(last)..last].iter()
+    }
+}
+
+/// Looks up the frame for `addr`, returning `None` before the frame table is set up or if
+/// `addr` isn't RAM.
+pub fn frame(addr: PhysAddr) -> Option<&'static Frame> {
+    FRAME_TABLE.get()?.frame(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const BASE: PhysAddr = PhysAddr(0x8000_0000);
+
+    fn table(pages: usize) -> FrameTable {
+        let frames = (0..pages).map(|_| Frame::new()).collect::<Vec<_>>().leak();
+        unsafe { FrameTable::new(BASE, frames) }
+    }
+
+    #[test_case]
+    fn the_last_put_is_reported() {
+        let frame = Frame::new();
+        assert_eq!(frame.get(), 1);
+        assert_eq!(frame.get(), 2);
+        assert!(!frame.put());
+        assert_eq!(frame.refcount(), 1);
+        assert!(frame.put());
+        assert_eq!(frame.refcount(), 0);
+    }
+
+    #[test_case]
+    fn reset_clears_mappings_and_flags() {
+        let frame = Frame::new();
+        frame.set_flags(FrameFlags::USER);
+        frame.inc_map_count();
+        frame.inc_map_count();
+        frame.dec_map_count();
+        assert_eq!(frame.map_count(), 1);
+
+        frame.reset();
+        assert_eq!(frame.map_count(), 0);
+        assert_eq!(frame.flags(), FrameFlags::empty());
+    }
+
+    #[test_case]
+    fn frames_are_looked_up_by_address() {
+        let table = table(4);
+        assert_eq!(table.range(), BASE..BASE + 4 * PAGE_SIZE);
+
+        table.frame(BASE + PAGE_SIZE + 8).unwrap().get();
+        assert_eq!(table.frame(BASE + PAGE_SIZE).unwrap().refcount(), 1);
+        assert_eq!(table.frame(BASE).unwrap().refcount(), 0);
+        assert!(table.frame(BASE - PAGE_SIZE).is_none());
+        assert!(table.frame(BASE + 4 * PAGE_SIZE).is_none());
+
+        let count = |start: usize, end: usize| table.frames(BASE + start, BASE + end).count();
+        assert_eq!(count(8, 2 * PAGE_SIZE + 1), 3);
+        assert_eq!(count(8 * PAGE_SIZE, 9 * PAGE_SIZE), 0);
+        let all = table.frames(BASE - 8 * PAGE_SIZE, BASE + 64 * PAGE_SIZE);
+        assert_eq!(all.count(), 4);
+    }
+}
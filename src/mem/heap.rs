@@ -0,0 +1,190 @@
+//! The kernel heap. It starts out empty and grows on demand by pulling blocks out of
+//! `PAGE_ALLOCATOR` whenever talc runs out of memory.
+
+use core::alloc::Layout;
+
+use log::{debug, warn};
+use spin::Mutex;
+use talc::{OomHandler, Span, Talc, Talck};
+
+use super::{
+    PAGE_SIZE,
+    addr::VirtAddr,
+    alloc::{Allocation, HIGHEST_ORDER, PAGE_ALLOCATOR},
+    frame::{FRAME_TABLE, FrameFlags},
+};
+use crate::cmdline::Param;
+
+// With `debug-alloc`, `mem::debug::DebugHeap` wraps this instead.
+#[cfg_attr(not(feature = "debug-alloc"), global_allocator)]
+pub static HEAP_ALLOCATOR: Talck<Mutex<()>, GrowOnOom> = Talck::new(Talc::new(GrowOnOom::new()));
+
+/// Smallest block requested from the page allocator when the heap grows (32 KiB).
+pub const MIN_GROWTH_ORDER: usize = 3;
+pub const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// The most the heap grows to, applied by `mem::init`.
+pub static HEAP_MAX: Param<usize> = Param::new("heap_max", DEFAULT_MAX_SIZE);
+
+// Room for talc's tags and, on the first claim, its bin array (~1 KiB).
+const CLAIM_OVERHEAD: usize = 2048;
+
+#[derive(Debug, Clone, Copy)]
+pub enum HeapEvent {
+    /// A block that isn't adjacent to the current heap was claimed as a new span.
+    Claimed { span: Span, size: usize },
+    /// The current heap span was extended over an adjacent block.
+    Extended { span: Span, size: usize },
+    /// The heap couldn't grow to satisfy `layout`.
+    Failed { layout: Layout, size: usize },
+}
+
+pub struct GrowOnOom {
+    // Most recently claimed or extended span; older spans stay claimed but are never extended.
+    heap: Span,
+    size: usize,
+    max_size: usize,
+    hook: fn(&HeapEvent),
+}
+
+// Span holds raw pointers, but it's only ever touched under the heap lock.
+unsafe impl Send for GrowOnOom {}
+
+fn log_event(event: &HeapEvent) {
+    match event {
+        HeapEvent::Claimed { span, size } => {
+            debug!("heap: claimed {span:?}, heap size is now {size:#x}");
+        }
+        HeapEvent::Extended { span, size } => {
+            debug!("heap: extended to {span:?}, heap size is now {size:#x}");
+        }
+        HeapEvent::Failed { layout, size } => {
+            warn!("heap: failed to grow for {layout:?}, heap size is {size:#x}");
+        }
+    }
+}
+
+impl GrowOnOom {
+    pub const fn new() -> Self {
+        Self {
+            heap: Span::empty(),
+            size: 0,
+            max_size: DEFAULT_MAX_SIZE,
+            hook: log_event,
+        }
+    }
+
+    /// Total number of bytes taken from the page allocator so far.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    pub const fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Caps the heap at `max_size` bytes. Memory that was already claimed is kept.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Replaces the function that's called on every growth or growth failure.
+    // Nothing needs more than the default, which logs the event
+    #[allow(dead_code)]
+    pub fn set_hook(&mut self, hook: fn(&HeapEvent)) {
+        self.hook = hook;
+    }
+
+    fn growth_order(layout: Layout) -> usize {
+        let required = layout.size() + layout.align() + CLAIM_OVERHEAD;
+        let pages = required.div_ceil(PAGE_SIZE);
+        (pages.next_power_of_two().ilog2() as usize).max(MIN_GROWTH_ORDER)
+    }
+
+    fn alloc_block(&self, order: usize) -> Option<Allocation> {
+        let size = PAGE_SIZE << order;
+        if order > HIGHEST_ORDER || self.size + size > self.max_size {
+            return None;
+        }
+
+        PAGE_ALLOCATOR.lock().alloc(order)
+    }
+}
+
+impl OomHandler for GrowOnOom {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let handler = &talc.oom_handler;
+        let order = Self::growth_order(layout);
+
+        let Some(allocation) = handler.alloc_block(order) else {
+            (handler.hook)(&HeapEvent::Failed {
+                layout,
+                size: handler.size,
+            });
+            return Err(());
+        };
+
+        let block_size = allocation.size();
+        if let Some(frame_table) = FRAME_TABLE.get() {
+            for frame in frame_table.frames(allocation.start(), allocation.end()) {
+                frame.set_flags(FrameFlags::KERNEL | FrameFlags::SLAB);
+            }
+        }
+
+        let base = VirtAddr::from_phys(allocation.start()).as_ptr::<u8>();
+        let acme = VirtAddr::from_phys(allocation.end()).as_ptr::<u8>();
+
+        // Extend the current span if the new block sits directly above or below it, which is
+        // common since the buddy allocator hands out neighbouring blocks.
+        let extended = talc
+            .oom_handler
+            .heap
+            .get_base_acme()
+            .filter(|&(heap_base, heap_acme)| heap_acme == base || acme == heap_base)
+            .map(|(heap_base, heap_acme)| {
+                let req_heap = Span::new(heap_base.min(base), heap_acme.max(acme));
+                unsafe { talc.extend(talc.oom_handler.heap, req_heap) }
+            });
+
+        let event = if let Some(span) = extended {
+            talc.oom_handler.heap = span;
+            talc.oom_handler.size += block_size;
+            HeapEvent::Extended {
+                span,
+                size: talc.oom_handler.size,
+            }
+        } else {
+            let span = unsafe { talc.claim(Span::new(base, acme)) }.map_err(|_| {
+                PAGE_ALLOCATOR.lock().free(allocation);
+            })?;
+
+            talc.oom_handler.heap = span;
+            talc.oom_handler.size += block_size;
+            HeapEvent::Claimed {
+                span,
+                size: talc.oom_handler.size,
+            }
+        };
+
+        (talc.oom_handler.hook)(&event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn vec_push_and_collect() {
+        let mut pushed = Vec::new();
+        for i in 0..10_000 {
+            pushed.push(i);
+        }
+        // A megabyte, far more than the smallest growth step
+        let collected: Vec<usize> = (0..(1 << 20) / size_of::<usize>()).collect();
+
+        assert!(pushed.iter().enumerate().all(|(i, &value)| i == value));
+        assert!(collected.iter().enumerate().all(|(i, &value)| i == value));
+    }
+}
@@ -2,4 +2,251 @@ pub const PAGE_SIZE: usize = 4096;
 
 pub mod addr;
 pub mod alloc;
+#[cfg(feature = "debug-alloc")]
+pub mod debug;
+pub mod fault;
+pub mod frame;
+pub mod heap;
 pub mod paging;
+pub mod swap;
+pub mod vma;
+pub mod vmalloc;
+
+use core::{mem::size_of, ops::Range, slice};
+
+use ::alloc::vec::Vec;
+use fdt::Fdt;
+use log::{debug, info, warn};
+
+use self::{
+    addr::{PhysAddr, VirtAddr, direct_map, kernel_mem},
+    alloc::{HIGHEST_ORDER, PAGE_ALLOCATOR},
+    frame::{FRAME_TABLE, Frame, FrameFlags, FrameTable},
+    heap::HEAP_ALLOCATOR,
+    paging::{entry::EntryFlags, space::with_kernel_mapper},
+};
+use crate::{
+    boot::{kernel_phys_range, pheap_phys_range},
+    fs::initramfs::initrd_range,
+    pstore,
+};
+
+/// A range of RAM that must never be handed to the page allocator.
+#[derive(Clone)]
+struct Reservation {
+    range: Range<usize>,
+    flags: FrameFlags,
+}
+
+/// Removes `hole` from every range in `ranges`.
+fn subtract(ranges: &mut Vec<Range<usize>>, hole: &Range<usize>) {
+    *ranges = ranges
+        .iter()
+        .flat_map(|range| {
+            [
+                range.start..range.end.min(hole.start),
+                range.start.max(hole.end)..range.end,
+            ]
+        })
+        .filter(|range| !range.is_empty())
+        .collect();
+}
+
+// The frame table is a single block from the page allocator, which bounds the RAM it covers.
+const MAX_RAM: usize = (PAGE_SIZE << HIGHEST_ORDER) / size_of::<Frame>() * PAGE_SIZE;
+
+const DIRECT_MAP_FLAGS: EntryFlags = EntryFlags::READ
+    .union(EntryFlags::WRITE)
+    .union(EntryFlags::GLOBAL)
+    .union(EntryFlags::ACCESSED)
+    .union(EntryFlags::DIRTY);
+
+// Only RAM the direct map has room for, and that the frame table can cover, is used.
+fn ram_ranges(fdt: &Fdt) -> Vec<Range<usize>> {
+    let usable = direct_map();
+    let usable = usable.start..usable.end.min(usable.start + MAX_RAM);
+    fdt.memory()
+        .regions()
+        .filter_map(|region| {
+            let start = region.starting_address as usize;
+            let end = start + region.size?;
+            let range = start.max(usable.start)..end.min(usable.end);
+            (!range.is_empty()).then_some(range)
+        })
+        .collect()
+}
+
+/// Maps the RAM in `ram` past `kernel_mem` at `DIRECT_MAP_START`, with the largest pages that
+/// fit. Whatever can't be mapped is dropped from `ram`.
+fn map_direct(ram: &mut Vec<Range<usize>>) {
+    let mut outside = ram.clone();
+    subtract(&mut outside, &kernel_mem());
+
+    for range in outside {
+        let start = PhysAddr(range.start.next_multiple_of(PAGE_SIZE));
+        let end = PhysAddr(range.end / PAGE_SIZE * PAGE_SIZE);
+        let mapped = start < end
+            && with_kernel_mapper(|mapper| {
+                let virt = VirtAddr::from_phys(start);
+                mapper.map_range(virt, start, end - start, DIRECT_MAP_FLAGS)
+            })
+            .is_ok();
+
+        if !mapped {
+            warn!("mem: can't map [{:#x}, {:#x})", range.start, range.end);
+            subtract(ram, &range);
+        }
+    }
+}
+
+fn reservations(fdt: &Fdt, dtb_addr: PhysAddr) -> Vec<Reservation> {
+    let kernel = kernel_phys_range();
+    let mut reservations = ::alloc::vec![
+        Reservation {
+            range: kernel.start.as_usize()..kernel.end.as_usize(),
+            flags: FrameFlags::KERNEL | FrameFlags::RESERVED,
+        },
+        Reservation {
+            range: dtb_addr.as_usize()..dtb_addr.as_usize() + fdt.total_size(),
+            flags: FrameFlags::RESERVED,
+        },
+    ];
+
+    for reservation in fdt.memory_reservations() {
+        let start = reservation.address() as usize;
+        reservations.push(Reservation {
+            range: start..start + reservation.size(),
+            flags: FrameFlags::RESERVED,
+        });
+    }
+
+    // The initramfs is used in place, so it stays reserved.
+    if let Some(initrd) = initrd_range(fdt) {
+        reservations.push(Reservation {
+            range: initrd,
+            flags: FrameFlags::RESERVED,
+        });
+    }
+
+    // Panic records are kept here across reboots.
+    if let Some(region) = pstore::region(fdt) {
+        reservations.push(Reservation {
+            range: region,
+            flags: FrameFlags::RESERVED,
+        });
+    }
+
+    // Firmware (OpenSBI) describes the memory it lives in here.
+    let reserved_memory = fdt.find_node("/reserved-memory").into_iter();
+    for region in reserved_memory
+        .flat_map(|node| node.children())
+        .filter_map(|node| node.reg())
+    {
+        for region in region {
+            let start = region.starting_address as usize;
+            reservations.push(Reservation {
+                range: start..start + region.size.unwrap_or(0),
+                flags: FrameFlags::RESERVED,
+            });
+        }
+    }
+
+    // Round outward to whole pages
+    for reservation in &mut reservations {
+        reservation.range.start = reservation.range.start / PAGE_SIZE * PAGE_SIZE;
+        reservation.range.end = reservation.range.end.next_multiple_of(PAGE_SIZE);
+    }
+
+    reservations
+}
+
+/// Picks the paging mode, maps RAM past the kernel's GiB, hands all usable RAM described by the
+/// device tree to `PAGE_ALLOCATOR`, then sets up the frame table.
+///
+/// # Safety
+/// Must only be called once, on the boot page table, with the device tree the kernel was booted
+/// with.
+pub unsafe fn init(fdt: &Fdt, dtb_addr: PhysAddr) {
+    HEAP_ALLOCATOR
+        .lock()
+        .oom_handler
+        .set_max_size(heap::HEAP_MAX.get());
+
+    // The pheap is claimed first so that the heap can grow while the memory map is parsed.
+    let pheap = pheap_phys_range();
+    unsafe {
+        PAGE_ALLOCATOR.lock().claim_range(pheap.start, pheap.end);
+    }
+
+    // The page tables for the mode and the direct map come out of the pheap.
+    unsafe { paging::space::init() };
+    let mut ram = ram_ranges(fdt);
+    map_direct(&mut ram);
+
+    let reservations = reservations(fdt, dtb_addr);
+
+    let mut free = ram.clone();
+    for reservation in &reservations {
+        subtract(&mut free, &reservation.range);
+    }
+    subtract(&mut free, &(pheap.start.as_usize()..pheap.end.as_usize()));
+
+    {
+        let mut page_alloc = PAGE_ALLOCATOR.lock();
+        for range in &free {
+            debug!("mem: claiming [{:#x}, {:#x})", range.start, range.end);
+            unsafe { page_alloc.claim_range(PhysAddr(range.start), PhysAddr(range.end)) };
+        }
+    }
+
+    let ram_start = ram.iter().map(|range| range.start).min().expect("no RAM");
+    let ram_end = ram.iter().map(|range| range.end).max().expect("no RAM");
+    let frame_count = (ram_end - ram_start).div_ceil(PAGE_SIZE);
+
+    let table_pages = (frame_count * size_of::<Frame>()).div_ceil(PAGE_SIZE);
+    let table_order = table_pages.next_power_of_two().ilog2() as usize;
+    let table_allocation = PAGE_ALLOCATOR
+        .lock()
+        .alloc(table_order)
+        .expect("not enough memory for the frame table");
+
+    let frames = unsafe {
+        let ptr = VirtAddr::from_phys(table_allocation.start()).as_ptr::<Frame>();
+        for i in 0..frame_count {
+            ptr.add(i).write(Frame::new());
+        }
+        slice::from_raw_parts(ptr, frame_count)
+    };
+
+    let frame_table =
+        FRAME_TABLE.call_once(|| unsafe { FrameTable::new(PhysAddr(ram_start), frames) });
+
+    // The frame table is never freed.
+    for frame in frame_table.frames(table_allocation.start(), table_allocation.end()) {
+        frame.set_flags(FrameFlags::KERNEL);
+        frame.get();
+    }
+
+    // Holes between memory regions aren't RAM, so they're treated as reserved
+    let mut holes = ::alloc::vec![ram_start..ram_end];
+    for range in &ram {
+        subtract(&mut holes, range);
+    }
+    let holes = holes.into_iter().map(|range| Reservation {
+        range,
+        flags: FrameFlags::RESERVED,
+    });
+
+    for reservation in reservations.iter().cloned().chain(holes) {
+        let start = PhysAddr(reservation.range.start);
+        let end = PhysAddr(reservation.range.end);
+        for frame in frame_table.frames(start, end) {
+            frame.set_flags(reservation.flags);
+        }
+    }
+
+    info!(
+        "mem: {} frames for [{:#x}, {:#x})",
+        frame_count, ram_start, ram_end
+    );
+}
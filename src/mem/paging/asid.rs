@@ -0,0 +1,158 @@
+//! ASID allocation. Each address space remembers the ASID it was last given together with the
+//! generation it was given in. When the ASIDs of a generation run out, a new generation starts
+//! and every address space gets a fresh ASID the next time it's activated. An ASID is handed out
+//! again after a rollover, or within a generation once its address space releases it. Either
+//! way it's flushed when it's handed out, which keeps stale translations from leaking between
+//! address spaces.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::asm::{read_csr, write_csr};
+
+pub static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+/// satp has room for 16 ASID bits, although hardware may implement fewer.
+pub const MAX_ASID_BITS: usize = 16;
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = (1 << MAX_ASID_BITS) - 1;
+
+const BITMAP_LEN: usize = (1 << MAX_ASID_BITS) / usize::BITS as usize;
+
+pub struct AsidAllocator {
+    bits: usize,
+    // Generations start at 1, so that a context of 0 is never current.
+    generation: usize,
+    // ASIDs handed out in the current generation. ASID 0 belongs to the kernel and is never
+    // handed out.
+    used: [usize; BITMAP_LEN],
+    next: usize,
+}
+
+/// An address space's last ASID and the generation it's from. Zero means no ASID yet.
+#[derive(Debug, Default)]
+pub struct AsidContext(AtomicUsize);
+
+/// Flushes every non-global TLB entry tagged with `asid`.
+pub fn flush_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {}", in(reg) asid) };
+}
+
+impl AsidContext {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    fn get(&self) -> (usize, usize) {
+        let context = self.0.load(Ordering::Acquire);
+        (context >> MAX_ASID_BITS, context & SATP_ASID_MASK)
+    }
+
+    fn set(&self, generation: usize, asid: usize) {
+        self.0
+            .store(generation << MAX_ASID_BITS | asid, Ordering::Release);
+    }
+}
+
+impl AsidAllocator {
+    pub const fn new() -> Self {
+        Self {
+            bits: 0,
+            generation: 1,
+            used: [0; BITMAP_LEN],
+            next: 1,
+        }
+    }
+
+    /// Finds out how many ASID bits the hardware implements by writing all ones to satp's
+    /// ASID field and reading back which bits stuck.
+    ///
+    /// # Safety
+    /// Must run with the kernel's page table loaded, before any address space is activated.
+    pub unsafe fn probe(&mut self) {
+        unsafe {
+            let satp = read_csr!("satp");
+            write_csr!("satp", satp | SATP_ASID_MASK << SATP_ASID_SHIFT);
+            let probed = read_csr!("satp");
+            write_csr!("satp", satp);
+
+            self.bits = ((probed >> SATP_ASID_SHIFT) & SATP_ASID_MASK).count_ones() as usize;
+        }
+
+        // The kernel's mappings are global, but anything cached under ASID 0 while probing
+        // must go.
+        flush_asid(0);
+    }
+
+    pub const fn bits(&self) -> usize {
+        self.bits
+    }
+
+    fn count(&self) -> usize {
+        1 << self.bits
+    }
+
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / usize::BITS as usize] & 1 << (asid % usize::BITS as usize) != 0
+    }
+
+    fn set_used(&mut self, asid: usize, used: bool) {
+        let word = &mut self.used[asid / usize::BITS as usize];
+        let bit = 1 << (asid % usize::BITS as usize);
+        if used {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; BITMAP_LEN];
+        self.next = 1;
+    }
+
+    /// Returns the ASID to activate `context` with, handing out a new one (and flushing it) if
+    /// its old one is from a previous generation. Returns 0 without touching the TLB if the
+    /// hardware has no ASIDs, in which case the caller has to flush everything itself.
+    pub fn assign(&mut self, context: &AsidContext) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+
+        let (generation, asid) = context.get();
+        if generation == self.generation {
+            return asid;
+        }
+
+        let asid = match (self.next..self.count()).find(|&asid| !self.is_used(asid)) {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                1
+            }
+        };
+
+        self.set_used(asid, true);
+        self.next = asid + 1;
+        context.set(self.generation, asid);
+
+        // Whoever had this ASID before may still have translations cached under it.
+        flush_asid(asid);
+        asid
+    }
+
+    /// Gives the context's ASID back if it's from the current generation.
+    pub fn release(&mut self, context: &AsidContext) {
+        let (generation, asid) = context.get();
+        if generation == self.generation && asid != 0 {
+            self.set_used(asid, false);
+            self.next = self.next.min(asid);
+        }
+        context.set(0, 0);
+    }
+}
@@ -1,8 +1,11 @@
+use crate::mem::addr::PhysAddr;
+
 pub const FLAG_BITS: usize = 0b1111111111;
-pub const PPN_BITS: usize = !FLAG_BITS;
+// Bits 10..54; the top 10 bits are reserved or used by extensions (Svpbmt, Svnapot).
+pub const PPN_BITS: usize = 0xfffffffffff << 10;
 
 bitflags::bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct EntryFlags: usize {
         const VALID     = 0b0000000001;
         const READ      = 0b0000000010;
@@ -17,7 +20,19 @@ bitflags::bitflags! {
     }
 }
 
-#[derive(Clone, Copy)]
+impl EntryFlags {
+    /// Marks a page shared copy-on-write. It's mapped read-only, and the first store to it gets
+    /// a private copy (see `fault::resolve`).
+    pub const COW: Self = Self::SOFTWARE0;
+    /// On a valid entry: the page wasn't accessed during the last reclaim scan (see
+    /// `swap::age`).
+    pub const OLD: Self = Self::SOFTWARE1;
+    /// On an invalid entry: the page is out in swap, in the slot stored in the PPN field. It's
+    /// the same bit as `OLD`, so check that the entry is invalid before reading it.
+    pub const SWAPPED: Self = Self::SOFTWARE1;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct Entry(usize);
 
@@ -26,12 +41,30 @@ impl Entry {
         Self(0)
     }
 
+    /// An invalid entry recording that the page is in swap slot `slot`.
+    pub const fn swapped(slot: usize) -> Self {
+        Self::new().with_ppn(slot).with_flags(EntryFlags::SWAPPED)
+    }
+
+    /// The swap slot holding the page, if this is a swap entry.
+    pub const fn swap_slot(&self) -> Option<usize> {
+        if !self.valid() && self.flags().contains(EntryFlags::SWAPPED) {
+            Some(self.ppn())
+        } else {
+            None
+        }
+    }
+
     pub const fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_retain(self.0 & FLAG_BITS)
     }
 
     pub const fn ppn(&self) -> usize {
-        self.0 & PPN_BITS >> 10
+        (self.0 & PPN_BITS) >> 10
+    }
+
+    pub const fn phys_addr(&self) -> PhysAddr {
+        PhysAddr(self.ppn() << 12)
     }
 
     pub const fn valid(&self) -> bool {
@@ -45,6 +78,11 @@ impl Entry {
         self.flags().intersects(LEAF_FLAGS)
     }
 
+    /// Whether the entry points to the next level's table.
+    pub const fn is_table(&self) -> bool {
+        self.valid() && !self.is_leaf()
+    }
+
     pub const fn with_flags(self, flags: EntryFlags) -> Self {
         Self(self.0 & !FLAG_BITS | flags.bits())
     }
@@ -53,3 +91,29 @@ impl Entry {
         Self(self.0 & !PPN_BITS | (ppn << 10 & PPN_BITS))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn swap_entries_keep_the_slot_in_the_ppn() {
+        for slot in [0, 0x1234, PPN_BITS >> 10] {
+            let entry = Entry::swapped(slot);
+            assert!(!entry.valid());
+            assert_eq!(entry.flags(), EntryFlags::SWAPPED);
+            assert_eq!(entry.ppn(), slot);
+            assert_eq!(entry.swap_slot(), Some(slot));
+        }
+    }
+
+    #[test_case]
+    fn old_pages_are_not_swap_entries() {
+        let old = Entry::new()
+            .with_ppn(0x80400)
+            .with_flags(EntryFlags::VALID | EntryFlags::READ | EntryFlags::OLD);
+        assert!(old.flags().contains(EntryFlags::SWAPPED));
+        assert_eq!(old.swap_slot(), None);
+        assert_eq!(Entry::new().swap_slot(), None);
+    }
+}
@@ -0,0 +1,636 @@
+//! Maps, unmaps and reprotects ranges of a page table. Contiguous ranges are mapped with the
+//! largest pages that alignment allows, and huge pages are split back into smaller ones when
+//! only part of one is unmapped or reprotected, so callers never deal with page sizes.
+
+#[cfg(target_arch = "riscv64")]
+use super::space::current_root;
+use super::{
+    PageType,
+    entry::{Entry, EntryFlags},
+    flush, flush_all, mode,
+    table::{ENTRY_COUNT, RawTable},
+};
+use crate::mem::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr},
+    alloc::{Allocation, PAGE_ALLOCATOR},
+    frame::{self, FrameFlags},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// An address or length isn't page aligned.
+    Misaligned,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// There's no memory left for page tables.
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    pub page_type: PageType,
+    pub flags: EntryFlags,
+}
+
+pub struct Mapper<'a> {
+    root: &'a mut RawTable,
+}
+
+/// Size of the region mapped by one entry of a table at `level`.
+const fn entry_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Allocates a zeroed page table.
+pub(super) fn alloc_table() -> Result<PhysAddr, MapError> {
+    let allocation = PAGE_ALLOCATOR
+        .lock()
+        .alloc(0)
+        .ok_or(MapError::OutOfMemory)?;
+    let (phys, _) = allocation.into_raw();
+
+    unsafe {
+        VirtAddr::from_phys(phys)
+            .as_ptr::<RawTable>()
+            .write(RawTable::new())
+    };
+
+    if let Some(frame) = frame::frame(phys) {
+        frame.set_flags(FrameFlags::KERNEL | FrameFlags::PAGE_TABLE);
+        frame.get();
+    }
+
+    Ok(phys)
+}
+
+/// # Safety
+/// `phys` must come from `alloc_table` and no longer be referenced by any page table.
+pub(super) unsafe fn free_table(phys: PhysAddr) {
+    if let Some(frame) = frame::frame(phys) {
+        frame.put();
+    }
+
+    PAGE_ALLOCATOR
+        .lock()
+        .free(unsafe { Allocation::from_raw(phys, 0) });
+}
+
+pub(super) const fn table_entry(phys: PhysAddr) -> Entry {
+    // NOTE: non-leaf PTEs mustn't have the Dirty, Accessed or User bits set.
+    Entry::new()
+        .with_ppn(phys.ppn())
+        .with_flags(EntryFlags::VALID)
+}
+
+/// Replaces the huge page mapped by `entry` with a table of pages one size smaller that map the
+/// same memory with the same flags.
+fn split(entry: &mut Entry, level: usize) -> Result<(), MapError> {
+    let table_phys = alloc_table()?;
+    let table = unsafe { &mut *VirtAddr::from_phys(table_phys).as_ptr::<RawTable>() };
+
+    let child_size = entry_size(level - 1);
+    for (i, child) in table.0.iter_mut().enumerate() {
+        *child = Entry::new()
+            .with_ppn((entry.phys_addr() + i * child_size).ppn())
+            .with_flags(entry.flags());
+    }
+
+    *entry = table_entry(table_phys);
+    Ok(())
+}
+
+/// Calls `f` on every leaf entry that maps part of the linear range `[start, end)`, and on every
+/// swap entry in it, splitting
+/// huge pages that straddle its boundaries so `f` only ever sees entries inside it. `base` is
+/// the linear address mapped by the first entry of `table`.
+fn update_range(
+    table: &mut RawTable,
+    level: usize,
+    base: usize,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(&mut Entry, VirtAddr),
+) -> Result<(), MapError> {
+    let size = entry_size(level);
+    let first = (start.max(base) - base) / size;
+    let last = ((end - base).div_ceil(size)).min(ENTRY_COUNT);
+
+    for index in first..last {
+        let entry = &mut table.0[index];
+        let entry_start = base + index * size;
+        let entry_end = entry_start + size;
+
+        if !entry.valid() {
+            // Invalid last-level entries may still be swap entries.
+            if level == 0 && *entry != Entry::new() {
+                f(entry, VirtAddr::new_truncate(entry_start));
+            }
+            continue;
+        }
+
+        if entry.is_leaf() {
+            if start <= entry_start && entry_end <= end {
+                f(entry, VirtAddr::new_truncate(entry_start));
+                continue;
+            }
+
+            split(entry, level)?;
+        }
+
+        let child = unsafe { RawTable::next_table(entry) };
+        update_range(child, level - 1, entry_start, start, end, f)?;
+
+        // Tables directly below the root may be shared between address spaces, so only lower
+        // tables are freed once empty.
+        if level != mode().root_level() && child.is_empty() {
+            let child_phys = entry.phys_addr();
+            *entry = Entry::new();
+            // Flushing an address only has to drop leaf entries, and the hart may still have
+            // cached the table's entry above, so everything goes before the table is reused.
+            flush_all();
+            unsafe { free_table(child_phys) };
+        }
+    }
+
+    Ok(())
+}
+
+impl<'a> Mapper<'a> {
+    /// # Safety
+    /// `root` must be the root of a page table that nothing else modifies while the mapper
+    /// exists.
+    pub unsafe fn new(root: &'a mut RawTable) -> Self {
+        Self { root }
+    }
+
+    /// The mapper for the page table that's currently loaded into `satp`.
+    ///
+    /// # Safety
+    /// Same as `new`.
+    #[cfg(target_arch = "riscv64")]
+    pub unsafe fn current() -> Mapper<'static> {
+        let root = current_root();
+        Mapper {
+            root: unsafe { &mut *VirtAddr::from_phys(root).as_ptr::<RawTable>() },
+        }
+    }
+
+    /// Whether a leaf of `page_type` could be placed at `virt` without disturbing an existing
+    /// mapping or table.
+    fn is_free(&self, virt: VirtAddr, page_type: PageType) -> bool {
+        let mut table = &*self.root;
+        let mut level = mode().root_level();
+        loop {
+            let entry = &table.0[virt.vpn(level)];
+            if level == page_type.level() || !entry.valid() {
+                return !entry.valid();
+            }
+
+            if entry.is_leaf() {
+                return false;
+            }
+
+            table = unsafe { RawTable::next_table(entry) };
+            level -= 1;
+        }
+    }
+
+    /// The table at `level` covering `virt`, creating it and any tables above it as needed.
+    fn table_at(&mut self, virt: VirtAddr, level: usize) -> Result<&mut RawTable, MapError> {
+        let mut table = &mut *self.root;
+        let mut current = mode().root_level();
+        while current > level {
+            let entry = &mut table.0[virt.vpn(current)];
+            if !entry.valid() {
+                *entry = table_entry(alloc_table()?);
+            } else if entry.is_leaf() {
+                return Err(MapError::AlreadyMapped);
+            }
+
+            table = unsafe { RawTable::next_table(entry) };
+            current -= 1;
+        }
+
+        Ok(table)
+    }
+
+    /// Creates every table at `level` (and above) covering `[virt, virt + len)` up front.
+    /// Kernel regions whose tables are created before any address space exists are shared by
+    /// all of them.
+    pub fn populate(&mut self, virt: VirtAddr, len: usize, level: usize) -> Result<(), MapError> {
+        let step = entry_size(level + 1);
+        let start = virt.as_usize() / step * step;
+        for table_virt in (start..virt.as_usize() + len).step_by(step) {
+            self.table_at(VirtAddr::new(table_virt), level)?;
+        }
+        Ok(())
+    }
+
+    /// Maps a single page, creating intermediate tables as needed.
+    pub fn map_page(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        page_type: PageType,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let size = page_type.size();
+        if !virt.as_usize().is_multiple_of(size) || !phys.as_usize().is_multiple_of(size) {
+            return Err(MapError::Misaligned);
+        }
+
+        let table = self.table_at(virt, page_type.level())?;
+        let entry = &mut table.0[virt.vpn(page_type.level())];
+        if entry.valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *entry = Entry::new()
+            .with_ppn(phys.ppn())
+            .with_flags(flags | EntryFlags::VALID);
+        Ok(())
+    }
+
+    /// Maps `[phys, phys + len)` at `virt` using the largest pages that alignment and existing
+    /// mappings allow. Nothing is left mapped if this fails.
+    pub fn map_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        len: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE)
+            || !phys.as_usize().is_multiple_of(PAGE_SIZE)
+            || !len.is_multiple_of(PAGE_SIZE)
+        {
+            return Err(MapError::Misaligned);
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let page_virt = virt + offset;
+            let page_phys = phys + offset;
+
+            let page_type = PageType::ALL
+                .into_iter()
+                .find(|page_type| {
+                    let size = page_type.size();
+                    page_type.level() <= mode().root_level()
+                        && page_virt.as_usize().is_multiple_of(size)
+                        && page_phys.as_usize().is_multiple_of(size)
+                        && len - offset >= size
+                        && self.is_free(page_virt, *page_type)
+                })
+                .unwrap_or(PageType::Base);
+
+            if let Err(err) = self.map_page(page_virt, page_phys, page_type, flags) {
+                self.unmap_range(virt, offset)?;
+                return Err(err);
+            }
+
+            offset += page_type.size();
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps every page in `[virt, virt + len)`. Unmapped holes in the range are skipped.
+    pub fn unmap_range(&mut self, virt: VirtAddr, len: usize) -> Result<(), MapError> {
+        self.unmap_range_with(virt, len, |_, _| {})
+    }
+
+    /// Like `unmap_range`, but calls `f` with every leaf entry and swap entry as it's removed, so
+    /// the caller can release the memory it referred to.
+    pub fn unmap_range_with(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(VirtAddr, Entry),
+    ) -> Result<(), MapError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+
+        let start = virt.linear();
+        update_range(
+            self.root,
+            mode().root_level(),
+            0,
+            start,
+            start + len,
+            &mut |entry, virt| {
+                let old = *entry;
+                *entry = Entry::new();
+                flush(virt);
+                f(virt, old);
+            },
+        )
+    }
+
+    /// Changes the permissions of every page in `[virt, virt + len)` to `flags`, keeping the
+    /// accessed and dirty bits.
+    // Nothing changes permissions in place yet, but the tests cover it
+    #[allow(dead_code)]
+    pub fn protect_range(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+
+        let start = virt.linear();
+        update_range(
+            self.root,
+            mode().root_level(),
+            0,
+            start,
+            start + len,
+            &mut |entry, virt| {
+                if !entry.valid() {
+                    return;
+                }
+
+                let kept = entry.flags() & (EntryFlags::ACCESSED | EntryFlags::DIRTY);
+                *entry = entry.with_flags(flags | kept | EntryFlags::VALID);
+                flush(virt);
+            },
+        )
+    }
+
+    /// Stores a swap entry for the base page at `virt`, creating intermediate tables as needed.
+    pub fn map_swapped(&mut self, virt: VirtAddr, entry: Entry) -> Result<(), MapError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+
+        let slot = &mut self.table_at(virt, 0)?.0[virt.vpn(0)];
+        if *slot != Entry::new() {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *slot = entry;
+        Ok(())
+    }
+
+    /// Calls `f` on every leaf entry and swap entry in `[virt, virt + len)`, splitting huge pages
+    /// that straddle the range's boundaries.
+    pub fn for_each_leaf(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(VirtAddr, &mut Entry),
+    ) -> Result<(), MapError> {
+        if !virt.as_usize().is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+
+        let start = virt.linear();
+        update_range(
+            self.root,
+            mode().root_level(),
+            0,
+            start,
+            start + len,
+            &mut |entry, virt| f(virt, entry),
+        )
+    }
+
+    /// The entry that maps (or would map) the page containing `virt`: a valid leaf at any
+    /// level, or the last-level entry, whether valid or not. `None` if a table on the way there
+    /// is missing.
+    pub fn leaf_mut(&mut self, virt: VirtAddr) -> Option<(&mut Entry, PageType)> {
+        let mut table = &mut *self.root;
+        let mut level = mode().root_level();
+        loop {
+            let entry = &mut table.0[virt.vpn(level)];
+            if level == 0 || (entry.valid() && entry.is_leaf()) {
+                return Some((entry, PageType::from_level(level)?));
+            }
+
+            if !entry.valid() {
+                return None;
+            }
+
+            table = unsafe { RawTable::next_table(entry) };
+            level -= 1;
+        }
+    }
+
+    /// Calls `f` with the level and entry of each table entry the hardware would read to
+    /// translate `virt`, from the root down to a leaf or an invalid entry.
+    pub fn walk(&self, virt: VirtAddr, mut f: impl FnMut(usize, &Entry)) {
+        let mut table = &*self.root;
+        let mut level = mode().root_level();
+        loop {
+            let entry = &table.0[virt.vpn(level)];
+            f(level, entry);
+            if !entry.valid() || entry.is_leaf() || level == 0 {
+                return;
+            }
+
+            table = unsafe { RawTable::next_table(entry) };
+            level -= 1;
+        }
+    }
+
+    pub fn translate(&self, virt: VirtAddr) -> Option<Translation> {
+        let mut table = &*self.root;
+        let mut level = mode().root_level();
+        loop {
+            let entry = &table.0[virt.vpn(level)];
+            if !entry.valid() {
+                return None;
+            }
+
+            if entry.is_leaf() {
+                let page_type = PageType::from_level(level)?;
+                let offset = virt.as_usize() & (page_type.size() - 1);
+                return Some(Translation {
+                    phys: entry.phys_addr() + offset,
+                    page_type,
+                    flags: entry.flags(),
+                });
+            }
+
+            table = unsafe { RawTable::next_table(entry) };
+            level = level.checked_sub(1)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    // In the user half, which is empty in a new table. Nothing is accessed through these
+    // mappings, so they can point anywhere.
+    const VIRT: VirtAddr = VirtAddr::new_const(0x4000_0000);
+    const PHYS: PhysAddr = PhysAddr(0x8040_0000);
+    const LEN: usize = 4 * PageType::Mega.size();
+    const FLAGS: EntryFlags = EntryFlags::READ.union(EntryFlags::WRITE);
+
+    // Runs `f` with a mapper for a new, empty page table, which is freed afterwards.
+    fn with_table(f: impl FnOnce(&mut Mapper)) {
+        let root_phys = alloc_table().unwrap();
+        let root = unsafe { &mut *VirtAddr::from_phys(root_phys).as_ptr::<RawTable>() };
+        let mut mapper = unsafe { Mapper::new(root) };
+        f(&mut mapper);
+
+        mapper.unmap_range(VIRT, LEN).unwrap();
+        for entry in mapper.root.0.iter_mut().filter(|entry| entry.is_table()) {
+            unsafe { free_table(entry.phys_addr()) };
+            *entry = Entry::new();
+        }
+        unsafe { free_table(root_phys) };
+    }
+
+    #[test_case]
+    fn map_page_then_translate() {
+        with_table(|mapper| {
+            mapper.map_page(VIRT, PHYS, PageType::Base, FLAGS).unwrap();
+
+            let translation = mapper.translate(VIRT + 0x123).unwrap();
+            assert_eq!(translation.phys, PHYS + 0x123);
+            assert_eq!(translation.page_type, PageType::Base);
+            assert!(translation.flags.contains(FLAGS | EntryFlags::VALID));
+            assert!(mapper.translate(VIRT + PAGE_SIZE).is_none());
+        });
+    }
+
+    #[test_case]
+    fn map_page_rejects_overlap_and_misalignment() {
+        with_table(|mapper| {
+            mapper.map_page(VIRT, PHYS, PageType::Base, FLAGS).unwrap();
+            assert_eq!(
+                mapper.map_page(VIRT, PHYS, PageType::Base, FLAGS),
+                Err(MapError::AlreadyMapped)
+            );
+            assert_eq!(
+                mapper.map_page(VIRT + PAGE_SIZE, PHYS, PageType::Mega, FLAGS),
+                Err(MapError::Misaligned)
+            );
+            assert_eq!(
+                mapper.map_range(VIRT, PHYS, 100, FLAGS),
+                Err(MapError::Misaligned)
+            );
+        });
+    }
+
+    #[test_case]
+    fn walk_stops_at_leaves_and_holes() {
+        with_table(|mapper| {
+            mapper.map_page(VIRT, PHYS, PageType::Mega, FLAGS).unwrap();
+
+            let mut levels = Vec::new();
+            mapper.walk(VIRT + PAGE_SIZE, |level, entry| {
+                levels.push((level, entry.is_leaf()))
+            });
+            let root = mode().root_level();
+            assert_eq!(levels.first(), Some(&(root, false)));
+            assert_eq!(levels.last(), Some(&(1, true)));
+            assert_eq!(levels.len(), root);
+
+            // Stops at the empty level 1 entry next to the megapage
+            let mut visited = 0;
+            mapper.walk(VIRT + PageType::Mega.size(), |_, entry| {
+                visited += 1;
+                assert!(entry.is_table() || !entry.valid());
+            });
+            assert_eq!(visited, root);
+        });
+    }
+
+    #[test_case]
+    fn map_range_uses_huge_pages() {
+        with_table(|mapper| {
+            // A base page, then megapages once both addresses are aligned
+            let start = VirtAddr::new(VIRT.as_usize() + PageType::Mega.size() - PAGE_SIZE);
+            let phys = PHYS + PageType::Mega.size() - PAGE_SIZE;
+            mapper
+                .map_range(start, phys, PAGE_SIZE + 2 * PageType::Mega.size(), FLAGS)
+                .unwrap();
+
+            assert_eq!(mapper.translate(start).unwrap().page_type, PageType::Base);
+            let huge = mapper.translate(start + PAGE_SIZE).unwrap();
+            assert_eq!(huge.page_type, PageType::Mega);
+            assert_eq!(huge.phys, phys + PAGE_SIZE);
+        });
+    }
+
+    #[test_case]
+    fn unmap_splits_huge_pages() {
+        with_table(|mapper| {
+            mapper.map_page(VIRT, PHYS, PageType::Mega, FLAGS).unwrap();
+            mapper.unmap_range(VIRT + PAGE_SIZE, PAGE_SIZE).unwrap();
+
+            assert!(mapper.translate(VIRT + PAGE_SIZE).is_none());
+            for offset in [0, 2 * PAGE_SIZE, PageType::Mega.size() - PAGE_SIZE] {
+                let translation = mapper.translate(VIRT + offset).unwrap();
+                assert_eq!(translation.page_type, PageType::Base);
+                assert_eq!(translation.phys, PHYS + offset);
+            }
+        });
+    }
+
+    #[test_case]
+    fn protect_range_keeps_mappings() {
+        with_table(|mapper| {
+            mapper.map_range(VIRT, PHYS, 2 * PAGE_SIZE, FLAGS).unwrap();
+            mapper
+                .protect_range(VIRT, PAGE_SIZE, EntryFlags::READ)
+                .unwrap();
+
+            let protected = mapper.translate(VIRT).unwrap();
+            assert_eq!(protected.phys, PHYS);
+            assert!(!protected.flags.contains(EntryFlags::WRITE));
+            assert!(
+                mapper
+                    .translate(VIRT + PAGE_SIZE)
+                    .unwrap()
+                    .flags
+                    .contains(EntryFlags::WRITE)
+            );
+        });
+    }
+
+    #[test_case]
+    fn swap_entries_round_trip() {
+        with_table(|mapper| {
+            let swapped = VIRT + PAGE_SIZE;
+            mapper.map_page(VIRT, PHYS, PageType::Base, FLAGS).unwrap();
+            mapper.map_swapped(swapped, Entry::swapped(42)).unwrap();
+
+            // Not translated, but still visited with the range's leaves
+            assert!(mapper.translate(swapped).is_none());
+            let mut slots = Vec::new();
+            mapper
+                .for_each_leaf(VIRT, 2 * PAGE_SIZE, |virt, entry| {
+                    slots.push((virt, entry.swap_slot()));
+                })
+                .unwrap();
+            assert_eq!(slots, [(VIRT, None), (swapped, Some(42))]);
+
+            for (virt, err) in [
+                (VIRT, MapError::AlreadyMapped),
+                (swapped, MapError::AlreadyMapped),
+                (swapped + 8, MapError::Misaligned),
+            ] {
+                assert_eq!(mapper.map_swapped(virt, Entry::swapped(7)), Err(err));
+            }
+
+            let mut removed = Vec::new();
+            mapper
+                .unmap_range_with(swapped, PAGE_SIZE, |_, entry| removed.push(entry))
+                .unwrap();
+            assert_eq!(removed, [Entry::swapped(42)]);
+        });
+    }
+}
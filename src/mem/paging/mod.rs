@@ -1,10 +1,78 @@
+// Address spaces and ASIDs need the hardware, so host tests (see `host-tests`) go without them.
+#[cfg(target_arch = "riscv64")]
+pub mod asid;
 pub mod entry;
+pub mod mapper;
+#[cfg(target_arch = "riscv64")]
+pub mod space;
 pub mod table;
-use core::marker::PhantomData;
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use super::addr::{Addr, VirtAddr};
 use crate::mem::addr::PhysAddr;
 
+// Set once by `space::init`; `_boot` always starts out in Sv39.
+static MODE: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+/// Paging modes, named after their virtual address width. The discriminant is the value of
+/// satp's MODE field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    /// Best first, which is the order they're probed in.
+    pub const ALL: [Self; 3] = [Self::Sv57, Self::Sv48, Self::Sv39];
+
+    pub const fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+
+    pub const fn root_level(self) -> usize {
+        self.levels() - 1
+    }
+
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    pub const fn satp_mode(self) -> usize {
+        self as usize
+    }
+
+    pub const fn from_satp_mode(mode: usize) -> Option<Self> {
+        match mode {
+            8 => Some(Self::Sv39),
+            9 => Some(Self::Sv48),
+            10 => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+}
+
+/// The paging mode the kernel runs under.
+pub fn mode() -> PagingMode {
+    PagingMode::from_satp_mode(MODE.load(Ordering::Relaxed) as usize).unwrap()
+}
+
+pub(crate) fn set_mode(mode: PagingMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Page<A: Addr> {
     index: usize,
     ty: PageType,
@@ -14,7 +82,7 @@ pub struct Page<A: Addr> {
 pub type PhysPage = Page<PhysAddr>;
 pub type VirtPage = Page<VirtAddr>;
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageType {
     /// 4 KiB page
     Base,
@@ -22,18 +90,62 @@ pub enum PageType {
     Mega,
     /// 1 GiB page
     Giga,
+    /// 512 GiB page (Sv48 and up)
+    Tera,
+    /// 256 TiB page (Sv57 only)
+    Peta,
 }
 
 impl PageType {
+    /// Largest first, which is the order the mapper tries them in.
+    pub const ALL: [Self; 5] = [Self::Peta, Self::Tera, Self::Giga, Self::Mega, Self::Base];
+
     pub const fn size(self) -> usize {
+        0x1000 << (9 * self.level())
+    }
+
+    /// The page table level whose leaf entries map pages of this type.
+    pub const fn level(self) -> usize {
         match self {
-            Self::Base => 0x1000,
-            Self::Mega => 0x200_0000,
-            Self::Giga => 0x4000_0000,
+            Self::Base => 0,
+            Self::Mega => 1,
+            Self::Giga => 2,
+            Self::Tera => 3,
+            Self::Peta => 4,
         }
     }
+
+    pub const fn from_level(level: usize) -> Option<Self> {
+        match level {
+            0 => Some(Self::Base),
+            1 => Some(Self::Mega),
+            2 => Some(Self::Giga),
+            3 => Some(Self::Tera),
+            4 => Some(Self::Peta),
+            _ => None,
+        }
+    }
+}
+
+/// Flushes the TLB entries for the page containing `addr`, in every address space.
+#[cfg(target_arch = "riscv64")]
+pub fn flush(addr: VirtAddr) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) addr.as_usize()) };
 }
 
+// There's no TLB when the page tables are only walked in software.
+#[cfg(not(target_arch = "riscv64"))]
+pub fn flush(_addr: VirtAddr) {}
+
+/// Flushes the entire TLB.
+#[cfg(target_arch = "riscv64")]
+pub fn flush_all() {
+    unsafe { asm!("sfence.vma") };
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+pub fn flush_all() {}
+
 impl<A: Addr> Page<A> {
     pub fn try_new(index: usize, ty: PageType) -> Option<Self> {
         if A::try_new(index * ty.size()).is_some() {
@@ -0,0 +1,498 @@
+//! Address spaces. Each one owns a root table whose upper half is shared with the kernel's
+//! page table, and is tagged with an ASID so switching between them doesn't flush the TLB.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use log::info;
+use spin::{Mutex, Once};
+
+use super::{
+    PageType, PagingMode,
+    asid::{ASID_ALLOCATOR, AsidContext},
+    entry::{Entry, EntryFlags},
+    flush, flush_all,
+    mapper::{MapError, Mapper, alloc_table, free_table, table_entry},
+    mode, set_mode,
+    table::{ENTRY_COUNT, RawTable},
+};
+use crate::{
+    asm::{read_csr, write_csr},
+    mem::{
+        addr::{PhysAddr, VirtAddr},
+        fault::{self, Access, FaultError},
+        swap,
+        vma::{Backing, Vma, VmaError, VmaFlags, VmaTree},
+    },
+};
+
+const SATP_MODE_SHIFT: usize = 60;
+const SATP_ASID_SHIFT: usize = 44;
+
+/// Root entries at or above this index map the kernel half of the address space.
+pub const KERNEL_ROOT_INDEX: usize = ENTRY_COUNT / 2;
+
+// Root of the page table set up by `_boot`.
+static KERNEL_ROOT: Once<PhysAddr> = Once::new();
+
+/// Pages to try to free when a fault runs out of memory.
+const RECLAIM_BATCH: usize = 32;
+
+// Serializes changes to the kernel's page table.
+static KERNEL_PT_LOCK: Mutex<()> = Mutex::new(());
+
+// The address space in satp, if it isn't the kernel's. Holding a reference keeps it from being
+// dropped while it's loaded.
+static ACTIVE: Mutex<Option<Arc<AddressSpace>>> = Mutex::new(None);
+
+// Every address space, for reclaim to scan.
+static SPACES: Mutex<Vec<Weak<AddressSpace>>> = Mutex::new(Vec::new());
+
+pub struct AddressSpace {
+    root: PhysAddr,
+    asid: AsidContext,
+    // Also serializes changes to the user half of the page table.
+    vmas: Mutex<VmaTree>,
+}
+
+pub const fn satp(mode: PagingMode, root: PhysAddr, asid: usize) -> usize {
+    mode.satp_mode() << SATP_MODE_SHIFT | asid << SATP_ASID_SHIFT | root.ppn()
+}
+
+/// Root of the page table in satp.
+pub fn current_root() -> PhysAddr {
+    let satp = unsafe { read_csr!("satp") };
+    PhysAddr((satp & 0xfffffffffff) << 12)
+}
+
+/// Builds the chain of tables that turns the Sv39 root into a root for `mode`. Each new table
+/// points its last entry at the one below it, so the Sv39 root ends up mapping the top 512 GiB,
+/// which is where the kernel lives in every mode.
+fn wrap_root(mode: PagingMode, sv39_root: PhysAddr) -> Result<PhysAddr, MapError> {
+    let mut root = sv39_root;
+    for level in PagingMode::Sv39.levels()..mode.levels() {
+        let table = match alloc_table() {
+            Ok(table) => table,
+            Err(err) => {
+                unsafe { unwrap_root(root, level - 1) };
+                return Err(err);
+            }
+        };
+
+        raw_table(table).0[ENTRY_COUNT - 1] = table_entry(root);
+        root = table;
+    }
+
+    Ok(root)
+}
+
+/// Frees the tables added by `wrap_root`, given the root and its level.
+unsafe fn unwrap_root(mut root: PhysAddr, root_level: usize) {
+    for _ in PagingMode::Sv39.root_level()..root_level {
+        let next = raw_table(root).0[ENTRY_COUNT - 1].phys_addr();
+        unsafe { free_table(root) };
+        root = next;
+    }
+}
+
+/// Switches to the deepest paging mode the hart supports. A satp write with an unsupported mode
+/// has no effect, so each mode is tried by writing it and reading satp back.
+unsafe fn select_mode(sv39_root: PhysAddr) -> PhysAddr {
+    for candidate in PagingMode::ALL {
+        if candidate == PagingMode::Sv39 {
+            break;
+        }
+
+        let Ok(root) = wrap_root(candidate, sv39_root) else {
+            continue;
+        };
+
+        unsafe {
+            write_csr!("satp", satp(candidate, root, 0));
+        }
+        let satp_mode = unsafe { read_csr!("satp") } >> SATP_MODE_SHIFT;
+        if satp_mode == candidate.satp_mode() {
+            flush_all();
+            set_mode(candidate);
+            return root;
+        }
+
+        unsafe { unwrap_root(root, candidate.root_level()) };
+    }
+
+    sv39_root
+}
+
+/// Picks the paging mode, records the kernel's page table and probes for ASID support.
+///
+/// # Safety
+/// Must be called once, on the boot page table, before any address space is created.
+pub unsafe fn init() {
+    let sv39_root = current_root();
+
+    // The identity mapping `_boot` needed while enabling paging lives in the user half, and
+    // would end up somewhere in the kernel half under a deeper mode.
+    raw_table(sv39_root).0[..KERNEL_ROOT_INDEX].fill(Entry::new());
+    flush_all();
+
+    let root = unsafe { select_mode(sv39_root) };
+    KERNEL_ROOT.call_once(|| root);
+
+    let mut asid_allocator = ASID_ALLOCATOR.lock();
+    unsafe { asid_allocator.probe() };
+    info!("paging: {:?}, {} ASID bits", mode(), asid_allocator.bits());
+}
+
+/// Runs `f` with a mapper for the kernel's page table, whose upper half is shared by every
+/// address space.
+pub fn with_kernel_mapper<R>(f: impl FnOnce(&mut Mapper<'_>) -> R) -> R {
+    let _guard = KERNEL_PT_LOCK.lock();
+    let root = *KERNEL_ROOT.get().expect("paging isn't initialized");
+    f(&mut unsafe { Mapper::new(raw_table(root)) })
+}
+
+/// The address space that's currently active, if any.
+pub fn current() -> Option<Arc<AddressSpace>> {
+    ACTIVE.lock().clone()
+}
+
+/// Every live address space.
+pub fn spaces() -> Vec<Arc<AddressSpace>> {
+    SPACES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Switches back to the kernel's page table.
+// There are no processes yet, so only the tests switch address spaces
+#[allow(dead_code)]
+pub fn activate_kernel() {
+    let root = *KERNEL_ROOT.get().expect("paging isn't initialized");
+    unsafe {
+        write_csr!("satp", satp(mode(), root, 0));
+    }
+    flush_all();
+    ACTIVE.lock().take();
+}
+
+fn raw_table<'a>(phys: PhysAddr) -> &'a mut RawTable {
+    unsafe { &mut *VirtAddr::from_phys(phys).as_ptr::<RawTable>() }
+}
+
+// Frees every table below `table`, but not the pages they map.
+unsafe fn free_tables(table: &mut RawTable, level: usize) {
+    for entry in table.0.iter_mut().filter(|entry| entry.is_table()) {
+        let child = entry.phys_addr();
+        if level > 1 {
+            unsafe { free_tables(raw_table(child), level - 1) };
+        }
+        unsafe { free_table(child) };
+        *entry = Entry::new();
+    }
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    // There are no processes yet, so only the tests create address spaces
+    #[allow(dead_code)]
+    pub fn new() -> Result<Arc<Self>, MapError> {
+        let kernel_root = *KERNEL_ROOT.get().expect("paging isn't initialized");
+        let root = alloc_table()?;
+
+        let kernel_half = &raw_table(kernel_root).0[KERNEL_ROOT_INDEX..];
+        raw_table(root).0[KERNEL_ROOT_INDEX..].copy_from_slice(kernel_half);
+
+        let space = Arc::new(Self {
+            root,
+            asid: AsidContext::new(),
+            vmas: Mutex::new(VmaTree::new()),
+        });
+        SPACES.lock().push(Arc::downgrade(&space));
+        Ok(space)
+    }
+
+    /// Loads the address space into satp. Only the ASID being handed out is flushed, and only
+    /// if this address space hasn't been given one in the current generation.
+    #[allow(dead_code)]
+    pub fn activate(self: &Arc<Self>) {
+        let mut active = ACTIVE.lock();
+        let asid = ASID_ALLOCATOR.lock().assign(&self.asid);
+        unsafe {
+            write_csr!("satp", satp(mode(), self.root, asid));
+        }
+
+        if asid == 0 {
+            // No ASIDs, so every address space shares ASID 0.
+            flush_all();
+        }
+
+        *active = Some(self.clone());
+    }
+
+    /// Whether this address space is the one in satp.
+    pub fn is_active(&self) -> bool {
+        current_root() == self.root
+    }
+
+    /// Adds an area. Its pages are mapped on first access.
+    // Like `new`, only the tests have address spaces to map areas into yet
+    #[allow(dead_code)]
+    pub fn map_area(&self, vma: Vma) -> Result<(), VmaError> {
+        self.vmas.lock().insert(vma)
+    }
+
+    /// Removes the area starting at `start`, unmapping and releasing its pages.
+    #[allow(dead_code)]
+    pub fn unmap_area(&self, start: VirtAddr) -> Result<(), VmaError> {
+        let mut vmas = self.vmas.lock();
+        let vma = vmas.remove(start)?;
+        unsafe { self.release_area(&vma) };
+        Ok(())
+    }
+
+    /// Creates a copy of this address space. Pages are shared rather than copied: pages of
+    /// writable areas become read-only copy-on-write pages in both address spaces, and each
+    /// side gets its own copy on its first store.
+    // Nothing forks until there are processes
+    #[allow(dead_code)]
+    pub fn fork(&self) -> Result<Arc<Self>, MapError> {
+        let child = Self::new()?;
+        let vmas = self.vmas.lock();
+        let mut child_vmas = child.vmas.lock();
+        let mut parent_mapper = unsafe { Mapper::new(raw_table(self.root)) };
+        let mut child_mapper = unsafe { Mapper::new(raw_table(child.root)) };
+
+        for vma in vmas.iter() {
+            child_vmas
+                .insert(vma.clone())
+                .expect("the parent's areas don't overlap");
+
+            let owns_pages = !matches!(vma.backing(), Backing::Device { .. });
+            let cow = owns_pages && vma.flags().contains(VmaFlags::WRITE);
+
+            // Areas are only ever mapped with base pages, by the fault handler.
+            let mut result = Ok(());
+            parent_mapper.for_each_leaf(vma.start(), vma.len(), |virt, entry| {
+                if result.is_err() {
+                    return;
+                }
+
+                if let Some(slot) = entry.swap_slot() {
+                    result = child_mapper.map_swapped(virt, *entry);
+                    if result.is_ok() {
+                        swap::dup_slot(slot);
+                    }
+                    return;
+                }
+
+                if cow {
+                    *entry =
+                        entry.with_flags((entry.flags() - EntryFlags::WRITE) | EntryFlags::COW);
+                    flush(virt);
+                }
+
+                result =
+                    child_mapper.map_page(virt, entry.phys_addr(), PageType::Base, entry.flags());
+                if result.is_ok() && owns_pages {
+                    fault::share_page(entry.phys_addr());
+                }
+            })?;
+            result?;
+        }
+
+        drop(child_vmas);
+        Ok(child)
+    }
+
+    /// Maps the page containing `addr` if it's part of an area that allows `access`, evicting
+    /// cold pages to make room if memory runs out.
+    pub fn handle_fault(&self, addr: VirtAddr, access: Access) -> Result<(), FaultError> {
+        loop {
+            let vmas = self.vmas.lock();
+            let vma = vmas.find(addr).ok_or(FaultError::AccessViolation)?;
+
+            // The user half of the table is only changed with the area lock held.
+            let mut mapper = unsafe { Mapper::new(raw_table(self.root)) };
+            match fault::resolve(&mut mapper, vma, addr, access) {
+                Err(FaultError::OutOfMemory) => {
+                    // Reclaim scans this address space too.
+                    drop(vmas);
+                    if swap::reclaim(RECLAIM_BATCH) == 0 {
+                        return Err(FaultError::OutOfMemory);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs the reclaim clock over the pages of anonymous areas, evicting up to `target` cold
+    /// ones. Returns how many were freed. Skips the address space if it's busy.
+    pub fn reclaim(&self, target: usize) -> usize {
+        let Some(vmas) = self.vmas.try_lock() else {
+            return 0;
+        };
+
+        let mut mapper = unsafe { Mapper::new(raw_table(self.root)) };
+        let mut freed = 0;
+        for vma in vmas.iter() {
+            if freed >= target {
+                break;
+            }
+            if !matches!(vma.backing(), Backing::Anonymous) {
+                continue;
+            }
+
+            mapper
+                .for_each_leaf(vma.start(), vma.len(), |virt, entry| {
+                    if freed < target && swap::age(virt, entry) {
+                        freed += 1;
+                    }
+                })
+                .expect("areas are page aligned");
+        }
+        freed
+    }
+
+    // Must be called with the area lock held, or with `&mut self`.
+    unsafe fn release_area(&self, vma: &Vma) {
+        let mut mapper = unsafe { Mapper::new(raw_table(self.root)) };
+        let owns_pages = !matches!(vma.backing(), Backing::Device { .. });
+        mapper
+            .unmap_range_with(vma.start(), vma.len(), |_, entry| {
+                if let Some(slot) = entry.swap_slot() {
+                    swap::free_slot(slot);
+                } else if owns_pages {
+                    fault::release_page(entry.phys_addr());
+                }
+            })
+            .expect("areas are page aligned");
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        ASID_ALLOCATOR.lock().release(&self.asid);
+
+        let mut vmas = core::mem::take(self.vmas.get_mut());
+        for vma in vmas.drain() {
+            unsafe { self.release_area(&vma) };
+        }
+
+        let root = raw_table(self.root);
+        for entry in root.0[..KERNEL_ROOT_INDEX]
+            .iter_mut()
+            .filter(|entry| entry.is_table())
+        {
+            let child = entry.phys_addr();
+            unsafe {
+                free_tables(raw_table(child), mode().root_level() - 1);
+                free_table(child);
+            }
+            *entry = Entry::new();
+        }
+
+        unsafe { free_table(self.root) };
+
+        SPACES.lock().retain(|space| space.strong_count() > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr;
+
+    use super::*;
+    use crate::mem::{PAGE_SIZE, frame, paging::mapper::Translation};
+
+    const AREA: VirtAddr = VirtAddr::new_const(0x1000_0000);
+
+    fn anonymous_space() -> Arc<AddressSpace> {
+        let space = AddressSpace::new().unwrap();
+        let vma = Vma::new(
+            AREA,
+            AREA + PAGE_SIZE,
+            VmaFlags::READ | VmaFlags::WRITE,
+            Backing::Anonymous,
+        )
+        .unwrap();
+        space.map_area(vma).unwrap();
+        space
+    }
+
+    fn read(space: &Arc<AddressSpace>) -> u64 {
+        space.activate();
+        unsafe { ptr::read_volatile(AREA.as_ptr()) }
+    }
+
+    fn write(space: &Arc<AddressSpace>, value: u64) {
+        space.activate();
+        unsafe { ptr::write_volatile(AREA.as_ptr(), value) };
+    }
+
+    fn translate(space: &AddressSpace) -> Translation {
+        unsafe { Mapper::new(raw_table(space.root)) }
+            .translate(AREA)
+            .unwrap()
+    }
+
+    fn refcount(phys: PhysAddr) -> u32 {
+        frame::frame(phys).unwrap().refcount()
+    }
+
+    #[test_case]
+    fn fork_shares_pages_until_written() {
+        let parent = anonymous_space();
+        write(&parent, 1);
+        let child = parent.fork().unwrap();
+
+        let shared = translate(&parent).phys;
+        assert_eq!(translate(&child).phys, shared);
+        assert_eq!(refcount(shared), 2);
+        assert!(translate(&parent).flags.contains(EntryFlags::COW));
+        assert_eq!(read(&child), 1);
+
+        write(&child, 2);
+        assert_ne!(translate(&child).phys, shared);
+        assert_eq!(refcount(shared), 1);
+        assert_eq!(read(&child), 2);
+        assert_eq!(read(&parent), 1);
+
+        activate_kernel();
+    }
+
+    #[test_case]
+    fn write_after_child_exits_reuses_page() {
+        let parent = anonymous_space();
+        write(&parent, 1);
+        let child = parent.fork().unwrap();
+        let shared = translate(&parent).phys;
+        drop(child);
+
+        // The page is no longer shared, so breaking COW just makes it writable again.
+        write(&parent, 3);
+        let translation = translate(&parent);
+        assert_eq!(translation.phys, shared);
+        assert!(translation.flags.contains(EntryFlags::WRITE));
+        assert!(!translation.flags.contains(EntryFlags::COW));
+        assert_eq!(read(&parent), 3);
+
+        activate_kernel();
+    }
+
+    #[test_case]
+    fn fork_leaves_unmapped_pages_to_fault() {
+        let parent = anonymous_space();
+        let child = parent.fork().unwrap();
+        assert_eq!(read(&child), 0);
+        assert!(
+            unsafe { Mapper::new(raw_table(parent.root)) }
+                .translate(AREA)
+                .is_none()
+        );
+
+        activate_kernel();
+    }
+}
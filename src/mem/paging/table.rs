@@ -1,13 +1,36 @@
 use core::marker::PhantomData;
 
 use super::entry::Entry;
-use crate::mem::paging::{PageType, PhysPage};
+use crate::mem::{
+    addr::VirtAddr,
+    paging::{PageType, PhysPage},
+};
 
 pub const ENTRY_COUNT: usize = 512;
 
 #[repr(C, align(4096))]
 pub struct RawTable(pub [Entry; ENTRY_COUNT]);
 
+impl RawTable {
+    pub const fn new() -> Self {
+        Self([Entry::new(); ENTRY_COUNT])
+    }
+
+    /// Whether every entry is zero. Invalid entries can still record swapped out pages.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|entry| *entry == Entry::new())
+    }
+
+    /// The table that a non-leaf entry points to.
+    ///
+    /// # Safety
+    /// `entry` must be a valid non-leaf entry of a live page table.
+    pub unsafe fn next_table<'a>(entry: &Entry) -> &'a mut RawTable {
+        debug_assert!(entry.is_table());
+        unsafe { &mut *VirtAddr::from_phys(entry.phys_addr()).as_ptr::<RawTable>() }
+    }
+}
+
 pub trait Level {
     const PAGE_TYPE: PageType;
 }
@@ -40,6 +63,7 @@ impl Level for Level0 {
     const PAGE_TYPE: PageType = PageType::Base;
 }
 
+#[repr(transparent)]
 pub struct Table<L: Level> {
     inner: RawTable,
     _phantom: PhantomData<L>,
@@ -60,32 +84,43 @@ pub enum TableEntryMut<'a, L: Superlevel> {
 }
 
 impl<L: Superlevel> Table<L> {
-    pub fn next(&self, index: usize) -> Option<TableEntry<L>> {
+    pub fn next(&self, index: usize) -> Option<TableEntry<'_, L>> {
         self.get(index).map(|entry| {
-            let ppn = entry.ppn();
             if entry.is_leaf() {
-                TableEntry::Page(PhysPage::new(ppn, L::PAGE_TYPE))
+                TableEntry::Page(PhysPage::containing_addr(entry.phys_addr(), L::PAGE_TYPE))
             } else {
-                // TODO: get virtual address, cast to table pointer and dereference
-                todo!()
+                let table = unsafe { RawTable::next_table(entry) };
+                TableEntry::Table(Table::from_raw(table))
             }
         })
     }
 
-    pub fn next_mut(&self, index: usize) -> Option<TableEntryMut<L>> {
+    pub fn next_mut(&mut self, index: usize) -> Option<TableEntryMut<'_, L>> {
         self.get(index).map(|entry| {
-            let ppn = entry.ppn();
             if entry.is_leaf() {
-                TableEntryMut::Page(PhysPage::new(ppn, L::PAGE_TYPE))
+                TableEntryMut::Page(PhysPage::containing_addr(entry.phys_addr(), L::PAGE_TYPE))
             } else {
-                // TODO: get virtual address, cast to table pointer and dereference
-                todo!()
+                let table = unsafe { RawTable::next_table(entry) };
+                TableEntryMut::Table(Table::from_raw_mut(table))
             }
         })
     }
 }
 
 impl<L: Level> Table<L> {
+    pub fn from_raw(raw: &RawTable) -> &Self {
+        // SAFETY: Table is a transparent wrapper around RawTable
+        unsafe { &*(raw as *const RawTable).cast() }
+    }
+
+    pub fn from_raw_mut(raw: &mut RawTable) -> &mut Self {
+        unsafe { &mut *(raw as *mut RawTable).cast() }
+    }
+
+    pub fn as_raw_mut(&mut self) -> &mut RawTable {
+        &mut self.inner
+    }
+
     fn get(&self, index: usize) -> Option<&Entry> {
         self.inner.0.get(index).filter(|entry| entry.valid())
     }
@@ -93,6 +128,57 @@ impl<L: Level> Table<L> {
     pub fn get_page(&self, index: usize) -> Option<PhysPage> {
         self.get(index)
             .filter(|entry| entry.is_leaf())
-            .map(|entry| PhysPage::new(entry.ppn(), L::PAGE_TYPE))
+            .map(|entry| PhysPage::containing_addr(entry.phys_addr(), L::PAGE_TYPE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{
+        PAGE_SIZE,
+        addr::PhysAddr,
+        paging::{
+            entry::EntryFlags,
+            mapper::{alloc_table, free_table, table_entry},
+        },
+    };
+
+    const FLAGS: EntryFlags = EntryFlags::VALID.union(EntryFlags::READ);
+
+    fn leaf(phys: PhysAddr) -> Entry {
+        Entry::new().with_ppn(phys.ppn()).with_flags(FLAGS)
+    }
+
+    #[test_case]
+    fn tables_walk_to_pages_and_subtables() {
+        let mega = PhysAddr(0x8020_0000);
+        let base = PhysAddr(0x8000_5000);
+        let (p1_phys, p0_phys) = (alloc_table().unwrap(), alloc_table().unwrap());
+        let p1 = unsafe { &mut *VirtAddr::from_phys(p1_phys).as_ptr::<RawTable>() };
+        let p0 = unsafe { &mut *VirtAddr::from_phys(p0_phys).as_ptr::<RawTable>() };
+        p1.0[1] = leaf(mega);
+        p1.0[2] = table_entry(p0_phys);
+        p0.0[7] = leaf(base);
+
+        let table = P1Table::from_raw(p1);
+        assert!(table.next(0).is_none());
+        assert_eq!(
+            table.get_page(1),
+            Some(PhysPage::containing_addr(mega, PageType::Mega))
+        );
+        let Some(TableEntry::Table(sub)) = table.next(2) else {
+            panic!("entry 2 isn't a table");
+        };
+        assert!(table.get_page(2).is_none());
+        let page = sub.get_page(7).unwrap();
+        assert_eq!(page, PhysPage::containing_addr(base, PageType::Base));
+        assert_eq!(page.start(), base);
+        assert_eq!(page.end(), base + PAGE_SIZE);
+
+        unsafe {
+            free_table(p0_phys);
+            free_table(p1_phys);
+        }
     }
 }
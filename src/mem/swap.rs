@@ -0,0 +1,305 @@
+//! Swapping anonymous pages out to a block device when memory runs low.
+//!
+//! Pages are aged with a clock scan over the page tables: a scan clears the accessed bit of
+//! pages that were accessed, marks those that weren't as old, and evicts those that were
+//! already old. Pages that were never written since they were zero-filled are just dropped,
+//! the rest are written to a swap slot, and their entry is replaced by a swap entry (see
+//! `Entry::swapped`) that the fault handler reads the page back from.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use log::info;
+use spin::Mutex;
+
+use super::{
+    PAGE_SIZE,
+    addr::VirtAddr,
+    fault::release_page,
+    frame,
+    paging::{
+        entry::{Entry, EntryFlags},
+        flush,
+        space::spaces,
+    },
+};
+use crate::{
+    cmdline::Param,
+    drivers::block::{BlockDevice, BlockError},
+};
+
+/// Swap to the first block device. Swapping overwrites the whole disk, so it has to be asked for.
+pub static ENABLE: Param<bool> = Param::new("swap", false);
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+/// How many times every address space is scanned before giving up. Pages need two scans
+/// without being accessed to be evicted.
+const MAX_PASSES: usize = 3;
+
+struct SwapArea {
+    device: Arc<dyn BlockDevice>,
+    // Number of swap entries referring to each slot; shared by forked address spaces
+    counts: Vec<u16>,
+    next: usize,
+}
+
+impl SwapArea {
+    fn blocks_per_slot(&self) -> u64 {
+        (PAGE_SIZE / self.device.block_size()) as u64
+    }
+
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let len = self.counts.len();
+        let slot = (self.next..len)
+            .chain(0..self.next)
+            .find(|&slot| self.counts[slot] == 0)?;
+        self.counts[slot] = 1;
+        self.next = (slot + 1) % len;
+        Some(slot)
+    }
+}
+
+/// Starts swapping to the whole of `device`, overwriting whatever is on it.
+pub fn enable(device: Arc<dyn BlockDevice>) {
+    let slots = (device.block_count() * device.block_size() as u64 / PAGE_SIZE as u64) as usize;
+    info!("swap: {slots} pages");
+
+    *SWAP.lock() = Some(SwapArea {
+        device,
+        counts: vec![0; slots],
+        next: 0,
+    });
+}
+
+/// Adds a reference to a swap slot, for a swap entry copied into another address space.
+pub fn dup_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap entry without a swap area");
+    swap.counts[slot] = swap.counts[slot]
+        .checked_add(1)
+        .expect("swap slot count overflow");
+}
+
+/// Drops a reference to a swap slot, freeing it once no swap entry refers to it.
+pub fn free_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap entry without a swap area");
+    assert!(swap.counts[slot] > 0, "free of unused swap slot {slot}");
+    swap.counts[slot] -= 1;
+}
+
+/// Reads the page in `slot` back into `page`.
+pub fn read_page(slot: usize, page: &mut [u8; PAGE_SIZE]) -> Result<(), BlockError> {
+    let swap = SWAP.lock();
+    let swap = swap.as_ref().expect("swap entry without a swap area");
+    swap.device
+        .read_blocks(slot as u64 * swap.blocks_per_slot(), page)
+}
+
+/// Writes the page mapped by `entry` to a new swap slot.
+fn write_page(entry: &Entry) -> Option<usize> {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut()?;
+    let slot = swap.alloc_slot()?;
+
+    let page = unsafe { &*VirtAddr::from_phys(entry.phys_addr()).as_ptr::<[u8; PAGE_SIZE]>() };
+    let block = slot as u64 * swap.blocks_per_slot();
+    if swap.device.write_blocks(block, page).is_err() {
+        swap.counts[slot] = 0;
+        return None;
+    }
+
+    Some(slot)
+}
+
+/// Advances the clock over the page mapped by `entry` at `virt`, evicting it if it's cold.
+/// Returns whether the page was freed. Only call this on entries of anonymous areas.
+pub(super) fn age(virt: VirtAddr, entry: &mut Entry) -> bool {
+    if !entry.valid() {
+        return false;
+    }
+
+    let flags = entry.flags();
+    if flags.contains(EntryFlags::ACCESSED) {
+        *entry = entry.with_flags(flags - EntryFlags::ACCESSED - EntryFlags::OLD);
+        flush(virt);
+        return false;
+    }
+
+    if !flags.contains(EntryFlags::OLD) {
+        *entry = entry.with_flags(flags | EntryFlags::OLD);
+        return false;
+    }
+
+    // Pages shared copy-on-write stay until every address space has its own copy.
+    let phys = entry.phys_addr();
+    if frame::frame(phys).is_none_or(|frame| frame.refcount() != 1) {
+        return false;
+    }
+
+    if !flags.contains(EntryFlags::DIRTY) {
+        // Never written since it was zero-filled, so the fault handler can zero-fill it again.
+        *entry = Entry::new();
+    } else {
+        let Some(slot) = write_page(entry) else {
+            return false;
+        };
+        *entry = Entry::swapped(slot);
+    }
+
+    flush(virt);
+    release_page(phys);
+    true
+}
+
+/// Evicts up to `target` cold pages from every address space. Returns how many were freed.
+pub fn reclaim(target: usize) -> usize {
+    let mut freed = 0;
+    for _ in 0..MAX_PASSES {
+        for space in spaces() {
+            if freed >= target {
+                return freed;
+            }
+            freed += space.reclaim(target - freed);
+        }
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::{addr::PhysAddr, alloc::PAGE_ALLOCATOR, fault::share_page, frame::FrameFlags};
+
+    const BLOCK_SIZE: usize = 512;
+    // Entries aren't in any page table, so this is only ever flushed.
+    const VIRT: VirtAddr = VirtAddr::new_const(0x4000_0000);
+
+    struct RamDisk(Mutex<Vec<u8>>);
+
+    impl BlockDevice for RamDisk {
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.0.lock().len() / BLOCK_SIZE) as u64
+        }
+
+        fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+            self.check_range(block, buf.len())?;
+            let start = block as usize * BLOCK_SIZE;
+            buf.copy_from_slice(&self.0.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+            self.check_range(block, buf.len())?;
+            let start = block as usize * BLOCK_SIZE;
+            self.0.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    // Runs `f` swapping to a disk of `pages` pages.
+    fn with_swap(pages: usize, f: impl FnOnce()) {
+        let old = SWAP.lock().take();
+        enable(Arc::new(RamDisk(Mutex::new(vec![0; pages * PAGE_SIZE]))));
+        f();
+        *SWAP.lock() = old;
+    }
+
+    fn alloc_slot() -> Option<usize> {
+        SWAP.lock().as_mut().unwrap().alloc_slot()
+    }
+
+    // A page filled with `byte`, mapped like the fault handler maps pages of anonymous areas
+    fn mapped_page(byte: u8, flags: EntryFlags) -> Entry {
+        let (phys, _) = PAGE_ALLOCATOR.lock().alloc(0).unwrap().into_raw();
+        unsafe {
+            VirtAddr::from_phys(phys)
+                .as_ptr::<u8>()
+                .write_bytes(byte, PAGE_SIZE)
+        };
+        if let Some(frame) = frame::frame(phys) {
+            frame.set_flags(FrameFlags::USER);
+            frame.get();
+            frame.inc_map_count();
+        }
+
+        let rw = EntryFlags::VALID | EntryFlags::READ | EntryFlags::WRITE;
+        Entry::new().with_ppn(phys.ppn()).with_flags(rw | flags)
+    }
+
+    fn refcount(phys: PhysAddr) -> Option<u32> {
+        frame::frame(phys).map(|frame| frame.refcount())
+    }
+
+    #[test_case]
+    fn slots_are_freed_with_their_last_entry() {
+        with_swap(2, || {
+            let slot = alloc_slot().unwrap();
+            dup_slot(slot);
+            free_slot(slot);
+
+            // The copy still refers to it
+            let other = alloc_slot().unwrap();
+            assert_ne!(other, slot);
+            assert_eq!(alloc_slot(), None);
+
+            free_slot(slot);
+            assert_eq!(alloc_slot(), Some(slot));
+            free_slot(slot);
+            free_slot(other);
+        });
+    }
+
+    #[test_case]
+    fn the_clock_evicts_pages_idle_for_two_scans() {
+        with_swap(1, || {
+            let mut entry = mapped_page(0, EntryFlags::ACCESSED);
+            let phys = entry.phys_addr();
+
+            assert!(!age(VIRT, &mut entry));
+            assert!(!entry.flags().contains(EntryFlags::ACCESSED));
+            assert!(!age(VIRT, &mut entry));
+            assert!(entry.flags().contains(EntryFlags::OLD));
+
+            // An access starts it over
+            entry = entry.with_flags(entry.flags() | EntryFlags::ACCESSED);
+            assert!(!age(VIRT, &mut entry));
+            assert!(!entry.flags().contains(EntryFlags::OLD));
+            assert!(!age(VIRT, &mut entry));
+
+            // Never written, so it's dropped rather than swapped
+            assert!(age(VIRT, &mut entry));
+            assert_eq!(entry, Entry::new());
+            assert!(refcount(phys).is_none_or(|count| count == 0));
+        });
+    }
+
+    #[test_case]
+    fn dirty_pages_are_written_to_swap() {
+        with_swap(1, || {
+            let mut entry = mapped_page(0xa5, EntryFlags::DIRTY | EntryFlags::OLD);
+            assert!(age(VIRT, &mut entry));
+            let slot = entry.swap_slot().unwrap();
+
+            let mut page = vec![0; PAGE_SIZE];
+            read_page(slot, page.as_mut_slice().try_into().unwrap()).unwrap();
+            assert!(page.iter().all(|&byte| byte == 0xa5));
+
+            // Stays mapped while there's no free slot, or while it's shared copy-on-write
+            let mut stuck = mapped_page(0, EntryFlags::DIRTY | EntryFlags::OLD);
+            assert!(!age(VIRT, &mut stuck));
+            free_slot(slot);
+            share_page(stuck.phys_addr());
+            assert!(!age(VIRT, &mut stuck));
+            assert!(stuck.valid());
+
+            release_page(stuck.phys_addr());
+            assert!(age(VIRT, &mut stuck));
+            free_slot(stuck.swap_slot().unwrap());
+        });
+    }
+}
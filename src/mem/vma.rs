@@ -0,0 +1,277 @@
+//! Virtual memory areas: the regions of an address space that may be touched, what they may be
+//! touched with, and what backs them. Pages inside an area are only mapped once they're first
+//! accessed (see `AddressSpace::handle_fault`).
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::fmt;
+
+use super::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr},
+    paging::entry::EntryFlags,
+};
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct VmaFlags: u8 {
+        const READ    = 0b0001;
+        const WRITE   = 0b0010;
+        const EXECUTE = 0b0100;
+        /// Accessible from U-mode.
+        const USER    = 0b1000;
+    }
+}
+
+/// Something that can fill a page of a file-backed area.
+pub trait PageSource: Send + Sync {
+    /// Fills `page` with the contents at `offset`, zeroing anything past the end of the source.
+    fn read_page(&self, offset: usize, page: &mut [u8; PAGE_SIZE]);
+}
+
+// Like `Vma::new`, only used by the tests yet
+#[allow(dead_code)]
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero-filled memory.
+    Anonymous,
+    /// A `PageSource`, starting at `offset` bytes into it.
+    File {
+        source: Arc<dyn PageSource>,
+        offset: usize,
+    },
+    /// Physical memory (usually MMIO) starting at `phys`. Never freed.
+    Device { phys: PhysAddr },
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: VmaFlags,
+    backing: Backing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The range or a device's physical address isn't page aligned, or the range is empty.
+    Misaligned,
+    /// The range overlaps an existing area.
+    Overlap,
+    /// No area starts at the given address.
+    NotFound,
+}
+
+/// The areas of an address space, sorted by start address and never overlapping.
+#[derive(Default)]
+pub struct VmaTree {
+    // Keyed by start address
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Anonymous => write!(f, "anonymous"),
+            Self::File { offset, .. } => write!(f, "file+{offset:#x}"),
+            Self::Device { phys } => write!(f, "device {phys:?}"),
+        }
+    }
+}
+
+impl fmt::Debug for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:#x}, {:#x}) {:?} {:?}",
+            self.start.as_usize(),
+            self.end.as_usize(),
+            self.flags,
+            self.backing
+        )
+    }
+}
+
+impl Vma {
+    // Only the tests create areas, as there are no processes yet
+    #[allow(dead_code)]
+    pub fn new(
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: VmaFlags,
+        backing: Backing,
+    ) -> Result<Self, VmaError> {
+        if !start.as_usize().is_multiple_of(PAGE_SIZE)
+            || !end.as_usize().is_multiple_of(PAGE_SIZE)
+            || start >= end
+        {
+            return Err(VmaError::Misaligned);
+        }
+        if let Backing::Device { phys } = &backing
+            && !phys.as_usize().is_multiple_of(PAGE_SIZE)
+        {
+            return Err(VmaError::Misaligned);
+        }
+
+        Ok(Self {
+            start,
+            end,
+            flags,
+            backing,
+        })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn flags(&self) -> VmaFlags {
+        self.flags
+    }
+
+    pub fn backing(&self) -> &Backing {
+        &self.backing
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Flags for the page table entries that map this area.
+    pub fn entry_flags(&self) -> EntryFlags {
+        let mut flags = EntryFlags::empty();
+        flags.set(EntryFlags::READ, self.flags.contains(VmaFlags::READ));
+        flags.set(EntryFlags::WRITE, self.flags.contains(VmaFlags::WRITE));
+        flags.set(EntryFlags::EXECUTE, self.flags.contains(VmaFlags::EXECUTE));
+        flags.set(EntryFlags::USER, self.flags.contains(VmaFlags::USER));
+        flags
+    }
+}
+
+impl VmaTree {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        let overlaps_prev = self
+            .areas
+            .range(..vma.end.as_usize())
+            .next_back()
+            .is_some_and(|(_, prev)| prev.end > vma.start);
+        if overlaps_prev {
+            return Err(VmaError::Overlap);
+        }
+
+        self.areas.insert(vma.start.as_usize(), vma);
+        Ok(())
+    }
+
+    /// Removes the area starting at `start`.
+    pub fn remove(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        self.areas
+            .remove(&start.as_usize())
+            .ok_or(VmaError::NotFound)
+    }
+
+    /// The area containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_usize())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = Vma> {
+        core::mem::take(&mut self.areas).into_values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: usize = 0x1000_0000;
+    const RW: VmaFlags = VmaFlags::READ.union(VmaFlags::WRITE);
+
+    // An anonymous area over the given pages past `START`
+    fn pages(first: usize, count: usize) -> Vma {
+        let start = VirtAddr::new(START + first * PAGE_SIZE);
+        Vma::new(start, start + count * PAGE_SIZE, RW, Backing::Anonymous).unwrap()
+    }
+
+    #[test_case]
+    fn areas_must_be_page_aligned() {
+        let start = VirtAddr::new(START);
+        let end = start + PAGE_SIZE;
+        let new = |start, end, backing| Vma::new(start, end, RW, backing).map(|vma| vma.len());
+
+        assert_eq!(new(start, end, Backing::Anonymous), Ok(PAGE_SIZE));
+        assert_eq!(
+            new(start + 8, end, Backing::Anonymous),
+            Err(VmaError::Misaligned)
+        );
+        assert_eq!(
+            new(start, start, Backing::Anonymous),
+            Err(VmaError::Misaligned)
+        );
+
+        let device = |phys| Backing::Device {
+            phys: PhysAddr(phys),
+        };
+        assert_eq!(new(start, end, device(0x1000_0000)), Ok(PAGE_SIZE));
+        assert_eq!(
+            new(start, end, device(0x1000_0010)),
+            Err(VmaError::Misaligned)
+        );
+    }
+
+    #[test_case]
+    fn overlapping_areas_are_rejected() {
+        let mut tree = VmaTree::new();
+        tree.insert(pages(4, 4)).unwrap();
+
+        for (first, count) in [(4, 4), (2, 3), (7, 2), (5, 1), (0, 16)] {
+            assert_eq!(tree.insert(pages(first, count)), Err(VmaError::Overlap));
+        }
+        // Touching either end is fine
+        tree.insert(pages(2, 2)).unwrap();
+        tree.insert(pages(8, 1)).unwrap();
+        assert_eq!(tree.iter().count(), 3);
+    }
+
+    #[test_case]
+    fn lookups_find_the_containing_area() {
+        let mut tree = VmaTree::new();
+        tree.insert(pages(0, 2)).unwrap();
+        tree.insert(pages(4, 1)).unwrap();
+
+        let start_of = |addr| {
+            tree.find(VirtAddr::new(START + addr))
+                .map(|vma| vma.start())
+        };
+        assert_eq!(start_of(0), Some(VirtAddr::new(START)));
+        assert_eq!(start_of(2 * PAGE_SIZE - 1), Some(VirtAddr::new(START)));
+        assert_eq!(start_of(2 * PAGE_SIZE), None);
+        assert_eq!(
+            start_of(4 * PAGE_SIZE + 8),
+            Some(VirtAddr::new(START + 4 * PAGE_SIZE))
+        );
+        assert_eq!(start_of(5 * PAGE_SIZE), None);
+
+        let second = VirtAddr::new(START + 4 * PAGE_SIZE);
+        assert_eq!(tree.remove(second).map(|vma| vma.len()), Ok(PAGE_SIZE));
+        assert_eq!(tree.remove(second).err(), Some(VmaError::NotFound));
+        assert!(tree.find(second).is_none());
+    }
+}
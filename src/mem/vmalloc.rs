@@ -0,0 +1,353 @@
+//! Virtually contiguous kernel allocations backed by individual, physically scattered pages, and
+//! mappings of device memory. Every area is followed by an unmapped guard gap, so running off its
+//! end faults instead of silently corrupting the next one.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{panic::Location, ptr::NonNull};
+
+use spin::Mutex;
+
+use super::{
+    PAGE_SIZE,
+    addr::{PhysAddr, VirtAddr},
+    alloc::{Allocation, PAGE_ALLOCATOR},
+    frame::{self, FrameFlags},
+    paging::{
+        PageType,
+        entry::EntryFlags,
+        mapper::{MapError, Mapper},
+        space::with_kernel_mapper,
+    },
+};
+use crate::io::serial::{print, println};
+
+pub const VMALLOC_START: VirtAddr = VirtAddr::new_const(0xffffffd000000000);
+pub const VMALLOC_LEN: usize = 16 * 1024 * 1024 * 1024;
+/// Unmapped space left after every area.
+pub const GUARD_LEN: usize = PAGE_SIZE;
+
+static VMALLOC: Mutex<Vmalloc> = Mutex::new(Vmalloc::new());
+
+const FLAGS: EntryFlags = EntryFlags::READ
+    .union(EntryFlags::WRITE)
+    .union(EntryFlags::GLOBAL)
+    .union(EntryFlags::ACCESSED)
+    .union(EntryFlags::DIRTY);
+
+#[derive(Debug)]
+pub struct VmArea {
+    start: VirtAddr,
+    memory: Memory,
+    site: &'static Location<'static>,
+}
+
+#[derive(Debug)]
+enum Memory {
+    /// Backing pages, one per page of the area.
+    Frames(Vec<PhysAddr>),
+    /// Device memory mapped by `ioremap`, which isn't freed with the area.
+    Device { phys: PhysAddr, len: usize },
+}
+
+struct Vmalloc {
+    // Keyed by start address
+    areas: BTreeMap<usize, VmArea>,
+}
+
+impl VmArea {
+    pub fn len(&self) -> usize {
+        match &self.memory {
+            Memory::Frames(frames) => frames.len() * PAGE_SIZE,
+            Memory::Device { len, .. } => *len,
+        }
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.len()
+    }
+}
+
+fn free_frame(phys: PhysAddr) {
+    if let Some(frame) = frame::frame(phys) {
+        frame.put();
+    }
+    PAGE_ALLOCATOR
+        .lock()
+        .free(unsafe { Allocation::from_raw(phys, 0) });
+}
+
+fn map_frames(mapper: &mut Mapper, start: VirtAddr, frames: &[PhysAddr]) -> Result<(), MapError> {
+    for (i, &phys) in frames.iter().enumerate() {
+        let virt = start + i * PAGE_SIZE;
+        if let Err(err) = mapper.map_page(virt, phys, PageType::Base, FLAGS) {
+            mapper.unmap_range(start, i * PAGE_SIZE)?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+impl Vmalloc {
+    const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// First fit: the lowest gap that fits `len` bytes plus a guard gap, starting at a multiple
+    /// of `align`.
+    fn find_gap(&self, len: usize, align: usize) -> Option<VirtAddr> {
+        let mut candidate = VMALLOC_START.as_usize();
+        for area in self.areas.values() {
+            candidate = candidate.next_multiple_of(align);
+            if candidate + len + GUARD_LEN <= area.start.as_usize() {
+                break;
+            }
+            candidate = area.end().as_usize() + GUARD_LEN;
+        }
+        let candidate = candidate.next_multiple_of(align);
+
+        let region_end = VMALLOC_START.as_usize() + VMALLOC_LEN;
+        (candidate + len + GUARD_LEN <= region_end).then(|| VirtAddr::new(candidate))
+    }
+}
+
+/// Creates the tables covering the vmalloc region, so that address spaces created afterwards
+/// share them and see every later vmalloc mapping.
+pub fn init() {
+    with_kernel_mapper(|mapper| mapper.populate(VMALLOC_START, VMALLOC_LEN, 1))
+        .expect("failed to populate the vmalloc region");
+}
+
+/// Allocates `size` bytes (rounded up to whole pages) of virtually contiguous, zeroed memory.
+// Nothing needs buffers this large yet
+#[allow(dead_code)]
+#[track_caller]
+pub fn vmalloc(size: usize) -> Option<NonNull<u8>> {
+    let site = Location::caller();
+    let pages = size.div_ceil(PAGE_SIZE).max(1);
+
+    let mut frames = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        let Some(allocation) = PAGE_ALLOCATOR.lock().alloc(0) else {
+            frames.into_iter().for_each(free_frame);
+            return None;
+        };
+
+        let (phys, _) = allocation.into_raw();
+        unsafe {
+            VirtAddr::from_phys(phys)
+                .as_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE)
+        };
+        if let Some(frame) = frame::frame(phys) {
+            frame.set_flags(FrameFlags::KERNEL);
+            frame.get();
+        }
+        frames.push(phys);
+    }
+
+    let mut vmalloc = VMALLOC.lock();
+    let Some(start) = vmalloc.find_gap(pages * PAGE_SIZE, PAGE_SIZE) else {
+        frames.into_iter().for_each(free_frame);
+        return None;
+    };
+
+    if with_kernel_mapper(|mapper| map_frames(mapper, start, &frames)).is_err() {
+        frames.into_iter().for_each(free_frame);
+        return None;
+    }
+
+    vmalloc.areas.insert(
+        start.as_usize(),
+        VmArea {
+            start,
+            memory: Memory::Frames(frames),
+            site,
+        },
+    );
+
+    start.as_non_null()
+}
+
+/// Maps `len` bytes of device memory at `phys`, which needn't be page aligned.
+#[track_caller]
+pub fn ioremap(phys: PhysAddr, len: usize) -> Option<NonNull<u8>> {
+    let site = Location::caller();
+    let offset = phys.as_usize() % PAGE_SIZE;
+    let phys = phys - offset;
+    let len = (offset + len).next_multiple_of(PAGE_SIZE);
+
+    // Placed like `phys` within a megapage, so `map_range` can use megapages for large windows.
+    // The region's tables are created down to level 1 by `init`, so nothing larger would fit.
+    let mega = PageType::Mega.size();
+    let align = if len >= mega && phys.as_usize().is_multiple_of(mega) {
+        mega
+    } else {
+        PAGE_SIZE
+    };
+
+    let mut vmalloc = VMALLOC.lock();
+    let start = vmalloc.find_gap(len, align)?;
+    with_kernel_mapper(|mapper| mapper.map_range(start, phys, len, FLAGS)).ok()?;
+
+    vmalloc.areas.insert(
+        start.as_usize(),
+        VmArea {
+            start,
+            memory: Memory::Device { phys, len },
+            site,
+        },
+    );
+
+    (start + offset).as_non_null()
+}
+
+/// Unmaps device memory mapped by `ioremap`.
+///
+/// # Safety
+/// `ptr` must come from `ioremap` and must not be used afterwards.
+pub unsafe fn iounmap(ptr: NonNull<u8>) {
+    let start = ptr.as_ptr() as usize / PAGE_SIZE * PAGE_SIZE;
+    let area = VMALLOC
+        .lock()
+        .areas
+        .remove(&start)
+        .unwrap_or_else(|| panic!("iounmap of {ptr:p}, which isn't an ioremap area"));
+
+    with_kernel_mapper(|mapper| mapper.unmap_range(area.start, area.len()))
+        .expect("failed to unmap an ioremap area");
+}
+
+/// Frees an allocation made by `vmalloc`.
+///
+/// # Safety
+/// `ptr` must come from `vmalloc` and must not be used afterwards.
+#[allow(dead_code)]
+pub unsafe fn vfree(ptr: NonNull<u8>) {
+    let area = VMALLOC
+        .lock()
+        .areas
+        .remove(&(ptr.as_ptr() as usize))
+        .unwrap_or_else(|| panic!("vfree of {ptr:p}, which isn't a vmalloc area"));
+
+    let len = area.len();
+    let Memory::Frames(frames) = area.memory else {
+        panic!("vfree of {ptr:p}, which is an ioremap area");
+    };
+
+    with_kernel_mapper(|mapper| mapper.unmap_range(area.start, len))
+        .expect("failed to unmap a vmalloc area");
+
+    frames.into_iter().for_each(free_frame);
+}
+
+/// Prints every vmalloc area.
+pub fn dump() {
+    let vmalloc = VMALLOC.lock();
+    println!("vmalloc: {} areas", vmalloc.areas.len());
+    for area in vmalloc.areas.values() {
+        print!(
+            "  [{:#x}, {:#x}) {:>8} bytes ",
+            area.start.as_usize(),
+            area.end().as_usize(),
+            area.len(),
+        );
+        match &area.memory {
+            Memory::Frames(frames) => {
+                print!("{:>5} pages", frames.len());
+            }
+            Memory::Device { phys, .. } => {
+                print!("ioremap {:#x}", phys.as_usize());
+            }
+        }
+        println!(", allocated at {}", area.site);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::slice;
+
+    use super::*;
+    use crate::mem::paging::mapper::Translation;
+
+    // Nothing is accessed through the mapping, so it can point anywhere.
+    const DEVICE: PhysAddr = PhysAddr(0x4000_0000);
+
+    fn translate(ptr: *const u8) -> Option<Translation> {
+        with_kernel_mapper(|mapper| mapper.translate(VirtAddr::new(ptr as usize)))
+    }
+
+    fn refcount(phys: PhysAddr) -> Option<u32> {
+        frame::frame(phys).map(|frame| frame.refcount())
+    }
+
+    #[test_case]
+    fn vmalloc_maps_zeroed_pages() {
+        let len = 3 * PAGE_SIZE;
+        let ptr = vmalloc(len - 8).unwrap();
+
+        let frames: Vec<PhysAddr> = (0..3)
+            .map(|i| {
+                let translation = translate(ptr.as_ptr().wrapping_add(i * PAGE_SIZE)).unwrap();
+                assert_eq!(translation.page_type, PageType::Base);
+                translation.phys
+            })
+            .collect();
+        for &phys in &frames {
+            assert!(refcount(phys).is_none_or(|count| count == 1));
+        }
+
+        let bytes = unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), len) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+        bytes.fill(0xa5);
+
+        unsafe { vfree(ptr) };
+        assert!(translate(ptr.as_ptr()).is_none());
+        for &phys in &frames {
+            assert!(refcount(phys).is_none_or(|count| count == 0));
+        }
+    }
+
+    #[test_case]
+    fn areas_are_followed_by_a_guard_gap() {
+        let first = vmalloc(PAGE_SIZE).unwrap();
+        let second = vmalloc(PAGE_SIZE).unwrap();
+
+        let guard = first.as_ptr().wrapping_add(PAGE_SIZE);
+        assert_eq!(second.as_ptr(), guard.wrapping_add(GUARD_LEN));
+        assert!(translate(guard).is_none());
+
+        unsafe { vfree(first) };
+        // Too large for the hole with its guard gap, so it goes past `second`
+        let larger = vmalloc(2 * PAGE_SIZE).unwrap();
+        assert!(larger > second);
+        // First fit: the hole is handed out again
+        let reused = vmalloc(PAGE_SIZE).unwrap();
+        assert_eq!(reused, first);
+
+        unsafe {
+            vfree(second);
+            vfree(larger);
+            vfree(reused);
+        }
+    }
+
+    #[test_case]
+    fn ioremap_uses_megapages() {
+        let mega = PageType::Mega.size();
+        let ptr = ioremap(DEVICE + 0x10, 2 * mega).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % mega, 0x10);
+
+        let translation = translate(ptr.as_ptr()).unwrap();
+        assert_eq!(translation.page_type, PageType::Mega);
+        assert_eq!(translation.phys, DEVICE + 0x10);
+        // The last 0x10 bytes spill into a base page
+        let last = ptr.as_ptr().wrapping_add(2 * mega);
+        assert_eq!(translate(last).unwrap().page_type, PageType::Base);
+
+        unsafe { iounmap(ptr) };
+        assert!(translate(ptr.as_ptr()).is_none());
+    }
+}
@@ -0,0 +1,230 @@
+//! Line editing for the monitor, on a VT100-style terminal. Understands printable ASCII,
+//! backspace, the arrow keys (up and down go through the history), and the usual control keys:
+//! ^A and ^E to go to the start and end, ^U to delete up to the cursor, ^W to delete a word and
+//! ^C to drop the line.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+const HISTORY: usize = 32;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+pub struct Editor {
+    prompt: &'static str,
+    // Only ever printable ASCII, so bytes and columns line up
+    line: String,
+    cursor: usize,
+    // Oldest first
+    history: Vec<String>,
+    // Which history entry is shown, if one is
+    browsing: Option<usize>,
+    escape: Escape,
+}
+
+// Where in an escape sequence (ESC [ <final byte>) input is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+impl Editor {
+    pub const fn new(prompt: &'static str) -> Self {
+        Self {
+            prompt,
+            line: String::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            escape: Escape::None,
+        }
+    }
+
+    /// Prints the prompt for a new line.
+    pub fn start(&self, out: &mut impl Write) -> fmt::Result {
+        out.write_str(self.prompt)
+    }
+
+    /// Handles one byte of input, echoing to `out`. Returns the line once it's entered.
+    pub fn feed(&mut self, byte: u8, out: &mut impl Write) -> Result<Option<String>, fmt::Error> {
+        match (self.escape, byte) {
+            (Escape::None, ESC) => self.escape = Escape::Esc,
+            (Escape::Esc, b'[') => self.escape = Escape::Csi,
+            // Parameters of a sequence that's ignored, like ESC [ 3 ~
+            (Escape::Csi, b'0'..=b'9' | b';') => {}
+            (Escape::Csi, key) => {
+                self.escape = Escape::None;
+                match key {
+                    b'A' => self.browse_older(),
+                    b'B' => self.browse_newer(),
+                    b'C' => self.cursor = (self.cursor + 1).min(self.line.len()),
+                    b'D' => self.cursor = self.cursor.saturating_sub(1),
+                    _ => return Ok(None),
+                }
+                self.redraw(out)?;
+            }
+            (Escape::Esc, _) => self.escape = Escape::None,
+
+            (_, b'\r' | b'\n') => {
+                out.write_str("\r\n")?;
+                let line = core::mem::take(&mut self.line);
+                self.cursor = 0;
+                self.browsing = None;
+                if !line.is_empty() && self.history.last() != Some(&line) {
+                    if self.history.len() == HISTORY {
+                        self.history.remove(0);
+                    }
+                    self.history.push(line.clone());
+                }
+                return Ok(Some(line));
+            }
+            (_, CTRL_C) => {
+                out.write_str("^C\r\n")?;
+                self.line.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                self.start(out)?;
+            }
+            (_, BACKSPACE | DEL) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                    self.redraw(out)?;
+                }
+            }
+            (_, CTRL_A) => {
+                self.cursor = 0;
+                self.redraw(out)?;
+            }
+            (_, CTRL_E) => {
+                self.cursor = self.line.len();
+                self.redraw(out)?;
+            }
+            (_, CTRL_U) => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+                self.redraw(out)?;
+            }
+            (_, CTRL_W) => {
+                let before = self.line[..self.cursor].trim_end();
+                let start = before.rfind(' ').map_or(0, |space| space + 1);
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+                self.redraw(out)?;
+            }
+            (_, b' '..=b'~') => {
+                self.line.insert(self.cursor, byte as char);
+                self.cursor += 1;
+                self.redraw(out)?;
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn browse_older(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.show_history(Some(index));
+    }
+
+    fn browse_newer(&mut self) {
+        match self.browsing {
+            Some(index) if index + 1 < self.history.len() => self.show_history(Some(index + 1)),
+            Some(_) => self.show_history(None),
+            None => {}
+        }
+    }
+
+    // Replaces the line with a history entry, or with an empty one for `None`.
+    fn show_history(&mut self, index: Option<usize>) {
+        self.browsing = index;
+        self.line = index.map_or_else(String::new, |index| self.history[index].clone());
+        self.cursor = self.line.len();
+    }
+
+    // Rewrites the whole line, and puts the terminal's cursor back where ours is.
+    fn redraw(&self, out: &mut impl Write) -> fmt::Result {
+        write!(out, "\r{}{}\x1b[K", self.prompt, self.line)?;
+        match self.line.len() - self.cursor {
+            0 => Ok(()),
+            back => write!(out, "\x1b[{back}D"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `input`, returning every line entered.
+    fn type_in(editor: &mut Editor, input: &[u8]) -> Vec<String> {
+        let mut echo = String::new();
+        input
+            .iter()
+            .filter_map(|&byte| editor.feed(byte, &mut echo).unwrap())
+            .collect()
+    }
+
+    #[test_case]
+    fn lines_are_returned_on_enter() {
+        let mut editor = Editor::new("> ");
+        assert_eq!(
+            type_in(&mut editor, b"mem\rpt 0x1000\n"),
+            ["mem", "pt 0x1000"]
+        );
+        assert_eq!(type_in(&mut editor, b"\r"), [""]);
+    }
+
+    #[test_case]
+    fn editing_keys_change_the_line() {
+        let mut editor = Editor::new("> ");
+        // Backspace, then a left arrow to insert before the last character
+        assert_eq!(type_in(&mut editor, b"peex\x7fk\x1b[Do\r"), ["peeok"]);
+        // ^A to insert at the start, ^E to append
+        assert_eq!(type_in(&mut editor, b"og\x01l\x05!\r"), ["log!"]);
+        // ^W deletes the last word, ^U everything before the cursor
+        assert_eq!(type_in(&mut editor, b"poke 0x10 \x17x\r"), ["poke x"]);
+        assert_eq!(type_in(&mut editor, b"harts\x15ps\r"), ["ps"]);
+        // ^C drops the line
+        assert_eq!(type_in(&mut editor, b"reboot\x03help\r"), ["help"]);
+    }
+
+    #[test_case]
+    fn arrows_go_through_the_history() {
+        let mut editor = Editor::new("> ");
+        type_in(&mut editor, b"first\rsecond\rsecond\r");
+        // Repeats are only kept once
+        assert_eq!(type_in(&mut editor, b"\x1b[A\x1b[A\r"), ["first"]);
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[A\x1b[A\x1b[A\x1b[B\r"),
+            ["second"]
+        );
+        // Down past the newest entry is an empty line again
+        assert_eq!(type_in(&mut editor, b"\x1b[A\x1b[Bnew\r"), ["new"]);
+    }
+
+    #[test_case]
+    fn echo_redraws_the_line() {
+        let mut editor = Editor::new("> ");
+        let mut echo = String::new();
+        editor.start(&mut echo).unwrap();
+        for &byte in b"ab\x1b[D" {
+            editor.feed(byte, &mut echo).unwrap();
+        }
+        assert_eq!(echo, "> \r> a\x1b[K\r> ab\x1b[K\r> ab\x1b[K\x1b[1D");
+    }
+}
@@ -0,0 +1,393 @@
+//! A debugging shell on the console, for poking at the kernel without rebuilding it. It runs at
+//! the end of boot if the kernel was booted with `monitor`, or if a key was pressed during boot.
+//! `help` lists the commands.
+
+pub mod editor;
+
+use core::{num::ParseIntError, ptr};
+
+use self::editor::Editor;
+use crate::{
+    cmdline::{self, Param},
+    fs::{FileKind, FsError, initramfs},
+    hart,
+    io::{
+        logger::{self, Line},
+        serial::{self, SERIAL, println},
+    },
+    mem::{
+        PAGE_SIZE,
+        addr::VirtAddr,
+        alloc::PAGE_ALLOCATOR,
+        frame,
+        heap::HEAP_ALLOCATOR,
+        paging::{
+            entry::{Entry, EntryFlags},
+            mapper::Mapper,
+        },
+        vmalloc,
+    },
+    sbi::{self, ResetReason, ResetType},
+};
+
+/// Run the monitor at the end of boot.
+pub static MONITOR: Param<bool> = Param::new("monitor", false);
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        args: "",
+        help: "free blocks in the page allocator, the heap's size and vmalloc areas",
+        run: mem,
+    },
+    Command {
+        name: "pt",
+        args: "<vaddr>",
+        help: "walk the current page table for an address",
+        run: pt,
+    },
+    Command {
+        name: "peek",
+        args: "<vaddr> [count]",
+        help: "read 64-bit words",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        args: "<vaddr> <value>",
+        help: "write a 64-bit word",
+        run: poke,
+    },
+    Command {
+        name: "harts",
+        args: "",
+        help: "the state of every hart",
+        run: harts,
+    },
+    Command {
+        name: "ps",
+        args: "",
+        help: "list processes",
+        run: ps,
+    },
+    Command {
+        name: "log",
+        args: "[count]",
+        help: "print the newest log records, or all of them",
+        run: log,
+    },
+    Command {
+        name: "cmdline",
+        args: "",
+        help: "the command line and every param",
+        run: cmdline,
+    },
+    Command {
+        name: "ls",
+        args: "[path]",
+        help: "list a directory of the initramfs",
+        run: ls,
+    },
+    Command {
+        name: "reboot",
+        args: "[warm]",
+        help: "reset the machine",
+        run: reboot,
+    },
+    Command {
+        name: "exit",
+        args: "",
+        help: "leave the monitor and carry on",
+        run: |_| Ok(()),
+    },
+];
+
+struct Command {
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+    run: fn(&[&str]) -> Result<(), CommandError>,
+}
+
+// Only ever printed
+#[allow(dead_code)]
+#[derive(Debug)]
+enum CommandError {
+    /// The arguments don't match the command's usage.
+    Usage,
+    BadNumber(ParseIntError),
+    /// The address isn't canonical, or isn't mapped with the access needed.
+    Inaccessible(usize),
+    Misaligned(usize),
+    /// A lock the command needs is held, maybe for good by a stopped hart.
+    Busy,
+    Sbi(sbi::SbiError),
+    Fs(FsError),
+}
+
+/// Whether the monitor should run: it was asked for on the command line, or a key was pressed.
+pub fn requested() -> bool {
+    MONITOR.get() || serial::read_byte().is_some()
+}
+
+/// Runs commands until `exit`.
+pub fn run() {
+    println!("monitor: type `help` for commands");
+    let mut editor = Editor::new("yaro> ");
+
+    loop {
+        let _ = editor.start(&mut *SERIAL.lock());
+        let line = loop {
+            let Some(byte) = serial::read_byte() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            if let Ok(Some(line)) = editor.feed(byte, &mut *SERIAL.lock()) {
+                break line;
+            }
+        };
+
+        let args: alloc::vec::Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else {
+            continue;
+        };
+        let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+            println!("{name}: unknown command, try `help`");
+            continue;
+        };
+
+        match (command.run)(args) {
+            Ok(()) if name == "exit" => return,
+            Ok(()) => {}
+            Err(CommandError::Usage) => {
+                println!("usage: {} {}", command.name, command.args);
+            }
+            Err(err) => {
+                println!("{name}: {err:?}");
+            }
+        }
+    }
+}
+
+// Decimal, or hexadecimal with `0x`.
+fn parse_number(s: &str) -> Result<usize, CommandError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(CommandError::BadNumber)
+}
+
+// Checks that the word at `addr` is mapped with `access` in the current page table.
+fn word(addr: usize, access: EntryFlags) -> Result<*mut u64, CommandError> {
+    if !addr.is_multiple_of(size_of::<u64>()) {
+        return Err(CommandError::Misaligned(addr));
+    }
+
+    let virt = VirtAddr::try_new(addr).ok_or(CommandError::Inaccessible(addr))?;
+    let translation = unsafe { Mapper::current() }.translate(virt);
+    if !translation.is_some_and(|translation| translation.flags.contains(access)) {
+        return Err(CommandError::Inaccessible(addr));
+    }
+    Ok(virt.as_ptr())
+}
+
+fn help(_: &[&str]) -> Result<(), CommandError> {
+    for command in COMMANDS {
+        let usage = alloc::format!("{} {}", command.name, command.args);
+        println!("  {usage:<24} {}", command.help);
+    }
+    Ok(())
+}
+
+fn mem(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let allocator = PAGE_ALLOCATOR.lock();
+    for (order, count) in allocator.free_counts().iter().enumerate() {
+        println!(
+            "  order {order:>2} ({:>5} KiB): {count} free",
+            (PAGE_SIZE << order) / 1024
+        );
+    }
+    println!("  {} KiB free", allocator.free_size() / 1024);
+    drop(allocator);
+
+    let (size, max_size) = {
+        let heap = HEAP_ALLOCATOR.lock();
+        (heap.oom_handler.size(), heap.oom_handler.max_size())
+    };
+    println!("heap: {} of at most {} KiB", size / 1024, max_size / 1024);
+
+    vmalloc::dump();
+    Ok(())
+}
+
+fn pt(args: &[&str]) -> Result<(), CommandError> {
+    let [addr] = args else {
+        return Err(CommandError::Usage);
+    };
+    let addr = parse_number(addr)?;
+    let virt = VirtAddr::try_new(addr).ok_or(CommandError::Inaccessible(addr))?;
+
+    unsafe { Mapper::current() }.walk(virt, |level, entry: &Entry| {
+        let kind = if !entry.valid() {
+            "invalid"
+        } else if entry.is_leaf() {
+            "leaf"
+        } else {
+            "table"
+        };
+        println!(
+            "  level {level} [{:>3}]: {kind} {:#x} {:?}",
+            virt.vpn(level),
+            entry.phys_addr().as_usize(),
+            entry.flags()
+        );
+    });
+
+    match unsafe { Mapper::current() }.translate(virt) {
+        Some(translation) => {
+            println!(
+                "  {addr:#x} -> {:#x} ({:?} page)",
+                translation.phys.as_usize(),
+                translation.page_type
+            );
+            if let Some(frame) = frame::frame(translation.phys) {
+                println!(
+                    "  frame: {:?}, refcount {}, map count {}",
+                    frame.flags(),
+                    frame.refcount(),
+                    frame.map_count()
+                );
+            }
+        }
+        None => {
+            println!("  {addr:#x} isn't mapped");
+        }
+    }
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result<(), CommandError> {
+    let (addr, count) = match args {
+        [addr] => (addr, 1),
+        [addr, count] => (addr, parse_number(count)?),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let start = parse_number(addr)?;
+    for i in 0..count {
+        // Each word is checked, as they may cross into an unmapped page.
+        let addr = i
+            .checked_mul(size_of::<u64>())
+            .and_then(|offset| start.checked_add(offset))
+            .ok_or(CommandError::Inaccessible(start))?;
+        let ptr = word(addr, EntryFlags::READ)?;
+        println!("  {addr:#x}: {:#018x}", unsafe { ptr::read_volatile(ptr) });
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), CommandError> {
+    let [addr, value] = args else {
+        return Err(CommandError::Usage);
+    };
+    let ptr = word(parse_number(addr)?, EntryFlags::WRITE)?;
+    let value = parse_number(value)? as u64;
+    unsafe { ptr::write_volatile(ptr, value) };
+    Ok(())
+}
+
+fn harts(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    // QEMU numbers harts from 0 without gaps, so the first that doesn't exist is past the end.
+    for hart in 0.. {
+        let Ok(state) = sbi::hart_status(hart) else {
+            break;
+        };
+        let current = if hart == hart::id() {
+            " (this one)"
+        } else {
+            ""
+        };
+        println!("  hart {hart}: {state:?}{current}");
+    }
+    Ok(())
+}
+
+fn ps(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    // There's no scheduler to keep a process table yet.
+    println!("  no processes, only the kernel is running");
+    Ok(())
+}
+
+fn log(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => logger::dump(),
+        [count] => {
+            let printed = logger::try_for_each_newest(parse_number(count)?, |record| {
+                println!("{}", Line(record));
+            });
+            if !printed {
+                return Err(CommandError::Busy);
+            }
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn cmdline(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    println!("  {}", cmdline::raw());
+    for (name, value) in cmdline::params() {
+        println!("  {name} = {value}");
+    }
+    Ok(())
+}
+
+fn ls(args: &[&str]) -> Result<(), CommandError> {
+    let path = match args {
+        [] => "/",
+        [path] => path,
+        _ => return Err(CommandError::Usage),
+    };
+
+    for entry in initramfs::read_dir(path).map_err(CommandError::Fs)? {
+        let suffix = match entry.kind {
+            FileKind::File => "",
+            FileKind::Directory => "/",
+            FileKind::Symlink => "@",
+        };
+        println!("  {}{suffix}", entry.name);
+    }
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result<(), CommandError> {
+    let ty = match args {
+        [] => ResetType::ColdReboot,
+        ["warm"] => ResetType::WarmReboot,
+        _ => return Err(CommandError::Usage),
+    };
+    sbi::system_reset(ty, ResetReason::None).map_err(CommandError::Sbi)
+}
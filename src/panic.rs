@@ -0,0 +1,158 @@
+//! The panic handler. The report goes to `io::serial::Emergency`, since the panicking hart may
+//! hold `SERIAL`, and the other harts are sent an IPI to stop them first. It's also kept in
+//! `pstore`, along with the last log lines, for the next boot. Only the first panic is reported:
+//! a hart that panics while reporting just prints where, and other harts that panic meanwhile
+//! stop without a word.
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    backtrace, hart,
+    io::{
+        logger::{self, Line},
+        serial::Emergency,
+    },
+    kaslr,
+    pstore::{self, record::Writer},
+    sbi::{self, HartState, ResetReason, ResetType},
+};
+
+const SSTATUS_SIE: usize = 1 << 1;
+
+// Hart status checks to wait for each hart to stop, before reporting anyway
+const STOP_SPINS: usize = 100_000;
+const BACKTRACE_FRAMES: usize = 16;
+// Log lines kept in the pstore record
+const LOG_LINES: usize = 32;
+
+// The hart reporting a panic, if any.
+const NOBODY: usize = usize::MAX;
+static PANICKING: AtomicUsize = AtomicUsize::new(NOBODY);
+// Whether the panicking hart panicked again
+static NESTED: AtomicBool = AtomicBool::new(false);
+
+// Writes to the console and the pstore record at once.
+struct Report {
+    pstore: Option<Writer<'static>>,
+}
+
+/// Whether a hart is reporting a panic, in which case the others should `park`.
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Acquire) != NOBODY
+}
+
+/// Stops the current hart for good.
+pub fn park() -> ! {
+    disable_interrupts();
+    let _ = sbi::hart_stop();
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+fn disable_interrupts() {
+    unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE) };
+}
+
+// Sends every hart an IPI, and waits a while for the others to stop. Harts with interrupts
+// masked only stop once they unmask them.
+fn stop_other_harts(me: usize) {
+    if sbi::send_ipi(0, sbi::ALL_HARTS).is_err() {
+        return;
+    }
+
+    for hart in (0..).filter(|&hart| hart != me) {
+        for _ in 0..STOP_SPINS {
+            match sbi::hart_status(hart) {
+                Ok(HartState::Stopped) => break,
+                Ok(_) => core::hint::spin_loop(),
+                // Past the last hart, as QEMU numbers them from 0 without gaps. Also where HSM
+                // isn't supported, and there's no telling.
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    disable_interrupts();
+    let me = hart::id();
+
+    if let Err(owner) = PANICKING.compare_exchange(NOBODY, me, Ordering::AcqRel, Ordering::Acquire)
+    {
+        if owner != me {
+            park();
+        }
+
+        // Whatever was being formatted may panic again, so only the location is printed.
+        if !NESTED.swap(true, Ordering::Relaxed) {
+            let _ = write!(Emergency, "\nnested panic");
+            if let Some(location) = info.location() {
+                let _ = write!(Emergency, " at [{}:{}]", location.file(), location.line());
+            }
+            let _ = writeln!(Emergency);
+            crate::exit(1);
+        }
+        park();
+    }
+
+    stop_other_harts(me);
+
+    #[cfg(test)]
+    let _ = writeln!(Emergency, "FAILED");
+
+    // Other harts are stopped, and this is the only panic being reported.
+    let mut report = Report {
+        pstore: unsafe { pstore::writer() },
+    };
+
+    let _ = write!(
+        report,
+        "Kernel panic on hart {me} (kaslr slide {:#x}) in ",
+        kaslr::slide()
+    );
+
+    let _ = match info.location() {
+        Some(location) => write!(report, "[{}:{}]: ", location.file(), location.line()),
+        None => write!(report, "[Location unavailable]: "),
+    };
+
+    let _ = writeln!(report, "{}", info.message());
+
+    let _ = writeln!(report, "backtrace:");
+    for addr in backtrace::capture::<BACKTRACE_FRAMES>(0) {
+        if addr != 0 {
+            let _ = writeln!(report, "  {addr:#x}");
+        }
+    }
+
+    // The log has already been printed, so it only goes in the record.
+    if let Some(mut pstore) = report.pstore {
+        let _ = writeln!(pstore, "log:");
+        logger::try_for_each_newest(LOG_LINES, |record| {
+            let _ = writeln!(pstore, "{}", Line(record));
+        });
+        pstore.finish();
+
+        if pstore::REBOOT.get() {
+            let _ = sbi::system_reset(ResetType::WarmReboot, ResetReason::SystemFailure);
+        }
+    }
+
+    crate::exit(1);
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(pstore) = &mut self.pstore {
+            let _ = pstore.write_str(s);
+        }
+        Emergency.write_str(s)
+    }
+}
@@ -0,0 +1,107 @@
+//! Persistent storage for panic reports. The panic handler writes its report, a backtrace and the
+//! last log lines into a reserved region of RAM, which nothing else touches and which survives a
+//! warm reboot. The next boot prints the record and clears it.
+//!
+//! The region is a `/reserved-memory` node compatible with `ramoops`, like Linux's, or else the
+//! last 64 KiB of RAM. Either way it's at the same address on every boot.
+
+pub mod record;
+
+use core::{ops::Range, slice};
+
+use fdt::Fdt;
+use log::{info, warn};
+use spin::Once;
+
+use self::record::Writer;
+use crate::{
+    boot::kernel_phys_range,
+    cmdline::Param,
+    fs::initramfs::initrd_range,
+    io::serial::println,
+    mem::addr::{PhysAddr, VirtAddr, kernel_mem},
+};
+
+/// After a panic, warm reboot through SBI instead of powering off, keeping the record.
+pub static REBOOT: Param<bool> = Param::new("panic_reboot", false);
+
+const SIZE: usize = 64 * 1024;
+
+// Physical memory of the region, once it's known to be free.
+static REGION: Once<Range<usize>> = Once::new();
+
+/// Where records are kept, whether or not it's free. `mem::init` reserves it.
+pub fn region(fdt: &Fdt) -> Option<Range<usize>> {
+    let kernel_mem = kernel_mem();
+
+    if let Some(node) = fdt.find_compatible(&["ramoops"]) {
+        let reg = node.reg()?.next()?;
+        let start = reg.starting_address as usize;
+        let range = start..start + reg.size?;
+        return (range.start >= kernel_mem.start && range.end <= kernel_mem.end).then_some(range);
+    }
+
+    // Only RAM reachable through `VirtAddr::from_phys` will do.
+    let last = fdt
+        .memory()
+        .regions()
+        .filter_map(|region| {
+            let start = region.starting_address as usize;
+            let end = start + region.size?;
+            Some(start.max(kernel_mem.start)..end.min(kernel_mem.end))
+        })
+        .filter(|range| range.len() >= SIZE)
+        .max_by_key(|range| range.end)?;
+    Some(last.end - SIZE..last.end)
+}
+
+/// Prints and clears the record the last boot left, if any, and makes the region available to
+/// the panic handler.
+pub fn init(fdt: &Fdt, dtb_addr: PhysAddr) {
+    let Some(region) = region(fdt) else {
+        info!("pstore: no region");
+        return;
+    };
+
+    let kernel = kernel_phys_range();
+    let dtb = dtb_addr.as_usize()..dtb_addr.as_usize() + fdt.total_size();
+    let in_use = [kernel.start.as_usize()..kernel.end.as_usize(), dtb]
+        .into_iter()
+        .chain(initrd_range(fdt))
+        .any(|used| used.start < region.end && region.start < used.end);
+    if in_use {
+        warn!(
+            "pstore: [{:#x}, {:#x}) is in use, not keeping panic records",
+            region.start, region.end
+        );
+        return;
+    }
+    REGION.call_once(|| region.clone());
+
+    let buf = unsafe { bytes() }.expect("pstore region was just set");
+    match record::read(buf) {
+        Some(text) => {
+            warn!("pstore: the last boot panicked:");
+            println!("{text}");
+            record::clear(buf);
+        }
+        None => {
+            info!("pstore: [{:#x}, {:#x})", region.start, region.end);
+        }
+    }
+}
+
+/// Starts a new record, replacing the last one. `None` before `init`, or if there's no region.
+///
+/// # Safety
+/// Only one record may be written at a time, and only by the panic handler.
+pub unsafe fn writer() -> Option<Writer<'static>> {
+    Writer::new(unsafe { bytes() }?)
+}
+
+// The region, if `init` has set it.
+unsafe fn bytes() -> Option<&'static mut [u8]> {
+    let region = REGION.get()?;
+    let ptr = VirtAddr::from_phys(PhysAddr(region.start)).as_ptr::<u8>();
+    Some(unsafe { slice::from_raw_parts_mut(ptr, region.len()) })
+}
@@ -0,0 +1,158 @@
+//! The record kept in the pstore region: a header with a magic number, the length and a CRC-32
+//! of the text, followed by the text. The magic is written last, so a record that was cut short
+//! reads as no record at all.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{Ordering, fence},
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"yaropstr");
+// Magic, length and checksum
+const HEADER: usize = 8 + 4 + 4;
+
+/// Writes a record into a buffer, replacing the one in it. The text is cut short if it doesn't
+/// fit. Nothing can be read back until `finish`.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+/// CRC-32, as used by Ethernet and zlib.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The text of the record in `buf`, if there's a whole one.
+pub fn read(buf: &[u8]) -> Option<&str> {
+    let header = buf.get(..HEADER)?;
+    if u64::from_le_bytes(header[0..8].try_into().unwrap()) != MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+    let text = buf.get(HEADER..HEADER.checked_add(len)?)?;
+    if crc32(text) != checksum {
+        return None;
+    }
+    core::str::from_utf8(text).ok()
+}
+
+/// Forgets the record in `buf`.
+pub fn clear(buf: &mut [u8]) {
+    if let Some(magic) = buf.get_mut(..8) {
+        magic.fill(0);
+    }
+}
+
+impl<'a> Writer<'a> {
+    /// Returns `None` if `buf` is too small for even an empty record.
+    pub fn new(buf: &'a mut [u8]) -> Option<Self> {
+        if buf.len() < HEADER {
+            return None;
+        }
+        clear(buf);
+        Some(Self { buf, len: 0 })
+    }
+
+    /// Makes the record readable.
+    pub fn finish(self) {
+        let text = &self.buf[HEADER..HEADER + self.len];
+        let checksum = crc32(text);
+        self.buf[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+        self.buf[12..16].copy_from_slice(&checksum.to_le_bytes());
+        // The fence keeps the compiler and the hart from writing the magic before the rest, and
+        // volatile keeps the write from being dropped, as nothing reads it back.
+        fence(Ordering::Release);
+        let magic = self.buf.as_mut_ptr() as *mut [u8; 8];
+        unsafe { magic.write_volatile(MAGIC.to_le_bytes()) };
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - HEADER - self.len;
+        let mut end = s.len().min(room);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let start = HEADER + self.len;
+        self.buf[start..start + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn crc32_matches_the_standard_check() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test_case]
+    fn records_read_back_once_finished() {
+        let mut buf = [0xaa; 64];
+        assert_eq!(read(&buf), None);
+
+        let mut writer = Writer::new(&mut buf).unwrap();
+        write!(writer, "panic at {}", 42).unwrap();
+        writer.finish();
+        assert_eq!(read(&buf), Some("panic at 42"));
+
+        clear(&mut buf);
+        assert_eq!(read(&buf), None);
+    }
+
+    #[test_case]
+    fn unfinished_records_replace_the_old_one() {
+        let mut buf = [0; 64];
+        let mut writer = Writer::new(&mut buf).unwrap();
+        writer.write_str("old").unwrap();
+        writer.finish();
+
+        let mut writer = Writer::new(&mut buf).unwrap();
+        writer.write_str("new").unwrap();
+        assert_eq!(read(&buf), None);
+    }
+
+    #[test_case]
+    fn corrupted_records_are_rejected() {
+        let mut buf = [0; 64];
+        let mut writer = Writer::new(&mut buf).unwrap();
+        writer.write_str("intact").unwrap();
+        writer.finish();
+
+        buf[HEADER + 2] ^= 1;
+        assert_eq!(read(&buf), None);
+    }
+
+    #[test_case]
+    fn long_text_is_cut_at_a_character() {
+        let mut buf = [0; HEADER + 5];
+        assert!(Writer::new(&mut buf[..HEADER - 1]).is_none());
+
+        let mut writer = Writer::new(&mut buf).unwrap();
+        writer.write_str("ab").unwrap();
+        writer.write_str("cé").unwrap();
+        writer.write_str("é").unwrap();
+        writer.finish();
+        assert_eq!(read(&buf), Some("abcé"));
+    }
+}
@@ -0,0 +1,382 @@
+//! An SBI implementation for host tests. It records every call it gets, and emulates the
+//! console, the timer and HSM well enough for code using them to be tested without firmware.
+//! `host-tests` installs `EMULATOR` as the backend before running any test.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::mem::MaybeUninit;
+
+use spin::Mutex;
+
+use super::{
+    ALL_HARTS, EID_BASE, EID_CONSOLE_GETCHAR, EID_CONSOLE_PUTCHAR, EID_HSM, EID_IPI, EID_SRST,
+    EID_TIME, FID_BASE_PROBE_EXTENSION, FID_BASE_SPEC_VERSION, FID_HSM_HART_START,
+    FID_HSM_HART_STATUS, FID_HSM_HART_STOP, FID_IPI_SEND_IPI, FID_SRST_SYSTEM_RESET,
+    FID_TIME_SET_TIMER, HartState, ResetReason, ResetType, SbiBackend, SbiCall, SbiError, SbiRet,
+};
+
+/// Harts of the emulated machine. Hart 0 starts out running, and the rest stopped.
+pub const HART_COUNT: usize = 4;
+
+// SBI 2.0
+const SPEC_VERSION: usize = 2 << 24;
+const EXTENSIONS: &[usize] = &[
+    EID_CONSOLE_PUTCHAR,
+    EID_CONSOLE_GETCHAR,
+    EID_BASE,
+    EID_TIME,
+    EID_IPI,
+    EID_HSM,
+    EID_SRST,
+];
+
+pub static EMULATOR: Emulator = Emulator::new();
+
+pub struct Emulator {
+    state: Mutex<State>,
+}
+
+struct State {
+    calls: Vec<SbiCall>,
+    console: Vec<u8>,
+    input: VecDeque<u8>,
+    time: u64,
+    deadline: Option<u64>,
+    harts: [HartState; HART_COUNT],
+    // Whether each hart has a software interrupt pending
+    ipis: [bool; HART_COUNT],
+    // Where each hart was last started, and its opaque argument
+    entries: [Option<(usize, usize)>; HART_COUNT],
+    // The hart making calls
+    current: usize,
+    reset: Option<(ResetType, ResetReason)>,
+}
+
+fn ok(value: usize) -> SbiRet {
+    SbiRet {
+        error: super::SBI_SUCCESS,
+        value: MaybeUninit::new(value),
+    }
+}
+
+fn err(err: SbiError) -> SbiRet {
+    SbiRet {
+        error: err as isize,
+        value: MaybeUninit::uninit(),
+    }
+}
+
+// Legacy extensions only return a0.
+fn legacy(a0: isize) -> SbiRet {
+    SbiRet {
+        error: a0,
+        value: MaybeUninit::uninit(),
+    }
+}
+
+impl State {
+    const fn new() -> Self {
+        let mut harts = [HartState::Stopped; HART_COUNT];
+        harts[0] = HartState::Started;
+        Self {
+            calls: Vec::new(),
+            console: Vec::new(),
+            input: VecDeque::new(),
+            time: 0,
+            deadline: None,
+            harts,
+            ipis: [false; HART_COUNT],
+            entries: [None; HART_COUNT],
+            current: 0,
+            reset: None,
+        }
+    }
+
+    fn handle(&mut self, call: &SbiCall) -> SbiRet {
+        match (call.eid, call.fid) {
+            (EID_CONSOLE_PUTCHAR, _) => {
+                self.console.push(call.arg0 as u8);
+                legacy(0)
+            }
+            (EID_CONSOLE_GETCHAR, _) => legacy(self.input.pop_front().map_or(-1, isize::from)),
+            (EID_BASE, FID_BASE_SPEC_VERSION) => ok(SPEC_VERSION),
+            (EID_BASE, FID_BASE_PROBE_EXTENSION) => ok(EXTENSIONS.contains(&call.arg0) as usize),
+            (EID_TIME, FID_TIME_SET_TIMER) => {
+                self.deadline = Some(call.arg0 as u64);
+                ok(0)
+            }
+            (EID_IPI, FID_IPI_SEND_IPI) => self.send_ipi(call.arg0, call.arg1),
+            (EID_HSM, FID_HSM_HART_START) => self.hart_start(call.arg0, call.arg1, call.arg2),
+            (EID_HSM, FID_HSM_HART_STOP) => self.hart_stop(),
+            (EID_HSM, FID_HSM_HART_STATUS) => match self.harts.get(call.arg0) {
+                Some(&state) => ok(state as usize),
+                None => err(SbiError::InvalidParam),
+            },
+            (EID_SRST, FID_SRST_SYSTEM_RESET) => self.system_reset(call.arg0, call.arg1),
+            _ => err(SbiError::NotSupported),
+        }
+    }
+
+    fn send_ipi(&mut self, mask: usize, base: usize) -> SbiRet {
+        if base == ALL_HARTS {
+            self.ipis = [true; HART_COUNT];
+            return ok(0);
+        }
+
+        let targets = (0..usize::BITS as usize)
+            .filter(|bit| mask & 1 << bit != 0)
+            .map(|bit| base.checked_add(bit));
+        for hart in targets.clone() {
+            if hart.is_none_or(|hart| hart >= HART_COUNT) {
+                return err(SbiError::InvalidParam);
+            }
+        }
+        for hart in targets.flatten() {
+            self.ipis[hart] = true;
+        }
+        ok(0)
+    }
+
+    // The hart is started right away, though it doesn't run anything.
+    fn hart_start(&mut self, hart: usize, start: usize, opaque: usize) -> SbiRet {
+        let Some(state) = self.harts.get_mut(hart) else {
+            return err(SbiError::InvalidParam);
+        };
+        match state {
+            HartState::Stopped => {
+                *state = HartState::Started;
+                self.entries[hart] = Some((start, opaque));
+                ok(0)
+            }
+            HartState::Started => err(SbiError::AlreadyAvailable),
+            _ => err(SbiError::Failed),
+        }
+    }
+
+    fn hart_stop(&mut self) -> SbiRet {
+        let state = &mut self.harts[self.current];
+        if *state != HartState::Started {
+            return err(SbiError::Failed);
+        }
+        *state = HartState::Stopped;
+        ok(0)
+    }
+
+    fn system_reset(&mut self, ty: usize, reason: usize) -> SbiRet {
+        let ty = match ty {
+            0 => ResetType::Shutdown,
+            1 => ResetType::ColdReboot,
+            2 => ResetType::WarmReboot,
+            _ => return err(SbiError::InvalidParam),
+        };
+        let reason = match reason {
+            0 => ResetReason::None,
+            1 => ResetReason::SystemFailure,
+            _ => return err(SbiError::InvalidParam),
+        };
+        self.reset = Some((ty, reason));
+        ok(0)
+    }
+}
+
+impl Emulator {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State::new()),
+        }
+    }
+
+    /// Forgets every call and puts the machine back in its initial state.
+    pub fn clear(&self) {
+        *self.state.lock() = State::new();
+    }
+
+    /// Every call made so far, oldest first.
+    pub fn calls(&self) -> Vec<SbiCall> {
+        self.state.lock().calls.clone()
+    }
+
+    /// Everything written to the console.
+    pub fn console(&self) -> String {
+        String::from_utf8_lossy(&self.state.lock().console).into_owned()
+    }
+
+    /// Queues `bytes` to be read from the console.
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.state.lock().input.extend(bytes);
+    }
+
+    /// The value of the emulated `time` CSR.
+    pub fn time(&self) -> u64 {
+        self.state.lock().time
+    }
+
+    pub fn advance_time(&self, ticks: u64) {
+        self.state.lock().time += ticks;
+    }
+
+    /// Whether a timer interrupt would be pending now.
+    pub fn timer_pending(&self) -> bool {
+        let state = self.state.lock();
+        state
+            .deadline
+            .is_some_and(|deadline| state.time >= deadline)
+    }
+
+    /// Panics if `hart` doesn't exist.
+    pub fn hart_state(&self, hart: usize) -> HartState {
+        self.state.lock().harts[hart]
+    }
+
+    /// The start address and opaque argument `hart` was last started with.
+    pub fn hart_entry(&self, hart: usize) -> Option<(usize, usize)> {
+        self.state.lock().entries[hart]
+    }
+
+    /// Whether `hart` has a software interrupt pending, which this clears.
+    pub fn take_ipi(&self, hart: usize) -> bool {
+        core::mem::take(&mut self.state.lock().ipis[hart])
+    }
+
+    /// Makes the following calls come from `hart`.
+    pub fn set_current_hart(&self, hart: usize) {
+        assert!(hart < HART_COUNT, "no hart {hart}");
+        self.state.lock().current = hart;
+    }
+
+    /// The last reset asked for, which the emulator only records.
+    pub fn last_reset(&self) -> Option<(ResetType, ResetReason)> {
+        self.state.lock().reset
+    }
+}
+
+impl SbiBackend for Emulator {
+    unsafe fn call(&self, call: &SbiCall) -> SbiRet {
+        let mut state = self.state.lock();
+        state.calls.push(call.clone());
+        state.handle(call)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sbi;
+
+    // Every test starts from a fresh machine. The tests run one at a time.
+    fn emulator() -> &'static Emulator {
+        EMULATOR.clear();
+        &EMULATOR
+    }
+
+    #[test_case]
+    fn console_output_and_input() {
+        let emulator = emulator();
+        for byte in *b"hi\n" {
+            sbi::console_putchar(byte).unwrap();
+        }
+        assert_eq!(emulator.console(), "hi\n");
+
+        assert_eq!(sbi::console_getchar(), None);
+        emulator.push_input(b"ok");
+        assert_eq!(sbi::console_getchar(), Some(b'o'));
+        assert_eq!(sbi::console_getchar(), Some(b'k'));
+        assert_eq!(sbi::console_getchar(), None);
+    }
+
+    #[test_case]
+    fn calls_are_recorded() {
+        let emulator = emulator();
+        assert_eq!(sbi::spec_version(), Ok((2, 0)));
+        assert!(sbi::probe_extension(EID_HSM));
+        assert!(!sbi::probe_extension(0x1234));
+
+        let calls = emulator.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(
+            calls[1],
+            SbiCall::new()
+                .with_eid(EID_BASE)
+                .with_fid(FID_BASE_PROBE_EXTENSION)
+                .with_arg0(EID_HSM)
+                .clone()
+        );
+    }
+
+    #[test_case]
+    fn unknown_calls_are_not_supported() {
+        emulator();
+        let ret = unsafe { SbiCall::new().with_eid(0x1234).with_fid(5).call() };
+        assert_eq!(ret.error(), Some(SbiError::NotSupported));
+    }
+
+    #[test_case]
+    fn unknown_error_codes_are_failures() {
+        assert_eq!(legacy(-13).error(), Some(SbiError::Io));
+        assert_eq!(legacy(-100).into_result(), Err(SbiError::Failed));
+    }
+
+    #[test_case]
+    fn timer_fires_at_deadline() {
+        let emulator = emulator();
+        assert!(!emulator.timer_pending());
+
+        sbi::set_timer(100).unwrap();
+        emulator.advance_time(99);
+        assert!(!emulator.timer_pending());
+        emulator.advance_time(1);
+        assert!(emulator.timer_pending());
+
+        // Setting the timer clears the pending interrupt
+        sbi::set_timer(u64::MAX).unwrap();
+        assert!(!emulator.timer_pending());
+    }
+
+    #[test_case]
+    fn hsm_tracks_hart_states() {
+        let emulator = emulator();
+        assert_eq!(sbi::hart_status(0), Ok(HartState::Started));
+        assert_eq!(sbi::hart_status(1), Ok(HartState::Stopped));
+        assert_eq!(sbi::hart_status(HART_COUNT), Err(SbiError::InvalidParam));
+
+        unsafe {
+            assert_eq!(sbi::hart_start(1, 0x8020_0000, 42), Ok(()));
+            assert_eq!(
+                sbi::hart_start(1, 0x8020_0000, 42),
+                Err(SbiError::AlreadyAvailable)
+            );
+        }
+        assert_eq!(sbi::hart_status(1), Ok(HartState::Started));
+        assert_eq!(emulator.hart_entry(1), Some((0x8020_0000, 42)));
+
+        emulator.set_current_hart(1);
+        assert_eq!(sbi::hart_stop(), Ok(()));
+        assert_eq!(sbi::hart_stop(), Err(SbiError::Failed));
+        assert_eq!(emulator.hart_state(1), HartState::Stopped);
+    }
+
+    #[test_case]
+    fn ipis_reach_their_targets() {
+        let emulator = emulator();
+        sbi::send_ipi(0b101, 1).unwrap();
+        assert!(!emulator.take_ipi(0));
+        assert!(emulator.take_ipi(1));
+        assert!(!emulator.take_ipi(2));
+        assert!(emulator.take_ipi(3));
+        assert!(!emulator.take_ipi(3));
+
+        assert_eq!(sbi::send_ipi(1, HART_COUNT), Err(SbiError::InvalidParam));
+        sbi::send_ipi(0, sbi::ALL_HARTS).unwrap();
+        assert!((0..HART_COUNT).all(|hart| emulator.take_ipi(hart)));
+    }
+
+    #[test_case]
+    fn system_reset_is_recorded() {
+        let emulator = emulator();
+        assert_eq!(emulator.last_reset(), None);
+        sbi::system_reset(ResetType::WarmReboot, ResetReason::None).unwrap();
+        assert_eq!(
+            emulator.last_reset(),
+            Some((ResetType::WarmReboot, ResetReason::None))
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! RISC V has a maximum of three privilege levels or "modes": M-mode, S-mode, and U-mode.
+//! The kernel operates under S-mode while SBI is above it at M-mode and runs concurrently
+//! with the kernel. It provides abstractions over the machine's hardware that are accessible
+//! through the `ecall` instruction, functionally behaving like a system call from a user
+//! program to its operating system.
+//!
+//! Calls go through an `SbiBackend`, which is `Ecall` unless another one is installed with
+//! `set_backend`, like the emulator host tests use.
+
+// The whole interface is here, whether the kernel uses it yet or not.
+#![allow(dead_code)]
+
+#[cfg(not(target_arch = "riscv64"))]
+pub mod emulator;
+
+#[cfg(target_arch = "riscv64")]
+use core::arch::asm;
+use core::mem::MaybeUninit;
+
+use spin::Once;
+
+pub const SBI_SUCCESS: isize = 0;
+
+// Legacy extensions, which return their result in a0 instead of an `SbiRet`
+const EID_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_CONSOLE_GETCHAR: usize = 0x02;
+
+const EID_BASE: usize = 0x10;
+const FID_BASE_SPEC_VERSION: usize = 0;
+const FID_BASE_PROBE_EXTENSION: usize = 3;
+
+const EID_TIME: usize = 0x54494d45;
+const FID_TIME_SET_TIMER: usize = 0;
+
+const EID_IPI: usize = 0x735049;
+const FID_IPI_SEND_IPI: usize = 0;
+
+const EID_HSM: usize = 0x48534d;
+const FID_HSM_HART_START: usize = 0;
+const FID_HSM_HART_STOP: usize = 1;
+const FID_HSM_HART_STATUS: usize = 2;
+
+const EID_SRST: usize = 0x53525354;
+const FID_SRST_SYSTEM_RESET: usize = 0;
+
+/// A `hart_mask_base` that targets every hart, whatever the mask.
+pub const ALL_HARTS: usize = usize::MAX;
+
+static BACKEND: Once<&'static dyn SbiBackend> = Once::new();
+
+/// Carries out SBI calls.
+pub trait SbiBackend: Sync {
+    /// # Safety
+    /// Same as `SbiCall::call`.
+    unsafe fn call(&self, call: &SbiCall) -> SbiRet;
+}
+
+/// Traps into the firmware.
+pub struct Ecall;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(isize)]
+pub enum SbiError {
+    Failed = -1,
+    NotSupported = -2,
+    InvalidParam = -3,
+    Denied = -4,
+    InvalidAddress = -5,
+    AlreadyAvailable = -6,
+    AlreadyStarted = -7,
+    AlreadyStopped = -8,
+    NoSharedMemory = -9,
+    InvalidState = -10,
+    BadRange = -11,
+    Timeout = -12,
+    Io = -13,
+}
+
+/// HSM states of a hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum HartState {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    /// Keeps the contents of RAM.
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+#[must_use]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SbiRet {
+    // SBI_SUCCESS or an error code, which may be one `SbiError` doesn't know. Legacy extensions
+    // return something else, which is only ever read from `error` directly.
+    error: isize,
+    value: MaybeUninit<usize>,
+}
+
+impl SbiRet {
+    pub fn is_success(&self) -> bool {
+        self.error == SBI_SUCCESS
+    }
+
+    pub fn error(&self) -> Option<SbiError> {
+        if self.error != SBI_SUCCESS {
+            Some(SbiError::from_isize(self.error))
+        } else {
+            None
+        }
+    }
+
+    pub fn value(&self) -> Option<usize> {
+        if self.error == SBI_SUCCESS {
+            Some(unsafe { self.value.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_result(self) -> Result<usize, SbiError> {
+        match self.error() {
+            Some(err) => Err(err),
+            None => Ok(unsafe { self.value.assume_init() }),
+        }
+    }
+}
+
+impl SbiError {
+    /// The error for an SBI error code. Codes from newer versions than this knows are `Failed`.
+    pub const fn from_isize(code: isize) -> Self {
+        match code {
+            -2 => Self::NotSupported,
+            -3 => Self::InvalidParam,
+            -4 => Self::Denied,
+            -5 => Self::InvalidAddress,
+            -6 => Self::AlreadyAvailable,
+            -7 => Self::AlreadyStarted,
+            -8 => Self::AlreadyStopped,
+            -9 => Self::NoSharedMemory,
+            -10 => Self::InvalidState,
+            -11 => Self::BadRange,
+            -12 => Self::Timeout,
+            -13 => Self::Io,
+            _ => Self::Failed,
+        }
+    }
+}
+
+impl HartState {
+    pub const fn from_usize(state: usize) -> Option<Self> {
+        match state {
+            0 => Some(Self::Started),
+            1 => Some(Self::Stopped),
+            2 => Some(Self::StartPending),
+            3 => Some(Self::StopPending),
+            4 => Some(Self::Suspended),
+            5 => Some(Self::SuspendPending),
+            6 => Some(Self::ResumePending),
+            _ => None,
+        }
+    }
+}
+
+impl SbiBackend for Ecall {
+    #[cfg(target_arch = "riscv64")]
+    unsafe fn call(&self, call: &SbiCall) -> SbiRet {
+        let error;
+        let value;
+
+        unsafe {
+            asm!(
+                "ecall",
+                in("a0") call.arg0,
+                in("a1") call.arg1,
+                in("a2") call.arg2,
+                in("a3") call.arg3,
+                in("a4") call.arg4,
+                in("a5") call.arg5,
+                in("a6") call.fid,
+                in("a7") call.eid,
+                lateout("a0") error,
+                lateout("a1") value,
+            );
+        }
+
+        SbiRet { error, value }
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    unsafe fn call(&self, _call: &SbiCall) -> SbiRet {
+        panic!("there's no firmware to ecall into; install a backend with `set_backend`");
+    }
+}
+
+/// Sends every SBI call to `backend` instead of the firmware. Only the first call has any
+/// effect.
+pub fn set_backend(backend: &'static dyn SbiBackend) {
+    BACKEND.call_once(|| backend);
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SbiCall {
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    fid: usize,
+    eid: usize,
+}
+
+impl SbiCall {
+    pub const fn new() -> Self {
+        Self {
+            arg0: 0,
+            arg1: 0,
+            arg2: 0,
+            arg3: 0,
+            arg4: 0,
+            arg5: 0,
+            fid: 0,
+            eid: 0,
+        }
+    }
+
+    /// # Safety
+    /// The call mustn't break the kernel's assumptions, e.g. by handing the firmware memory
+    /// the kernel is using.
+    pub unsafe fn call(&self) -> SbiRet {
+        let backend = BACKEND.get().copied().unwrap_or(&Ecall);
+        unsafe { backend.call(self) }
+    }
+
+    pub fn with_arg0(&mut self, value: usize) -> &mut Self {
+        self.arg0 = value;
+        self
+    }
+
+    pub fn with_arg1(&mut self, value: usize) -> &mut Self {
+        self.arg1 = value;
+        self
+    }
+
+    pub fn with_arg2(&mut self, value: usize) -> &mut Self {
+        self.arg2 = value;
+        self
+    }
+
+    pub fn with_arg3(&mut self, value: usize) -> &mut Self {
+        self.arg3 = value;
+        self
+    }
+
+    pub fn with_arg4(&mut self, value: usize) -> &mut Self {
+        self.arg4 = value;
+        self
+    }
+
+    pub fn with_arg5(&mut self, value: usize) -> &mut Self {
+        self.arg5 = value;
+        self
+    }
+
+    pub fn with_fid(&mut self, value: usize) -> &mut Self {
+        self.fid = value;
+        self
+    }
+
+    pub fn with_eid(&mut self, value: usize) -> &mut Self {
+        self.eid = value;
+        self
+    }
+}
+
+pub fn console_putchar(byte: u8) -> Result<(), SbiError> {
+    let ret = unsafe {
+        SbiCall::new()
+            .with_eid(EID_CONSOLE_PUTCHAR)
+            .with_arg0(byte as usize)
+            .call()
+    };
+    match ret.error {
+        SBI_SUCCESS => Ok(()),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+/// The next byte of console input, if any has arrived.
+pub fn console_getchar() -> Option<u8> {
+    let ret = unsafe { SbiCall::new().with_eid(EID_CONSOLE_GETCHAR).call() };
+    // The byte, or -1 if there's none
+    u8::try_from(ret.error).ok()
+}
+
+/// The SBI version the firmware implements, as (major, minor).
+pub fn spec_version() -> Result<(usize, usize), SbiError> {
+    let version = unsafe {
+        SbiCall::new()
+            .with_eid(EID_BASE)
+            .with_fid(FID_BASE_SPEC_VERSION)
+            .call()
+    }
+    .into_result()?;
+    Ok((version >> 24 & 0x7f, version & 0xffffff))
+}
+
+/// Whether the firmware implements the extension `eid`.
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = unsafe {
+        SbiCall::new()
+            .with_eid(EID_BASE)
+            .with_fid(FID_BASE_PROBE_EXTENSION)
+            .with_arg0(eid)
+            .call()
+    };
+    ret.value().is_some_and(|value| value != 0)
+}
+
+/// Raises a supervisor timer interrupt once `time` reaches `deadline`, clearing any pending
+/// one.
+pub fn set_timer(deadline: u64) -> Result<(), SbiError> {
+    unsafe {
+        SbiCall::new()
+            .with_eid(EID_TIME)
+            .with_fid(FID_TIME_SET_TIMER)
+            .with_arg0(deadline as usize)
+            .call()
+    }
+    .into_result()
+    .map(drop)
+}
+
+/// Raises a supervisor software interrupt on the harts in `hart_mask`, whose bit 0 is hart
+/// `hart_mask_base`. A base of `ALL_HARTS` targets every hart, the caller included.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    unsafe {
+        SbiCall::new()
+            .with_eid(EID_IPI)
+            .with_fid(FID_IPI_SEND_IPI)
+            .with_arg0(hart_mask)
+            .with_arg1(hart_mask_base)
+            .call()
+    }
+    .into_result()
+    .map(drop)
+}
+
+/// Starts `hart` at the physical address `start`, in S-mode with paging off, with its hart ID
+/// in a0 and `opaque` in a1.
+///
+/// # Safety
+/// `start` must be code that's ready for the new hart.
+pub unsafe fn hart_start(hart: usize, start: usize, opaque: usize) -> Result<(), SbiError> {
+    unsafe {
+        SbiCall::new()
+            .with_eid(EID_HSM)
+            .with_fid(FID_HSM_HART_START)
+            .with_arg0(hart)
+            .with_arg1(start)
+            .with_arg2(opaque)
+            .call()
+    }
+    .into_result()
+    .map(drop)
+}
+
+/// Stops the calling hart. Only returns if that failed, or under an emulator.
+pub fn hart_stop() -> Result<(), SbiError> {
+    unsafe {
+        SbiCall::new()
+            .with_eid(EID_HSM)
+            .with_fid(FID_HSM_HART_STOP)
+            .call()
+    }
+    .into_result()
+    .map(drop)
+}
+
+pub fn hart_status(hart: usize) -> Result<HartState, SbiError> {
+    let state = unsafe {
+        SbiCall::new()
+            .with_eid(EID_HSM)
+            .with_fid(FID_HSM_HART_STATUS)
+            .with_arg0(hart)
+            .call()
+    }
+    .into_result()?;
+    HartState::from_usize(state).ok_or(SbiError::Failed)
+}
+
+/// Resets or powers off the machine. Only returns if that failed, or under an emulator.
+pub fn system_reset(ty: ResetType, reason: ResetReason) -> Result<(), SbiError> {
+    unsafe {
+        SbiCall::new()
+            .with_eid(EID_SRST)
+            .with_fid(FID_SRST_SYSTEM_RESET)
+            .with_arg0(ty as usize)
+            .with_arg1(reason as usize)
+            .call()
+    }
+    .into_result()
+    .map(drop)
+}
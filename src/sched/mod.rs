@@ -1 +1,6 @@
 pub mod proc;
+
+use crate::cmdline::Param;
+
+/// Path of the first user process.
+pub static INIT: Param<&str> = Param::new("init", "/init");
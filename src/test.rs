@@ -0,0 +1,25 @@
+//! The in-kernel test runner. `cargo test` builds a kernel that runs every `#[test_case]` once
+//! memory and drivers are up, then powers off. A failing test panics, and the panic handler
+//! exits QEMU with a failure status, so the run stops at the first failure.
+
+use crate::io::serial::{print, println};
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("test {} ... ", core::any::type_name::<T>());
+        self();
+        println!("ok");
+    }
+}
+
+pub fn runner(tests: &[&dyn Testable]) {
+    println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("test result: ok. {} passed", tests.len());
+}